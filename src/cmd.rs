@@ -1,5 +1,9 @@
-use regex::Regex;
+use crate::common::now;
+use crate::error::Error;
+use crate::progress::Progress;
 use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct VideoInfo {
@@ -23,13 +27,17 @@ pub struct FfprobeStream {
     height: Option<i32>,
     codec_name: String,
     channels: Option<i32>,
+    #[serde(default)]
     avg_frame_rate: String,
-    bit_rate: String,
+    bit_rate: Option<String>,
     duration_ts: Option<i32>,
 }
 
-impl From<Ffprobe> for VideoInfo {
-    fn from(a: Ffprobe) -> Self {
+// ffprobe 没有返回的码率和时长按 0 处理, 合并时使用默认参数; 视频流缺少宽高或者数值无法解析时返回错误
+impl TryFrom<Ffprobe> for VideoInfo {
+    type Error = Error;
+
+    fn try_from(a: Ffprobe) -> Result<Self, Self::Error> {
         let mut video = VideoInfo {
             width: 0,
             height: 0,
@@ -38,146 +46,394 @@ impl From<Ffprobe> for VideoInfo {
             audio_rate: 0,
             fps: 0.0,
         };
+        let bit_rate = |rate: Option<String>| -> Result<i32, Error> {
+            match rate {
+                Some(rate) => rate.parse::<i32>().map_err(|_| Error::Parse(format!("无效的码率: {}", rate))),
+                None => Ok(0),
+            }
+        };
 
         for i in a.streams {
             if i.codec_type == "video" {
-                video.width = i.width.unwrap();
-                video.height = i.height.unwrap();
-                let regex = Regex::new(r"(?m)(\d+)\/(\d+)").unwrap();
-                let string = i.avg_frame_rate;
-                match regex.captures(&string) {
-                    Some(cap) => {
-                        // 获取第一个匹配的日期
-                        let year = cap[1].to_string();
-                        let month = cap[2].to_string();
-                        video.fps = format!(
-                            "{:.2}",
-                            (year.parse::<f32>().unwrap()) / (month.parse::<f32>().unwrap())
-                        )
-                            .parse::<f32>()
-                            .unwrap()
+                video.width = i.width.ok_or_else(|| Error::Parse("ffprobe 没有返回视频宽度".to_string()))?;
+                video.height = i.height.ok_or_else(|| Error::Parse("ffprobe 没有返回视频高度".to_string()))?;
+                // avg_frame_rate 为 30000/1001 这样的分数, 不知道时为 0/0
+                if let Some((num, den)) = i.avg_frame_rate.split_once('/') {
+                    let num = num.parse::<f32>().map_err(|_| Error::Parse(format!("无效的帧率: {}", i.avg_frame_rate)))?;
+                    let den = den.parse::<f32>().map_err(|_| Error::Parse(format!("无效的帧率: {}", i.avg_frame_rate)))?;
+                    if den > 0.0 {
+                        video.fps = (num / den * 100.0).round() / 100.0;
                     }
-                    None => {}
                 }
-                video.duration = i.duration_ts.unwrap() / 1000;
-                video.video_rate = i.bit_rate.parse::<i32>().unwrap();
+                video.duration = i.duration_ts.unwrap_or(0) / 1000;
+                video.video_rate = bit_rate(i.bit_rate)?;
             } else if i.codec_type == "audio" {
-                video.audio_rate = i.bit_rate.parse::<i32>().unwrap();
+                video.audio_rate = bit_rate(i.bit_rate)?;
             }
         }
-        return video;
+        Ok(video)
+    }
+}
+
+// 一路流的编码参数, 合并不连续的片段前比较, 不同时需要重新编码
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct StreamParams {
+    pub codec_type: String,
+    #[serde(default)]
+    pub codec_name: String,
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    #[serde(default)]
+    pub sample_rate: String,
+    #[serde(default)]
+    pub channels: u32,
+}
+
+#[derive(Deserialize)]
+struct FfprobeStreams {
+    streams: Vec<StreamParams>,
+}
+
+// 截取任务: Cutter::new("in.mp4").start(5).duration(10).output("out.mp4").run()
+#[derive(Debug, Clone)]
+pub struct Cutter {
+    input: String,
+    start: u32,
+    duration: u32,
+    output: String, // 为空时为当前目录下的 <时间戳>.mp4
+    progress: Arc<Progress>,
+}
+
+impl Cutter {
+    pub fn new(input: impl Into<String>) -> Cutter {
+        Cutter {
+            input: input.into(),
+            start: 0,
+            duration: 3,
+            output: String::default(),
+            progress: Arc::new(Progress::default()),
+        }
+    }
+
+    // 开始的秒数
+    pub fn start(mut self, start: u32) -> Self {
+        self.start = start;
+        self
+    }
+
+    // 截取的时长, 秒
+    pub fn duration(mut self, duration: u32) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    pub fn output(mut self, output: impl Into<String>) -> Self {
+        self.output = output.into();
+        self
+    }
+
+    // 使用外部的进度, 用于在其他线程查看 ffmpeg 的进度
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    // 截取, 返回输出文件的路径
+    pub fn run(&self) -> Result<PathBuf, Error> {
+        if self.duration == 0 {
+            return Err(Error::Parse("duration 需要 > 0".to_string()));
+        }
+        let mut output = self.output.clone();
+        if output.is_empty() {
+            output = format!("{}.mp4", now());
+        }
+        cmd::cut(self.input.clone(), self.start as f64, self.duration as f64, output.clone(), &self.progress)?;
+        Ok(PathBuf::from(output))
     }
 }
 
 pub mod cmd {
-    use crate::cmd::{Ffprobe, VideoInfo};
-    use std::env;
-    use std::fmt::{format, Error};
+    use crate::cmd::{Ffprobe, FfprobeStreams, StreamParams, VideoInfo};
+    use crate::error::Error;
+    use crate::progress::Progress;
     use std::fs::{self};
+    use std::io::{BufRead, BufReader, Read};
     use std::path::Path;
-    use std::process::{Command, Stdio};
+    use std::process::{Command, Output, Stdio};
+    use std::thread;
+    use std::time::Duration;
+    use crate::logging::{redact_header, redact_url};
+    use tracing::{debug, warn};
 
-    pub fn cut(file: String, start: u32, duration: u32, target: String) -> Result<bool, Error> {
-        let mut binding = Command::new("ffmpeg");
-        let res = binding
+    // start 和 duration 精确到毫秒, 按时间范围下载时需要从分片中间开始截取
+    pub fn cut(file: String, start: f64, duration: f64, target: String, progress: &Progress) -> Result<bool, Error> {
+        // 文件比截取的时长短时按文件剩余的时长计算进度
+        let mut total = duration;
+        if let Some(length) = get_duration(&file) {
+            total = total.min((length - start).max(0.0));
+        }
+        progress.start_stage("cut", Duration::from_secs_f64(total));
+        let mut binding = ffmpeg_command();
+        binding
             .arg("-i")
             .arg(file)
             .arg("-ss")
-            .arg(start.to_string())
+            .arg(format!("{:.3}", start))
             .arg("-t")
-            .arg(duration.to_string())
+            .arg(format!("{:.3}", duration))
             .arg("-c:v")
             .arg("libx264")
             .arg("-c:a")
             .arg("aac")
-            .arg(target)
+            .arg(target);
+        run_ffmpeg(binding, progress)
+    }
+
+    // 通过 -progress pipe:1 输出进度, 关闭默认的统计输出
+    fn ffmpeg_command() -> Command {
+        let mut binding = Command::new("ffmpeg");
+        binding.arg("-progress").arg("pipe:1").arg("-nostats");
+        binding
+    }
+
+    // 执行 ffmpeg, 从 stdout 读取 out_time_us 更新进度, stderr 在另一个线程中读取, 出错时放到错误信息里
+    fn run_ffmpeg(mut command: Command, progress: &Progress) -> Result<bool, Error> {
+        debug!(command = %command_line(&command), "执行 ffmpeg");
+        let mut child = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+        let mut stderr = child.stderr.take();
+        let stderr_reader = thread::spawn(move || {
+            let mut data = vec![];
+            if let Some(stderr) = stderr.as_mut() {
+                let _ = stderr.read_to_end(&mut data);
+            }
+            data
+        });
+        if let Some(stdout) = child.stdout.take() {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                // 老版本的 ffmpeg 只有 out_time_ms, 单位其实也是微秒
+                let value = line.strip_prefix("out_time_us=").or_else(|| line.strip_prefix("out_time_ms="));
+                if let Some(us) = value.and_then(|v| v.trim().parse::<u64>().ok()) {
+                    progress.set_media_done(Duration::from_micros(us));
+                }
+            }
+        }
+        let status = child.wait()?;
+        let stderr = stderr_reader.join().unwrap_or_default();
+        run_result(Output { status, stdout: vec![], stderr })
+    }
+
+    // 通过 ffprobe 获取时长(秒)
+    pub fn get_duration(input: &str) -> Option<f64> {
+        let output = Command::new("ffprobe")
+            .arg("-v")
+            .arg("error")
+            .arg("-show_entries")
+            .arg("format=duration")
+            .arg("-of")
+            .arg("default=noprint_wrappers=1:nokey=1")
+            .arg(input)
             .output()
-            .unwrap()
-            .status;
-        if res.success() {
-            Ok(true)
-        } else {
-            println!("ffmpeg 截取失败-{}", res.to_string());
-            Ok(false)
+            .ok()?;
+        String::from_utf8_lossy(&output.stdout).trim().parse().ok()
+    }
+
+    // concat 文件列表中所有输入的总时长, ffprobe 只能得到列表中第一个文件的时长
+    pub fn list_duration(list: &str) -> Option<f64> {
+        let content = fs::read_to_string(list).ok()?;
+        content
+            .lines()
+            .filter_map(|line| line.strip_prefix("file '")?.strip_suffix('\''))
+            .map(get_duration)
+            .sum()
+    }
+
+    // 通过 ffprobe 获取每路流的编码参数, 没有安装 ffprobe 或者无法识别时返回 None
+    pub fn get_stream_params(input: &str) -> Option<Vec<StreamParams>> {
+        let output = Command::new("ffprobe")
+            .arg("-v")
+            .arg("error")
+            .arg("-show_entries")
+            .arg("stream=codec_type,codec_name,width,height,sample_rate,channels")
+            .arg("-of")
+            .arg("json")
+            .arg(input)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
         }
+        let data: FfprobeStreams = serde_json::from_slice(&output.stdout).ok()?;
+        Some(data.streams)
     }
 
-    pub fn check_base_info_exists(folder_name: String) -> bool {
-        true
+    // ffmpeg 退出码不为 0 时返回带 stderr 的错误
+    fn run_result(output: Output) -> Result<bool, Error> {
+        if output.status.success() {
+            Ok(true)
+        } else {
+            Err(Error::ffmpeg(&output))
+        }
     }
 
-    pub fn clear_temp_files(folder_name: String) -> bool {
-        let current_dir = env::current_dir().unwrap();
-        let clear_ext = vec!["ts", "m3u8", "txt"];
-        let path_str = format!("./{}", folder_name.to_owned());
-        let dir_path = Path::new(path_str.as_str());
-        println!("now path {}, pass dir {:?}", current_dir.as_os_str().to_str().unwrap(), dir_path);
+    // 删除下载目录中的分片、播放列表等中间文件, folder_name 不是文件夹时返回 false
+    pub fn clear_temp_files(folder_name: &Path) -> Result<bool, Error> {
+        let clear_ext = vec!["ts", "m3u8", "mpd", "txt", "vtt", "m4s", "bin", "part"];
+        let dir_path = folder_name;
+        debug!(folder = %dir_path.display(), "清理临时文件");
 
         if !dir_path.is_dir() {
-            println!("-----path: {:?} is not dir", dir_path);
-            return false;
+            warn!(folder = %dir_path.display(), "不是文件夹");
+            return Ok(false);
         }
-        for i in clear_ext {
-            for entry in fs::read_dir(dir_path).unwrap() {
-                let entry = entry.unwrap();
-                let path = entry.path();
+        for entry in fs::read_dir(dir_path)? {
+            let path = entry?.path();
+            let ext = path.extension().map(|e| e.as_encoded_bytes());
+            if path.is_file() && clear_ext.iter().any(|i| ext == Some(i.as_bytes())) {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(true)
+    }
 
-                if path.is_file() && path.extension().unwrap().as_encoded_bytes() == i.as_bytes() {
-                    fs::remove_file(path).unwrap();
-                }
+    // headers 为空时不传 -headers, proxy 为 http 代理
+    // 日志中的 ffmpeg 命令, -headers 中的认证信息和地址中的 token 都去掉
+    fn command_line(command: &Command) -> String {
+        let mut args = vec![command.get_program().to_string_lossy().to_string()];
+        let mut headers = false;
+        for arg in command.get_args().map(|arg| arg.to_string_lossy()) {
+            if headers {
+                let lines: Vec<String> = arg
+                    .split("\r\n")
+                    .filter(|line| !line.is_empty())
+                    .map(|line| match line.split_once(':') {
+                        Some((name, value)) => redact_header(name.trim(), value.trim()),
+                        None => line.to_string(),
+                    })
+                    .collect();
+                args.push(format!("{:?}", lines.join("\r\n")));
+            } else {
+                args.push(redact_url(&arg));
             }
+            headers = arg == "-headers";
         }
-        true
+        args.join(" ")
     }
 
-    pub fn download(url: String, file_name: String) -> Result<bool, Error> {
-        let mut binding = Command::new("ffmpeg");
-        let res = binding
+    pub fn download(url: String, file_name: String, headers: String, proxy: Option<String>, progress: &Progress) -> Result<bool, Error> {
+        progress.start_stage("download", Duration::ZERO);
+        let mut binding = ffmpeg_command();
+        if !headers.is_empty() {
+            binding.arg("-headers").arg(headers);
+        }
+        if let Some(proxy) = proxy {
+            binding.arg("-http_proxy").arg(proxy);
+        }
+        binding
             .arg("-i")
             .arg(url.to_owned())
             .arg("-c")
             .arg("copy")
             .arg("-bsf:a")
             .arg("aac_adtstoasc")
-            .arg(file_name.to_owned())
-            .output()
-            .unwrap()
-            .status;
-        if res.success() {
-            Ok(true)
-        } else {
-            println!("{}", res.to_string());
-            Ok(false)
-        }
+            .arg(file_name);
+        run_ffmpeg(binding, progress)
     }
 
-    //ffmpeg -f concat -i input.txt -c copy output.mp4
-    pub fn combine(file: String, target: String) -> Result<bool, Error> {
-        let mut binding = Command::new("ffmpeg");
-        let res = binding
+    // 列表中是绝对路径, 需要 -safe 0
+    // ffmpeg -f concat -safe 0 -i input.txt -c copy output.mp4
+    pub fn combine(file: String, target: String, progress: &Progress) -> Result<bool, Error> {
+        progress.start_stage("combine", Duration::from_secs_f64(list_duration(&file).unwrap_or(0.0)));
+        let mut binding = ffmpeg_command();
+        binding
             .arg("-f")
             .arg("concat")
+            .arg("-safe")
+            .arg("0")
             .arg("-i")
             .arg(file)
             .arg("-c")
             .arg("copy")
-            .arg(target)
-            .output()
-            .unwrap()
-            .status;
-        if res.success() {
-            Ok(true)
-        } else {
-            println!("ffmpeg error---{}", res.to_string());
-            Ok(false)
+            .arg(target);
+        run_ffmpeg(binding, progress)
+    }
+
+    // 将视频与独立的音频、字幕合成一个文件, 并写入语言标签
+    // ffmpeg -i video.mp4 -i audio.ts -i sub.vtt -map 0:v -map 1:a -map 2:s -c copy -c:s mov_text output.mp4
+    pub fn mux(video: String, audios: Vec<(String, String)>, subtitles: Vec<(String, String)>, target: String, progress: &Progress) -> Result<bool, Error> {
+        progress.start_stage("mux", Duration::from_secs_f64(get_duration(&video).unwrap_or(0.0)));
+        let mut binding = ffmpeg_command();
+        binding.arg("-y").arg("-i").arg(video);
+        for (file, _) in &audios {
+            binding.arg("-i").arg(file);
+        }
+        for (file, _) in &subtitles {
+            binding.arg("-i").arg(file);
+        }
+        binding.arg("-map").arg("0:v");
+        if audios.is_empty() {
+            binding.arg("-map").arg("0:a?");
+        }
+        let mut input = 1;
+        for _ in &audios {
+            binding.arg("-map").arg(format!("{}:a", input));
+            input += 1;
+        }
+        for _ in &subtitles {
+            binding.arg("-map").arg(format!("{}:s", input));
+            input += 1;
         }
+        binding.arg("-c").arg("copy");
+        if !subtitles.is_empty() {
+            let subtitle_codec = if target.to_lowercase().ends_with(".mkv") { "srt" } else { "mov_text" };
+            binding.arg("-c:s").arg(subtitle_codec);
+        }
+        for (index, (_, language)) in audios.iter().enumerate() {
+            if !language.is_empty() {
+                binding.arg(format!("-metadata:s:a:{}", index)).arg(format!("language={}", to_iso639_2(language)));
+            }
+        }
+        for (index, (_, language)) in subtitles.iter().enumerate() {
+            if !language.is_empty() {
+                binding.arg(format!("-metadata:s:s:{}", index)).arg(format!("language={}", to_iso639_2(language)));
+            }
+        }
+        binding.arg(target);
+        run_ffmpeg(binding, progress)
+    }
+
+    // mp4 的语言标签需要 ISO 639-2 三位代码, HLS 里一般是 "en"、"zh-Hans" 这样的 BCP-47
+    fn to_iso639_2(language: &str) -> String {
+        let primary = language.split('-').next().unwrap_or("").to_lowercase();
+        let code = match primary.as_str() {
+            "en" => "eng",
+            "zh" => "chi",
+            "ja" => "jpn",
+            "ko" => "kor",
+            "fr" => "fre",
+            "de" => "ger",
+            "es" => "spa",
+            "pt" => "por",
+            "ru" => "rus",
+            "it" => "ita",
+            "ar" => "ara",
+            "hi" => "hin",
+            "th" => "tha",
+            "vi" => "vie",
+            "id" => "ind",
+            _ => primary.as_str(),
+        };
+        code.to_string()
     }
 
     // ffmpeg -i input.mp4 -b:v <视频码率> -b:a <音频码率> -r <帧率> output.mp4
     // ffmpeg -i input.mp4 -vf "scale=1280:720" -b:v 1500k -b:a 192k -r 30 -c:v libx264 -c:a aac output.mp4
-    pub fn transcode_video_to_spec_params(file: String, target: String, a_b: i32, v_b: i32, fps: i32, width: i32, height: i32) -> Result<bool, Error> {
-        let mut binding = Command::new("ffmpeg");
-        let res = binding
+    #[allow(clippy::too_many_arguments)]
+    pub fn transcode_video_to_spec_params(file: String, target: String, a_b: i32, v_b: i32, fps: i32, width: i32, height: i32, progress: &Progress) -> Result<bool, Error> {
+        progress.start_stage("transcode", Duration::from_secs_f64(get_duration(&file).unwrap_or(0.0)));
+        let mut binding = ffmpeg_command();
+        binding
             .arg("-i")
             .arg(file)
             .arg("-vf")
@@ -192,23 +448,16 @@ pub mod cmd {
             .arg("libx264".to_string())
             .arg("-c:a")
             .arg("aac".to_string())
-            .arg(target)
-            .output()
-            .unwrap()
-            .status;
-        if res.success() {
-            Ok(true)
-        } else {
-            println!("{}", res.to_string());
-            Ok(false)
-        }
+            .arg(target);
+        run_ffmpeg(binding, progress)
     }
 
+    // 分片很多, 总时长 duration 由调用方按 #EXTINF 计算, 不知道时为 0
     //ffmpeg -f concat -safe 0 -i filelist.txt -c copy output.mp4
-    pub fn combine_ts(file: String, target: String) -> Result<bool, Error> {
-        println!("{} file --- target {}", file.clone(), target.clone());
-        let mut binding = Command::new("ffmpeg");
-        let res = binding
+    pub fn combine_ts(file: String, target: String, duration: f64, progress: &Progress) -> Result<bool, Error> {
+        progress.start_stage("combine", Duration::from_secs_f64(duration));
+        let mut binding = ffmpeg_command();
+        binding
             .arg("-f")
             .arg("concat")
             .arg("-safe")
@@ -217,17 +466,58 @@ pub mod cmd {
             .arg(file)
             .arg("-c")
             .arg("copy")
-            .arg(target)
-            .output()
-            .unwrap()
-            .status;
-        if res.success() {
-            println!("-ffmpeg code {}", res.success());
-            Ok(true)
-        } else {
-            println!("combine_ts error,ffmpeg code:{}", res.to_string());
-            Ok(false)
+            .arg(target);
+        run_ffmpeg(binding, progress)
+    }
+
+    // 拼接分别合并好的不连续片段, concat 按每个文件的时长重新计算时间戳, 去掉片段开头的负时间戳
+    // ffmpeg -f concat -safe 0 -i runs.txt -c copy -avoid_negative_ts make_zero output.mp4
+    pub fn concat_runs(file: String, target: String, duration: f64, progress: &Progress) -> Result<bool, Error> {
+        progress.start_stage("combine", Duration::from_secs_f64(duration));
+        let mut binding = ffmpeg_command();
+        binding
+            .arg("-y")
+            .arg("-f")
+            .arg("concat")
+            .arg("-safe")
+            .arg("0")
+            .arg("-i")
+            .arg(file)
+            .arg("-c")
+            .arg("copy")
+            .arg("-avoid_negative_ts")
+            .arg("make_zero")
+            .arg(target);
+        run_ffmpeg(binding, progress)
+    }
+
+    // 编码参数不同的片段统一转成 H.264/AAC, 画面缩放并补边到 width x height, 音频统一采样率和声道数。
+    // 没有视频时 width 为 0
+    // ffmpeg -i run1.mp4 -vf scale=1280:720:force_original_aspect_ratio=decrease,pad=1280:720:(ow-iw)/2:(oh-ih)/2,setsar=1 -c:v libx264 -c:a aac -ar 48000 -ac 2 out.mp4
+    pub fn normalize_run(file: String, target: String, width: u32, height: u32, sample_rate: u32, channels: u32, progress: &Progress) -> Result<bool, Error> {
+        progress.start_stage("transcode", Duration::from_secs_f64(get_duration(&file).unwrap_or(0.0)));
+        let mut binding = ffmpeg_command();
+        binding.arg("-y").arg("-i").arg(file);
+        if width > 0 && height > 0 {
+            binding
+                .arg("-vf")
+                .arg(format!(
+                    "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2,setsar=1",
+                    w = width,
+                    h = height
+                ))
+                .arg("-c:v")
+                .arg("libx264");
         }
+        binding
+            .arg("-c:a")
+            .arg("aac")
+            .arg("-ar")
+            .arg(sample_rate.to_string())
+            .arg("-ac")
+            .arg(channels.to_string())
+            .arg(target);
+        run_ffmpeg(binding, progress)
     }
 
     pub fn check_video_validity(file_path: &str) -> Result<bool,Error> {
@@ -235,14 +525,18 @@ pub mod cmd {
             .args(&["-v", "error", "-show_format", "-show_streams"])
             .arg(file_path)
             .stderr(Stdio::piped())
-            .output().unwrap();
+            .output()?;
 
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        Ok(stderr.trim().is_empty())
+        // ffprobe 有错误输出时视频不完整
+        if String::from_utf8_lossy(&output.stderr).trim().is_empty() {
+            Ok(true)
+        } else {
+            Err(Error::ffmpeg(&output))
+        }
     }
 
-    pub fn get_video_info(file: &str) -> Option<VideoInfo> {
-        println!("pass file name： {}---",file);
+    pub fn get_video_info(file: &str) -> Result<VideoInfo, Error> {
+        debug!(file = %redact_url(file), "ffprobe 获取视频信息");
         let mut ffprobe = Command::new("ffprobe");
         let prob_result = ffprobe
             .arg("-v")
@@ -252,33 +546,43 @@ pub mod cmd {
             .arg("-show_format")
             .arg("-show_streams")
             .arg(file.to_owned())
-            .output()
-            .unwrap();
-        println!("ffmpeg status : {}", prob_result.status);
-        if prob_result.status.success() {
-            let res_data: Ffprobe =
-                serde_json::from_str(String::from_utf8(prob_result.stdout).unwrap().as_str())
-                    .expect("无法解析 JSON");
-            let video_info: VideoInfo = res_data.into();
-            Some(video_info)
-        } else {
-            None
+            .output()?;
+        debug!(status = %prob_result.status, "ffprobe 执行结束");
+        if !prob_result.status.success() {
+            return Err(Error::ffmpeg(&prob_result));
         }
+        let res_data: Ffprobe = serde_json::from_slice(&prob_result.stdout)?;
+        VideoInfo::try_from(res_data)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use crate::cmd::cmd::get_video_info;
+    use crate::cmd::{Ffprobe, VideoInfo};
 
     #[test]
     fn test_add() {
         let file = "https://cdn.poizon.com/du_app/2020/video/222341803_byte5570027_dur0_04e0fa415de1bd39e16dfe3b7085ddb8_1608103378948_du_android_w1088h1920.mp4";
-        let data = match get_video_info(file) {
-            None => {}
-            Some(a) => {
-                println!("{:?}", a);
-            }
-        };
+        if let Ok(a) = get_video_info(file) {
+            println!("{:?}", a);
+        }
+    }
+
+    #[test]
+    fn test_video_info_missing_fields() {
+        // 只有音频, 没有码率
+        let probe: Ffprobe = serde_json::from_str(r#"{"streams": [{"codec_type": "audio", "codec_name": "aac", "channels": 2, "avg_frame_rate": "0/0"}]}"#).unwrap();
+        let info = VideoInfo::try_from(probe).unwrap();
+        assert_eq!((info.width, info.audio_rate, info.fps), (0, 0, 0.0));
+
+        let probe: Ffprobe = serde_json::from_str(r#"{"streams": [{"codec_type": "video", "codec_name": "h264", "width": 1280, "height": 720, "avg_frame_rate": "30000/1001", "bit_rate": "1200000"}]}"#).unwrap();
+        let info = VideoInfo::try_from(probe).unwrap();
+        assert_eq!((info.width, info.height, info.video_rate, info.duration), (1280, 720, 1200000, 0));
+        assert_eq!(info.fps, 29.97);
+
+        // 视频流缺少宽度
+        let probe: Ffprobe = serde_json::from_str(r#"{"streams": [{"codec_type": "video", "codec_name": "h264", "avg_frame_rate": "25/1"}]}"#).unwrap();
+        assert!(VideoInfo::try_from(probe).is_err());
     }
 }
@@ -1,14 +1,125 @@
+use crate::combine::parse::{combine_video, get_reg_file_name, get_reg_files, to_files};
+use crate::error::Error;
+use crate::progress::Progress;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+// 合并任务, 按正则模式把 prefix(.*).ext 的多个文件合并成一个:
+// Combiner::new("video(.*).mp4", 1, 3).output("out.mp4").run()
+#[derive(Debug, Clone)]
+pub struct Combiner {
+    reg_name: String,
+    start: i32,
+    end: i32,
+    output: String, // 为空时使用去掉 (.*) 的文件名
+    same_param_index: i32,
+    audio_bitrate: i32,
+    video_bitrate: i32,
+    fps: i32,
+    width: i32,
+    height: i32,
+    progress: Arc<Progress>,
+}
+
+impl Combiner {
+    pub fn new(reg_name: impl Into<String>, start: i32, end: i32) -> Combiner {
+        Combiner {
+            reg_name: reg_name.into(),
+            start,
+            end,
+            output: String::default(),
+            same_param_index: -1,
+            audio_bitrate: 0,
+            video_bitrate: 0,
+            fps: 0,
+            width: 0,
+            height: 0,
+            progress: Arc::new(Progress::default()),
+        }
+    }
+
+    pub fn output(mut self, output: impl Into<String>) -> Self {
+        self.output = output.into();
+        self
+    }
+
+    // 按第 index 个视频的码率、帧率、分辨率转码后合并, 会覆盖下面指定的参数
+    pub fn same_param_index(mut self, index: i32) -> Self {
+        self.same_param_index = index;
+        self
+    }
+
+    pub fn audio_bitrate(mut self, bitrate: i32) -> Self {
+        self.audio_bitrate = bitrate;
+        self
+    }
+
+    pub fn video_bitrate(mut self, bitrate: i32) -> Self {
+        self.video_bitrate = bitrate;
+        self
+    }
+
+    pub fn fps(mut self, fps: i32) -> Self {
+        self.fps = fps;
+        self
+    }
+
+    pub fn size(mut self, width: i32, height: i32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    // 使用外部的进度, 用于在其他线程查看 ffmpeg 的进度
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    // 合并, 返回输出文件的路径
+    pub fn run(&self) -> Result<PathBuf, Error> {
+        let files = get_reg_files(self.reg_name.clone(), self.start, self.end)?;
+        let target = if self.output.is_empty() {
+            get_reg_file_name(self.reg_name.to_owned())
+        } else {
+            self.output.clone()
+        };
+        // 合并结束后 list 被 drop, 文件列表随之删除
+        let list = to_files(&target)?;
+        combine_video(
+            files,
+            list.path().display().to_string(),
+            target.clone(),
+            self.same_param_index,
+            self.audio_bitrate,
+            self.video_bitrate,
+            self.fps,
+            self.width,
+            self.height,
+            &self.progress,
+        )?;
+        Ok(PathBuf::from(target))
+    }
+}
+
 pub mod parse {
-    use crate::cmd::cmd::{clear_temp_files, combine, combine_ts, get_video_info, transcode_video_to_spec_params};
-    use crate::common::now;
-    use crate::m3u8::HlsM3u8Method;
+    use crate::cmd::cmd::{
+        combine, combine_ts, concat_runs, get_stream_params, get_video_info, normalize_run, transcode_video_to_spec_params,
+    };
+    use crate::common::is_url;
+    use crate::m3u8::{distinct_maps, HlsM3u8Method, HlsMap, HlsSegment};
+    use std::collections::HashMap;
+    use std::ops::Range;
+    use crate::sample_aes::sample_aes::decrypt_ts_file;
+    use crate::error::Error;
+    use crate::progress::Progress;
     use openssl::symm::{decrypt, Cipher};
-    use std::fmt::Error;
     use std::fs::{read, File, OpenOptions};
     use std::io::{BufReader, BufWriter};
+    use std::path::Path;
     use std::io::prelude::*;
-    use image::EncodableLayout;
-    use tempfile::tempdir;
+    use tempfile::NamedTempFile;
+    use tracing::{debug, trace, warn};
 
     pub fn get_reg_files(
         reg_name: String,
@@ -23,32 +134,39 @@ pub mod parse {
         Ok(files)
     }
 
-    pub fn get_reg_file_name(reg_name: String) -> String {
-        return reg_name.replace("(.*)", "");
+    // 下载时允许缺失分片, 合并时跳过不存在的分片
+    fn existing_files(files: Vec<String>) -> Vec<String> {
+        files
+            .into_iter()
+            .filter(|file| {
+                let exists = Path::new(file).exists();
+                if !exists {
+                    warn!(file = %file, "分片不存在, 跳过");
+                }
+                exists
+            })
+            .collect()
     }
 
-    fn get_temp_file() -> String {
-        if let Ok(dir) = tempdir() {
-            if let Some(a) = dir.path().join(format!("{}.txt", now())).to_str() {
-                return a.to_owned();
-            }
-        }
-        return String::default();
+    pub fn get_reg_file_name(reg_name: String) -> String {
+        return reg_name.replace("(.*)", "");
     }
 
-    pub fn to_files() -> Result<String, Error> {
-        // let file = get_temp_file();
-        let file = format!("{}.txt", now());
-        Ok(file)
+    // 文件列表写到输出文件所在目录下的临时文件中, 同时执行的任务不会互相覆盖
+    pub fn to_files(target: &str) -> Result<NamedTempFile, Error> {
+        let dir = Path::new(target).parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        Ok(tempfile::Builder::new().prefix("list_").suffix(".txt").tempfile_in(dir)?)
     }
 
     pub fn white_to_files(files: Vec<String>, file_name: String) -> Result<bool, Error> {
-        println!("{}", file_name.clone());
-        let mut file = File::create(file_name).expect("无法创建文件");
+        debug!(file = %file_name, "写入文件列表");
+        let mut file = File::create(file_name)?;
         for num in files {
+            // concat 按文件列表所在的目录解析相对路径, 本地文件统一写成绝对路径
+            let num = if is_url(num.clone()) { num } else { std::path::absolute(&num)?.display().to_string() };
             let str = format!("file \'{}\'", num);
-            file.write_all(str.as_bytes()).expect("写入文件失败");
-            file.write_all(b"\n").expect("写入文件失败");
+            file.write_all(str.as_bytes())?;
+            file.write_all(b"\n")?;
         }
         Ok(true)
     }
@@ -63,11 +181,12 @@ pub mod parse {
         set_fps: i32,
         set_width: i32,
         set_height: i32,
+        progress: &Progress,
     ) -> Result<bool, Error> {
         if same_param_index == -1 && set_a_b == 0 && set_v_b == 0 && set_fps == 0
             && set_width == 0 && set_height == 0 {
-            white_to_files(files.clone(), file_name.clone()).expect("写入文件失败");
-            return combine(file_name.clone(), target_file_name);
+            white_to_files(files.clone(), file_name.clone())?;
+            return combine(file_name.clone(), target_file_name, progress);
         }
         // 如果不指定视频参数相同的索引，那么就按照传过来的参数处理
         let mut a_b = 128000; // audio bitrate
@@ -76,28 +195,24 @@ pub mod parse {
         let mut width = 1280; // width
         let mut height = 720; // height
         if same_param_index != -1 {
-            let info = get_video_info(&files.get(same_param_index as usize).unwrap().to_string());
-            match info {
-                Some(data_info) => {
-                    if data_info.audio_rate > 0 {
-                        a_b = data_info.audio_rate;
-                    }
-                    if data_info.fps > 0.0 {
-                        fps = data_info.fps as i32;
-                    }
-                    if data_info.video_rate > 0 {
-                        v_b = data_info.video_rate;
-                    }
-                    if data_info.width > 0 {
-                        width = data_info.width;
-                    }
-                    if data_info.height > 0 {
-                        height = data_info.height;
-                    }
-                }
-                None => {
-                    return Ok(false)
-                }
+            let file = files
+                .get(same_param_index as usize)
+                .ok_or_else(|| Error::Parse(format!("same_param_index {} 超出文件数量", same_param_index)))?;
+            let data_info = get_video_info(file)?;
+            if data_info.audio_rate > 0 {
+                a_b = data_info.audio_rate;
+            }
+            if data_info.fps > 0.0 {
+                fps = data_info.fps as i32;
+            }
+            if data_info.video_rate > 0 {
+                v_b = data_info.video_rate;
+            }
+            if data_info.width > 0 {
+                width = data_info.width;
+            }
+            if data_info.height > 0 {
+                height = data_info.height;
             }
         } else {
             if set_a_b > 0 {
@@ -116,23 +231,24 @@ pub mod parse {
                 height = set_height
             }
         }
-        println!("ab {} vb {}  fps {} width {} height {}", a_b, v_b, fps, width, height);
-        transcode_videos_to_same_params(files.clone(), file_name.clone(), target_file_name, a_b, v_b, fps, width, height)
+        debug!(audio_bitrate = a_b, video_bitrate = v_b, fps, width, height, "统一转码参数");
+        transcode_videos_to_same_params(files.clone(), file_name.clone(), target_file_name, a_b, v_b, fps, width, height, progress)
     }
 
     // cargo run -- combine -r="/Users/meow.zang/RustroverProjects/ffmpeg-tool-rs/images/video/(.*).mp4" --reg-file-start=1 --reg-file-end=2 --same_param_index=1
-    fn transcode_videos_to_same_params(files: Vec<String>, file: String, target: String, a_b: i32, v_b: i32, fps: i32, width: i32, height: i32) -> Result<bool, Error> {
+    #[allow(clippy::too_many_arguments)]
+    fn transcode_videos_to_same_params(files: Vec<String>, file: String, target: String, a_b: i32, v_b: i32, fps: i32, width: i32, height: i32, progress: &Progress) -> Result<bool, Error> {
         let mut index: i32 = 0;
         let mut result_files = vec![];
         // 先将ts文件转成mp4
         for i in files.clone() {
             let file_name = format!("_temp_{}.mp4", index);
             result_files.push(file_name.clone());
-            let _ = transcode_video_to_spec_params(i.clone(), file_name.clone(), a_b, v_b, fps, width, height);
+            transcode_video_to_spec_params(i.clone(), file_name.clone(), a_b, v_b, fps, width, height, progress)?;
             index += 1;
         }
         // 在将mp4文件合并成一个文件
-        let combine_res = mp4_files_combine_one(result_files.clone(), file, target);
+        let combine_res = mp4_files_combine_one(result_files.clone(), file, target, progress);
         match combine_res {
             Ok(data) => {
                 if data {
@@ -152,10 +268,9 @@ pub mod parse {
         Ok(true)
     }
 
-    fn mp4_files_combine_one(mp4_files: Vec<String>, file: String, target: String) -> Result<bool, Error> {
-        println!("file {}, target {}", file.clone(), target.clone());
-        white_to_files(mp4_files.clone(), file.clone()).expect("写入文件失败");
-        combine(file.clone(), target)
+    fn mp4_files_combine_one(mp4_files: Vec<String>, file: String, target: String, progress: &Progress) -> Result<bool, Error> {
+        white_to_files(mp4_files.clone(), file.clone())?;
+        combine(file.clone(), target, progress)
     }
 
     async fn combine_without_crypto(
@@ -163,132 +278,150 @@ pub mod parse {
         reg_start: i32,
         reg_end: i32,
         target_name: String,
+        duration: f64,
+        progress: &Progress,
     ) -> Result<bool, Error> {
-        let files = get_reg_files(reg_name.clone(), reg_start, reg_end).expect("解析失败");
-        let file_name = to_files().expect("生成文件失败");
+        let files = existing_files(get_reg_files(reg_name.clone(), reg_start, reg_end)?);
+        // 文件列表和分片放在一起, 如 /tmp/audio0_(.*).ts => /tmp/audio0_list.txt
+        let file_name = format!("{}list.txt", reg_name.split("(.*)").next().unwrap_or_default());
         let mut target = String::default();
         if target_name.is_empty() {
             target = format!("{}", get_reg_file_name(reg_name.to_owned()));
         } else {
             target = format!("{}", target_name.clone());
         }
-        white_to_files(files.clone(), file_name.clone()).expect("写入文件失败");
-        let res = combine_ts(file_name.clone(), target).expect("合并文件失败");
-        Ok(res)
+        white_to_files(files.clone(), file_name.clone())?;
+        combine_ts(file_name.clone(), target, duration, progress)
     }
 
-    #[allow(dead_code)]
-    async fn combine_with_simple_aes(
+    // 解密后合并
+    async fn combine_with_crypto(
         reg_name: String,
         reg_start: i32,
         reg_end: i32,
         target_name: String,
-        key: String,
-        iv: String,
-        sequence: i32,
+        segments: Vec<HlsSegment>,
+        keys: HashMap<String, Vec<u8>>,
+        progress: &Progress,
     ) -> Result<bool, Error> {
-        // 未实现
-        Ok(false)
+        decrypt_segments(&reg_name, reg_start, reg_end, &segments, &keys)?;
+        let duration = segments.get(reg_start as usize..=reg_end as usize).map_or(0.0, |list| list.iter().map(|s| s.duration).sum());
+        combine_without_crypto(
+            decrypted_file_name(&reg_name),
+            reg_start,
+            reg_end,
+            target_name,
+            duration,
+            progress,
+        )
+            .await
     }
 
-    async fn decrypt_video_file(key: &[u8], iv: &[u8], sequence_number: u8, segment_url: &str) {
-        let mut file = File::open(segment_url).expect("文件不存在");
-        let mut file_data = Vec::new();
-        let _ = file.read_to_end(&mut file_data).expect("读文件失败");
+    // 按分片自己的 key 和 IV 解密到 decrypted- 开头的文件, AES-128 解密整个分片, SAMPLE-AES 解密 TS 中的音视频帧,
+    // 没有加密的分片直接复制
+    fn decrypt_segments(reg_name: &str, reg_start: i32, reg_end: i32, segments: &[HlsSegment], keys: &HashMap<String, Vec<u8>>) -> Result<(), Error> {
+        let files = get_reg_files(reg_name.to_string(), reg_start, reg_end)?;
+        for (index, file) in (reg_start..).zip(files.iter()) {
+            if !Path::new(file).exists() {
+                continue;
+            }
+            let target = decrypted_file_name(file);
+            let segment = segments
+                .get(index as usize)
+                .ok_or_else(|| Error::Parse(format!("分片{}没有对应的播放列表信息", file)))?;
+            let key = match &segment.key {
+                Some(data) => data,
+                None => {
+                    std::fs::copy(file, &target)?;
+                    continue;
+                }
+            };
+            let key_data = keys.get(&key.uri).ok_or_else(|| Error::MissingKey(key.uri.clone()))?;
+            let iv = key.iv_for(segment.sequence);
+            match key.method {
+                HlsM3u8Method::Aes128 => decrypt_video_file(key_data, &iv, file, &target)?,
+                HlsM3u8Method::SampleAes => {
+                    decrypt_ts_file(file, &target, key_data, &iv)?;
+                }
+            };
+        }
+        Ok(())
+    }
 
-        let cipher = Cipher::aes_128_cbc();
-        let decrypted_data =
-            decrypt(cipher, &key, Some(&iv), &file_data.as_slice()).expect("解析失败");
+    // 解密 fMP4 的初始化分片, #EXT-X-MAP 前面没有 AES-128 的 key 时直接复制。
+    // 按规范这时 #EXT-X-KEY 必须有 IV, 没有时按使用这个初始化分片的第一个分片的序号计算
+    fn decrypt_init(file: &str, map: Option<&HlsMap>, sequence: u64, keys: &HashMap<String, Vec<u8>>) -> Result<(), Error> {
+        let target = decrypted_file_name(file);
+        match map.and_then(|map| map.key.as_ref()) {
+            Some(key) => {
+                let key_data = keys.get(&key.uri).ok_or_else(|| Error::MissingKey(key.uri.clone()))?;
+                decrypt_video_file(key_data, &key.iv_for(sequence), file, &target)
+            }
+            None => {
+                std::fs::copy(file, &target)?;
+                Ok(())
+            }
+        }
+    }
 
-        let file_name = format!("decrypted-{}.ts", sequence_number);
-        let mut file = match File::create(&file_name) {
-            Err(why) => panic!("couldn't create: {}", why),
-            Ok(file) => file,
-        };
+    fn decrypt_video_file(key: &[u8], iv: &[u8], segment_url: &str, file_name: &str) -> Result<(), Error> {
+        let file_data = read(segment_url)?;
 
-        match file.write_all(&decrypted_data) {
-            Err(why) => panic!("couldn't write to: {}", why),
-            Ok(_) => println!("successfully wrote to {}", &file_name),
-        }
+        let cipher = Cipher::aes_128_cbc();
+        let decrypted_data = decrypt(cipher, key, Some(iv), file_data.as_slice())
+            .map_err(|e| Error::Decrypt(format!("AES-128 解密 {} 失败, {}", segment_url, e)))?;
+
+        std::fs::write(file_name, &decrypted_data)?;
+        Ok(())
     }
 
-    async fn combine_with_aes_128(
-        reg_start: i32,
-        reg_end: i32,
-        target_name: String,
-        key: String,
-        iv: String,
-        sequence: i32,
-        extension:String,
-    ) -> Result<bool, Error> {
-        let key_file = format!("./{}.bin", key.clone());
-        println!("pass key {}, iv {}", key_file.clone(), iv.clone());
-        let key_data = read(key_file).expect("打开文件失败");
-        println!("----映射的文件大小: {}", key_data.len());
-        let slice: &[u8] = &key_data; // 转为 &[u8]
-        println!("----映射的文件大小: {} {}", key_data.len(), slice.len());
-        let files = get_reg_files(format!("(.*).{}", extension.clone()), reg_start, reg_end).expect("解析失败");
-        let mut start_se = sequence;
-        for i in files.clone() {
-            let _ = decrypt_video_file(
-                slice,
-                iv.clone().as_bytes(),
-                start_se as u8,
-                &i,
-            )
-                .await;
-            start_se += 1;
+    // 解密后的文件名, 和原文件在同一目录一一对应, 如 /tmp/audio0_1.ts => /tmp/decrypted-audio0_1.ts
+    fn decrypted_file_name(file: &str) -> String {
+        match file.rfind('/') {
+            Some(index) => format!("{}decrypted-{}", &file[..=index], &file[index + 1..]),
+            None => format!("decrypted-{}", file),
         }
-        return combine_without_crypto(
-            "decrypted-(.*).ts".to_string(),
-            reg_start,
-            reg_end,
-            target_name,
-        )
-            .await;
     }
 
     fn append_file_to_output(input_path: &str, output: &mut BufWriter<File>) -> Result<(), Error> {
-        let input_file = File::open(input_path).expect("open file error");
+        let input_file = File::open(input_path)?;
         let mut reader = BufReader::new(input_file);
         let mut buffer = Vec::new();
 
-        reader.read_to_end(&mut buffer).expect("read to end error");
-        output.write_all(&buffer).expect("write all error");
+        reader.read_to_end(&mut buffer)?;
+        output.write_all(&buffer)?;
         Ok(())
     }
 
+    // init 为初始化分片的序号, 和分片按顺序直接拼接
     fn m4s_file_combine(reg_name: String,
                              reg_start: i32,
                              reg_end: i32,
-                             target_name: String, x_map_uri: String,extension:String) -> Result<bool,Error> {
+                             target_name: String, init: i32) -> Result<bool,Error> {
         // 输出文件，覆盖或创建新文件
         let output_file = OpenOptions::new()
             .create(true)
             .write(true)
             .truncate(true)
-            .open(target_name.clone()).expect("create file error");
+            .open(target_name.clone())?;
 
         let mut writer = BufWriter::new(output_file);
 
         // 要合并的文件列表（顺序非常重要）
-        let mut files:Vec<String> = vec![];
-        if !x_map_uri.is_empty() {
-            files.push(format!("-1.{}", extension.clone()));
-        }
+        let mut files:Vec<String> = vec![reg_name.replace("(.*)", &init.to_string())];
 
-        let reg_files = get_reg_files(reg_name.clone(), reg_start, reg_end).expect("解析失败");
+        let reg_files = existing_files(get_reg_files(reg_name.clone(), reg_start, reg_end)?);
         for i in reg_files.clone() {
             files.push(i.clone());
         }
 
         for file in &files.clone() {
-            println!("合并中：{}", file);
+            trace!(file = %file, "合并中");
             append_file_to_output(file, &mut writer)?;
         }
 
-        writer.flush().expect("flush error");
-        println!("合并完成：output.mp4");
+        writer.flush()?;
+        debug!(count = files.len(), "合并完成");
 
         Ok(true)
 
@@ -316,52 +449,195 @@ pub mod parse {
         // Ok(res)
     }
 
+    // 合并 WebVTT 字幕分片, 只保留第一个分片的文件头
+    pub fn combine_webvtt(reg_name: String, reg_start: i32, reg_end: i32, target_name: String) -> Result<bool, Error> {
+        let files = get_reg_files(reg_name, reg_start, reg_end)?;
+        let mut output = String::from("WEBVTT\n\n");
+        for file in files {
+            let content = match std::fs::read_to_string(&file) {
+                Ok(data) => data,
+                Err(_) => {
+                    warn!(file = %file, "字幕分片不存在");
+                    continue;
+                }
+            };
+            let content = content.replace("\r\n", "\n");
+            // 文件头(WEBVTT、X-TIMESTAMP-MAP等)到第一个空行为止
+            let body = match content.split_once("\n\n") {
+                Some((header, body)) if header.trim_start_matches('\u{feff}').starts_with("WEBVTT") => body,
+                _ => content.as_str(),
+            };
+            if !body.trim().is_empty() {
+                output.push_str(body.trim());
+                output.push_str("\n\n");
+            }
+        }
+        let mut file = File::create(target_name)?;
+        file.write_all(output.as_bytes())?;
+        Ok(true)
+    }
+
+    // 分片按 runs 分段合并, 每段是 #EXT-X-DISCONTINUITY 之间连续的分片(序号范围), 不在其中的分片被跳过。
+    // 只有一段时直接合并成 target_name, 多段时分别合并后再拼接
     pub async fn handle_combine_ts(
         reg_name: String,
-        reg_start: i32,
-        reg_end: i32,
         target_name: String,
-        method: Option<HlsM3u8Method>,
-        key: String,
-        iv: String,
-        sequence: i32,
+        segments: Vec<HlsSegment>,
+        keys: HashMap<String, Vec<u8>>,
         x_map_uri: String,
-        extension:String,
+        runs: Vec<Range<usize>>,
+        progress: &Progress,
     ) -> Result<bool, Error> {
-        if !x_map_uri.is_empty() {
-            return m4s_file_combine(reg_name.clone(), reg_start, reg_end, target_name.clone(), x_map_uri.clone(), extension.clone());
+        // 初始化分片的序号为 -1、-2..., 和下载时一致
+        let maps = distinct_maps(&segments);
+        let init = |run: &Range<usize>| -> Option<i32> {
+            match segments[run.start].map.as_ref() {
+                Some(map) => maps.iter().position(|item| *item == map).map(|index| -(index as i32) - 1),
+                None if !x_map_uri.is_empty() => Some(-1),
+                None => None,
+            }
+        };
+        if let [run] = runs.as_slice() {
+            return combine_run(&reg_name, run, init(run), target_name, &segments, &keys, progress).await;
+        }
+        debug!(runs = runs.len(), "分段合并不连续的分片");
+        let prefix = reg_name.split("(.*)").next().unwrap_or_default().to_string();
+        let extension = Path::new(&target_name).extension().map(|e| e.to_string_lossy().to_string()).unwrap_or("mp4".to_string());
+        let mut files = vec![];
+        for (index, run) in runs.iter().enumerate() {
+            let file = format!("{}run{}.{}", prefix, index, extension);
+            combine_run(&reg_name, run, init(run), file.clone(), &segments, &keys, progress).await?;
+            let duration: f64 = segments[run.clone()].iter().map(|s| s.duration).sum();
+            files.push((file, duration));
         }
-        match method {
-            Some(HlsM3u8Method::Aes128) => {
-                println!("aes 128 decode");
-                combine_with_aes_128(
-                    reg_start,
-                    reg_end,
-                    target_name,
-                    key.clone(),
-                    iv.clone(),
-                    sequence,
-                    extension.clone()
-                )
-                    .await
+        let res = join_runs(&files, &prefix, &extension, target_name, progress);
+        for (file, _) in files {
+            let _ = std::fs::remove_file(file);
+        }
+        res
+    }
+
+    // 合并一段连续的分片
+    async fn combine_run(
+        reg_name: &str,
+        run: &Range<usize>,
+        init: Option<i32>,
+        target_name: String,
+        segments: &[HlsSegment],
+        keys: &HashMap<String, Vec<u8>>,
+        progress: &Progress,
+    ) -> Result<bool, Error> {
+        let (start, end) = (run.start as i32, run.end as i32 - 1);
+        let encrypted = segments[run.clone()].iter().any(|s| s.key.is_some());
+        if let Some(init) = init {
+            if !encrypted {
+                return m4s_file_combine(reg_name.to_string(), start, end, target_name, init);
             }
-            Some(HlsM3u8Method::SampleAes) => {
-                println!("simple aes");
-                combine_with_simple_aes(
-                    reg_name,
-                    reg_start,
-                    reg_end,
-                    target_name,
-                    key.clone(),
-                    iv.clone(),
-                    sequence,
-                )
-                    .await
+            // fMP4 的 SAMPLE-AES 是 CENC 的 cbcs 加密, 需要按 sample 解密
+            if segments[run.clone()].iter().any(|s| s.key.as_ref().is_some_and(|k| k.method == HlsM3u8Method::SampleAes)) {
+                return Err(Error::Decrypt("暂不支持 SAMPLE-AES 加密的 fMP4 分片".to_string()));
             }
-            None => {
-                println!("no crypto");
-                combine_without_crypto(reg_name, reg_start, reg_end, target_name).await
+            debug!("解密 fMP4 分片后合并");
+            let first = &segments[run.start];
+            decrypt_init(&reg_name.replace("(.*)", &init.to_string()), first.map.as_ref(), first.sequence, keys)?;
+            decrypt_segments(reg_name, start, end, segments, keys)?;
+            return m4s_file_combine(decrypted_file_name(reg_name), start, end, target_name, init);
+        }
+        if encrypted {
+            debug!("解密分片后合并");
+            combine_with_crypto(reg_name.to_string(), start, end, target_name, segments.to_vec(), keys.clone(), progress).await
+        } else {
+            debug!("分片没有加密, 直接合并");
+            let duration = segments[run.clone()].iter().map(|s| s.duration).sum();
+            combine_without_crypto(reg_name.to_string(), start, end, target_name, duration, progress).await
+        }
+    }
+
+    // 拼接合并好的各段。编码参数都相同时直接拼接, 否则按时长最长的一段(一般是正片)的分辨率和音频参数重新编码后再拼接
+    fn join_runs(files: &[(String, f64)], prefix: &str, extension: &str, target_name: String, progress: &Progress) -> Result<bool, Error> {
+        let params: Vec<_> = files.iter().map(|(file, _)| get_stream_params(file)).collect();
+        let mut inputs: Vec<String> = files.iter().map(|(file, _)| file.clone()).collect();
+        let mut normalized = vec![];
+        if params.windows(2).any(|pair| pair[0] != pair[1]) {
+            let main = files.iter().enumerate().fold(0, |best, (index, item)| if item.1 > files[best].1 { index } else { best });
+            let streams = params[main].clone().unwrap_or_default();
+            let video = streams.iter().find(|s| s.codec_type == "video");
+            let audio = streams.iter().find(|s| s.codec_type == "audio");
+            let (width, height) = video.map(|s| (s.width, s.height)).unwrap_or((0, 0));
+            let sample_rate = audio.and_then(|s| s.sample_rate.parse().ok()).unwrap_or(48000);
+            let channels = audio.map(|s| s.channels).filter(|c| *c > 0).unwrap_or(2);
+            warn!(width, height, sample_rate, channels, "不连续的片段编码参数不同, 重新编码后拼接");
+            inputs.clear();
+            for (index, (file, _)) in files.iter().enumerate() {
+                let target = format!("{}normalized{}.{}", prefix, index, extension);
+                normalize_run(file.clone(), target.clone(), width, height, sample_rate, channels, progress)?;
+                normalized.push(target.clone());
+                inputs.push(target);
             }
         }
+        let list = format!("{}runs.txt", prefix);
+        white_to_files(inputs, list.clone())?;
+        let duration = files.iter().map(|(_, duration)| duration).sum();
+        let res = concat_runs(list, target_name, duration, progress);
+        for file in normalized {
+            let _ = std::fs::remove_file(file);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::combine::parse::{handle_combine_ts, to_files, white_to_files};
+    use crate::m3u8::m3u8::str_to_urls;
+    use crate::m3u8::segment_runs;
+    use crate::progress::Progress;
+    use openssl::symm::{encrypt, Cipher};
+    use std::collections::HashMap;
+    use std::fs;
+
+    #[tokio::test]
+    async fn test_combine_encrypted_fmp4() {
+        let data = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/m3u8/fmp4_aes128.m3u8")).unwrap();
+        let hls = str_to_urls(data, "https://example.com/fixtures/fmp4_aes128.m3u8".to_string()).await.unwrap();
+        let map_key = hls.list[0].map.as_ref().unwrap().key.clone().unwrap();
+        assert_eq!(map_key.uri, "https://example.com/fixtures/key1.bin");
+        assert_eq!(hls.key_uris().len(), 2);
+
+        let keys = HashMap::from([(map_key.uri.clone(), vec![1u8; 16]), ("https://example.com/fixtures/key2.bin".to_string(), vec![2u8; 16])]);
+        let dir = tempfile::tempdir().unwrap();
+        let reg_name = format!("{}/(.*).m4s", dir.path().display());
+        // 初始化分片使用 #EXT-X-MAP 前面的 key, 第二个分片换了 key, 没有 IV 时按序号计算
+        let plain = [b"ftyp-moov".to_vec(), b"moof-mdat-0".to_vec(), b"moof-mdat-1".to_vec()];
+        let files = [(-1, &map_key), (0, hls.list[0].key.as_ref().unwrap()), (1, hls.list[1].key.as_ref().unwrap())];
+        for ((index, key), (data, sequence)) in files.iter().zip(plain.iter().zip([10, 10, 11])) {
+            let encrypted = encrypt(Cipher::aes_128_cbc(), &keys[&key.uri], Some(&key.iv_for(sequence)), data).unwrap();
+            fs::write(reg_name.replace("(.*)", &index.to_string()), encrypted).unwrap();
+        }
+        let target = dir.path().join("out.mp4").display().to_string();
+        let runs = segment_runs(&hls.list);
+        handle_combine_ts(reg_name, target.clone(), hls.list.clone(), keys, hls.x_map_uri.clone(), runs, &Progress::default())
+            .await
+            .unwrap();
+        assert_eq!(fs::read(target).unwrap(), plain.concat());
+    }
+
+    #[test]
+    fn test_list_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out.mp4").display().to_string();
+        // 同一个输出目录下同时合并也不会使用同一个文件列表
+        let (a, b) = (to_files(&target).unwrap(), to_files(&target).unwrap());
+        assert_ne!(a.path(), b.path());
+        assert_eq!(a.path().parent(), Some(dir.path()));
+
+        white_to_files(vec!["a.ts".to_string(), "https://example.com/b.ts".to_string()], a.path().display().to_string()).unwrap();
+        let content = fs::read_to_string(a.path()).unwrap();
+        let expected = format!("file '{}'\nfile 'https://example.com/b.ts'\n", std::env::current_dir().unwrap().join("a.ts").display());
+        assert_eq!(content, expected);
+
+        let path = a.path().to_path_buf();
+        drop(a);
+        assert!(!path.exists());
     }
 }
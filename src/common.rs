@@ -1,7 +1,13 @@
-use std::fmt::{format, Error};
+use crate::error::Error;
+use crate::http::HttpClient;
+use crate::logging::redact_url;
+use std::collections::hash_map::RandomState;
 use std::fs;
-use std::fs::File;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::hash::{BuildHasher, Hasher};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::AsyncWriteExt;
+use tracing::warn;
 use url::Url;
 
 pub fn now() -> u64 {
@@ -12,6 +18,13 @@ pub fn now() -> u64 {
         .as_secs();
 }
 
+// 01:02:03.500 这样的时间, 用于输出播放列表中的时长和位置
+pub fn format_time(seconds: f64) -> String {
+    let millis = (seconds.max(0.0) * 1000.0).round() as u64;
+    let seconds = millis / 1000;
+    format!("{:02}:{:02}:{:02}.{:03}", seconds / 3600, seconds / 60 % 60, seconds % 60, millis % 1000)
+}
+
 pub fn is_url(_str: String) -> bool {
     let _url = &_str;
     let check_url = Url::parse(_url);
@@ -21,31 +34,284 @@ pub fn is_url(_str: String) -> bool {
     };
 }
 
-pub fn get_url_host(str: &str) -> Result<String, Error> {
-    if str.is_empty() {
-        return Ok("".to_string());
+// 按 RFC 3986 把播放列表中的地址解析成绝对地址, base 为播放列表重定向后的地址, 本地播放列表时为文件路径
+pub fn resolve_url(base: &str, reference: &str) -> String {
+    let reference = reference.trim();
+    if base.is_empty() || Url::parse(reference).is_ok() {
+        return reference.to_string();
+    }
+    let base_url = match Url::parse(base) {
+        Ok(data) => data,
+        Err(_) => {
+            let parent = Path::new(base).parent().unwrap_or(Path::new(""));
+            return parent.join(reference).display().to_string();
+        }
+    };
+    match base_url.join(reference) {
+        Ok(url) => url.to_string(),
+        Err(_) => reference.to_string(),
+    }
+}
+
+// 有的 CDN 只在播放列表地址的查询参数里带鉴权 token, 分片和 key 也需要。
+// 同一个域名下没有查询参数的地址带上 base 的查询参数, 每个路径单独签名的 CDN 不能这样处理, 所以默认不使用
+pub fn inherit_query(base: &str, url: &str) -> String {
+    let (base_url, mut parsed) = match (Url::parse(base), Url::parse(url)) {
+        (Ok(base_url), Ok(parsed)) => (base_url, parsed),
+        _ => return url.to_string(),
+    };
+    if parsed.query().is_none() && base_url.query().is_some() && parsed.origin() == base_url.origin() {
+        parsed.set_query(base_url.query());
+    }
+    parsed.to_string()
+}
+
+// 下载播放列表等文件, 返回重定向后的地址, 相对地址需要按这个地址解析
+pub async fn download_file(client: &HttpClient, url: String, file_name: String) -> Result<String, Error> {
+    fetch_file(client, url, file_name, None).await
+}
+
+// 下载失败后的重试次数和初始间隔, 第 n 次重试前等待 delay * 2^n, 并随机抖动到一半至全部,
+// 避免同时下载的分片一起重试
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub retries: u32,
+    pub delay: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Retry {
+            retries: 3,
+            delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Retry {
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let base = self.delay.saturating_mul(1 << attempt.min(16));
+        // 标准库的 RandomState 每次使用不同的随机种子, 用来生成抖动
+        let random = RandomState::new().build_hasher().finish();
+        base / 2 + base.mul_f64((random % 1000) as f64 / 2000.0)
     }
-    let binding = Url::parse(str).unwrap();
-    let url = binding.host_str().expect("解析url失败");
-    Ok(url.to_string())
 }
 
-pub fn replace_last_segment(url: &str, replacement: &str) -> String {
-    let mut components: Vec<&str> = url.split('/').collect();
-    if let Some(last_segment) = components.last_mut() {
-        *last_segment = replacement;
+// 按 retry 重试可以恢复的错误(连接失败、超时、5xx 等), 返回最后一次的结果
+pub async fn download_file_retry(client: &HttpClient, url: String, file_name: String, range: Option<(u64, u64)>, retry: Retry) -> Result<bool, Error> {
+    let mut attempt = 0;
+    loop {
+        match download_file_range(client, url.clone(), file_name.clone(), range).await {
+            Err(e) if attempt < retry.retries && e.is_retryable() => {
+                let wait = retry.backoff(attempt);
+                attempt += 1;
+                warn!(url = %redact_url(&url), error = %e, "{}ms 后第{}次重试", wait.as_millis(), attempt);
+                tokio::time::sleep(wait).await;
+            }
+            res => return res,
+        }
     }
-    components.join("/")
 }
 
-pub async fn download_file(url: String, file_name: String) -> Result<bool, Error> {
-    let resp = reqwest::get(&url).await.expect("get url data error");
-    if resp.status() == 200 {
-        let bytes = resp.bytes().await.expect("get data error");
-        fs::write(file_name.clone(), bytes).expect(format!("write file error {}", file_name.clone()).as_str());
-        Ok(true)
-    }else{
-        println!("download file status is not 200, now is {}", resp.status());
-        Ok(false)
+// 通过 HEAD 请求获取资源的 Content-Type
+pub async fn get_content_type(client: &HttpClient, url: String) -> Option<String> {
+    let resp = client.head(&url).send().await.ok()?;
+    let data = resp.headers().get(reqwest::header::CONTENT_TYPE)?;
+    data.to_str().ok().map(|d| d.to_lowercase())
+}
+
+// 下载资源的一部分, range 为 (开始位置, 长度), 通过 HTTP Range 请求
+// 内容边下载边写入 <file_name>.part, 完整后再改名, 中断时不会留下不完整的 file_name
+pub async fn download_file_range(client: &HttpClient, url: String, file_name: String, range: Option<(u64, u64)>) -> Result<bool, Error> {
+    fetch_file(client, url, file_name, range).await.map(|_| true)
+}
+
+async fn fetch_file(client: &HttpClient, url: String, file_name: String, range: Option<(u64, u64)>) -> Result<String, Error> {
+    let mut request = client.get(&url);
+    if let Some((offset, length)) = range {
+        if length == 0 {
+            return Err(Error::Parse(format!("{} 的请求范围长度为 0", redact_url(&url))));
+        }
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-{}", offset, offset + length - 1));
+    }
+    let resp = request.send().await.map_err(|e| Error::http(&url, None, e.without_url()))?;
+    let status = resp.status();
+    if status != 200 && !(status == 206 && range.is_some()) {
+        return Err(Error::http(&url, Some(status.as_u16()), status));
+    }
+    let final_url = resp.url().to_string();
+    let part_file = format!("{}.part", file_name);
+    match write_part_file(client, &url, resp, &part_file, range).await {
+        Ok(_) => {
+            fs::rename(&part_file, &file_name)?;
+            Ok(final_url)
+        }
+        Err(e) => {
+            let _ = fs::remove_file(&part_file);
+            Err(e)
+        }
     }
-}
\ No newline at end of file
+}
+
+async fn write_part_file(client: &HttpClient, url: &str, mut resp: reqwest::Response, part_file: &str, range: Option<(u64, u64)>) -> Result<(), Error> {
+    // 服务器不支持 Range 时返回了整个文件, 只保留需要的部分
+    let (start, end) = match (range, resp.status().as_u16()) {
+        (Some((offset, length)), 200) => (offset, Some(offset + length)),
+        _ => (0, None),
+    };
+    let content_length = resp.content_length();
+    let mut file = tokio::fs::File::create(part_file).await?;
+    let mut received: u64 = 0;
+    let mut written: u64 = 0;
+    let connection = client.connection_limiter();
+    // 读取内容时连接中断, 和没有收到响应一样可以重试
+    while let Some(chunk) = resp.chunk().await.map_err(|e| Error::http(url, None, e.without_url()))? {
+        client.consume(chunk.len() as u64, connection.as_ref()).await;
+        let chunk_start = received;
+        received += chunk.len() as u64;
+        let from = start.saturating_sub(chunk_start).min(chunk.len() as u64) as usize;
+        let to = match end {
+            Some(end) => end.saturating_sub(chunk_start).min(chunk.len() as u64) as usize,
+            None => chunk.len(),
+        };
+        if from < to {
+            file.write_all(&chunk[from..to]).await?;
+            written += (to - from) as u64;
+        }
+        if end.is_some_and(|end| received >= end) {
+            break;
+        }
+    }
+    file.flush().await?;
+    // 没有 Content-Length 或者 206 时连接提前关闭也会正常结束, 按请求的长度检查, 不完整的内容不能改名
+    if let Some((_, length)) = range {
+        if written != length {
+            return Err(Error::http(url, None, format!("内容长度 {} 和请求的范围长度 {} 不一致", written, length)));
+        }
+    }
+    // 截取时可能没有读完, 只检查完整读取的内容
+    if let Some(length) = content_length {
+        if end.is_none() && received != length {
+            return Err(Error::http(url, None, format!("内容长度 {} 和 Content-Length {} 不一致", received, length)));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{download_file_range, inherit_query, resolve_url};
+    use crate::http::HttpClient;
+    use crate::m3u8::m3u8::parse_url;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // 只响应一次请求的 http 服务, 返回固定内容
+    fn serve_once(response: &'static [u8]) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf);
+            let _ = stream.write_all(response);
+        });
+        format!("http://{}/a.ts", addr)
+    }
+
+    // 按顺序响应多个连接, 每个连接一次请求
+    fn serve_all(responses: Vec<String>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[test]
+    fn test_resolve_url() {
+        let base = "https://example.com/live/v1/index.m3u8";
+        assert_eq!(resolve_url(base, "0.ts"), "https://example.com/live/v1/0.ts");
+        assert_eq!(resolve_url(base, "../audio/0.aac"), "https://example.com/live/audio/0.aac");
+        assert_eq!(resolve_url(base, "/keys/1.key"), "https://example.com/keys/1.key");
+        assert_eq!(resolve_url(base, "//cdn.example.com/0.ts"), "https://cdn.example.com/0.ts");
+        assert_eq!(resolve_url(base, "https://other.com/0.ts"), "https://other.com/0.ts");
+        // 默认按 RFC 3986 解析, 不带播放列表的查询参数
+        let base = "https://example.com/v/index.m3u8?token=abc";
+        assert_eq!(resolve_url(base, "0.ts"), "https://example.com/v/0.ts");
+        assert_eq!(resolve_url(base, "?sig=1"), "https://example.com/v/index.m3u8?sig=1");
+        // 指定时同一个域名下的地址带上播放列表的 token, 自己有查询参数时不带
+        assert_eq!(inherit_query(base, "https://example.com/v/0.ts"), "https://example.com/v/0.ts?token=abc");
+        assert_eq!(inherit_query(base, "https://example.com/v/0.ts?sig=1"), "https://example.com/v/0.ts?sig=1");
+        assert_eq!(inherit_query(base, "https://cdn.example.com/0.ts"), "https://cdn.example.com/0.ts");
+        assert_eq!(inherit_query("videos/index.m3u8", "videos/0.ts"), "videos/0.ts");
+        assert_eq!(resolve_url("videos/index.m3u8", "0.ts"), "videos/0.ts");
+        assert_eq!(resolve_url("", "0.ts"), "0.ts");
+    }
+
+    #[tokio::test]
+    async fn test_resolve_after_redirect() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("index.m3u8").display().to_string();
+        let body = "#EXTM3U\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:6,\n../seg/0.m4s\n";
+        let base = serve_all(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /cdn/v/index.m3u8?token=abc\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body),
+        ]);
+        let mut hls = parse_url(&HttpClient::default(), format!("{}/index.m3u8", base), file).await.unwrap();
+        assert_eq!(hls.list[0].url, format!("{}/cdn/seg/0.m4s", base));
+        assert_eq!(hls.x_map_uri, format!("{}/cdn/v/init.mp4", base));
+        // 带上的是重定向后的播放列表地址的 token
+        hls.inherit_query();
+        assert_eq!(hls.list[0].url, format!("{}/cdn/seg/0.m4s?token=abc", base));
+        assert_eq!(hls.list[0].map.as_ref().unwrap().uri, format!("{}/cdn/v/init.mp4?token=abc", base));
+        assert_eq!(hls.x_map_uri, format!("{}/cdn/v/init.mp4?token=abc", base));
+    }
+
+    #[tokio::test]
+    async fn test_download_range_without_support() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.ts").display().to_string();
+        let url = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789");
+        download_file_range(&HttpClient::default(), url, file.clone(), Some((2, 5))).await.unwrap();
+        assert_eq!(std::fs::read(&file).unwrap(), b"23456");
+        assert!(!std::path::Path::new(&format!("{}.part", file)).exists());
+    }
+
+    #[tokio::test]
+    async fn test_download_range_closed_early() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.ts").display().to_string();
+        // 没有 Content-Length, 返回的内容比请求的范围短时连接就关闭了
+        let responses = [
+            &b"HTTP/1.1 206 Partial Content\r\nConnection: close\r\n\r\n234"[..],
+            &b"HTTP/1.1 200 OK\r\nConnection: close\r\n\r\n01234"[..],
+        ];
+        for response in responses {
+            let url = serve_once(response);
+            let res = download_file_range(&HttpClient::default(), url, file.clone(), Some((2, 5))).await;
+            assert!(res.unwrap_err().is_retryable());
+            assert!(!std::path::Path::new(&file).exists());
+            assert!(!std::path::Path::new(&format!("{}.part", file)).exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_truncated_body() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.ts").display().to_string();
+        let url = serve_once(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\nConnection: close\r\n\r\n0123");
+        let err = download_file_range(&HttpClient::default(), url, file.clone(), None).await.unwrap_err();
+        assert!(err.is_retryable());
+        // 不完整的内容不会留下文件
+        assert!(!std::path::Path::new(&file).exists());
+        assert!(!std::path::Path::new(&format!("{}.part", file)).exists());
+    }
+}
@@ -0,0 +1,600 @@
+use crate::common::resolve_url;
+use crate::m3u8::{HlsByteRange, HlsM3u8, HlsMap, HlsSegment, VariantSelect};
+use regex::Regex;
+
+// MPEG-DASH 的 MPD 清单, 只保留下载需要的信息
+#[derive(Debug, Clone, Default)]
+pub struct DashMpd {
+    pub original_url: String,
+    pub dynamic: bool,   // type="dynamic" 为直播
+    pub duration: f64,   // mediaPresentationDuration, 秒
+    pub periods: Vec<DashPeriod>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DashPeriod {
+    pub duration: f64, // 秒, 没有时为 0
+    pub adaptation_sets: Vec<DashAdaptationSet>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DashAdaptationSet {
+    pub content_type: String, // video / audio / text, 没有时从 mimeType 推断
+    pub language: String,
+    pub representations: Vec<DashRepresentation>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DashRepresentation {
+    pub id: String,
+    pub bandwidth: u64,
+    pub width: u32,
+    pub height: u32,
+    pub codecs: String,
+    pub mime_type: String,
+    pub init: Option<DashSegment>, // 初始化分片, SegmentBase 的文件自带初始化信息时为空
+    pub segments: Vec<DashSegment>,
+    pub protected: bool, // Representation 或者 AdaptationSet 上有 ContentProtection(CENC、Widevine 等 DRM), 无法解密
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DashSegment {
+    pub url: String,
+    pub byte_range: Option<HlsByteRange>,
+}
+
+// SegmentTemplate 的属性, Representation 上的会覆盖 AdaptationSet、Period 上的
+#[derive(Debug, Clone, Default)]
+struct SegmentTemplate {
+    media: Option<String>,
+    initialization: Option<String>,
+    start_number: Option<u64>,
+    timescale: Option<u64>,
+    duration: Option<u64>,
+    timeline: Option<Vec<TimelineItem>>,
+}
+
+// SegmentTimeline 中的 <S t="" d="" r="">
+#[derive(Debug, Clone)]
+struct TimelineItem {
+    t: Option<u64>,
+    d: u64,
+    r: i64,
+}
+
+impl DashMpd {
+    // 有 Period 的视频或者音频只有 DRM 加密的 Representation, 下载后无法播放
+    pub fn is_protected(&self) -> bool {
+        self.periods.iter().any(|period| {
+            ["video", "audio"].iter().any(|content_type| {
+                let mut reps = period.adaptation_sets.iter().filter(|a| a.content_type == *content_type).flat_map(|a| a.representations.iter());
+                reps.clone().next().is_some() && reps.all(|r| r.protected)
+            })
+        })
+    }
+
+    // 按 --variant 选择每个 Period 的视频 Representation, 跳过 DRM 加密的
+    pub fn select_video(&self, select: &VariantSelect) -> Vec<&DashRepresentation> {
+        let mut list = vec![];
+        for period in &self.periods {
+            let reps: Vec<&DashRepresentation> = period
+                .adaptation_sets
+                .iter()
+                .filter(|a| a.content_type == "video")
+                .flat_map(|a| a.representations.iter())
+                .filter(|r| !r.protected)
+                .collect();
+            let rep = match select {
+                VariantSelect::Highest => reps.iter().max_by_key(|r| r.bandwidth),
+                VariantSelect::Lowest => reps.iter().min_by_key(|r| r.bandwidth),
+                VariantSelect::Index(index) => reps.get(*index),
+                VariantSelect::Height(height) => reps.iter().min_by(|a, b| {
+                    let diff_a = (a.height as i64 - *height as i64).abs();
+                    let diff_b = (b.height as i64 - *height as i64).abs();
+                    diff_a.cmp(&diff_b).then(b.bandwidth.cmp(&a.bandwidth))
+                }),
+            };
+            if let Some(rep) = rep {
+                list.push(*rep);
+            }
+        }
+        list
+    }
+
+    // 选择每个 Period 的音频 Representation, 优先匹配语言, 同一语言取码率最高的, 跳过 DRM 加密的
+    pub fn select_audio(&self, language: &str) -> Vec<(&DashRepresentation, String)> {
+        let mut list = vec![];
+        for period in &self.periods {
+            let sets: Vec<&DashAdaptationSet> = period
+                .adaptation_sets
+                .iter()
+                .filter(|a| a.content_type == "audio" && a.representations.iter().any(|r| !r.protected))
+                .collect();
+            let target = language.trim().to_lowercase();
+            let set = sets
+                .iter()
+                .find(|a| {
+                    let own = a.language.to_lowercase();
+                    !target.is_empty() && (own == target || own.starts_with(&format!("{}-", target)))
+                })
+                .or(sets.first());
+            if let Some(set) = set {
+                if let Some(rep) = set.representations.iter().filter(|r| !r.protected).max_by_key(|r| r.bandwidth) {
+                    list.push((rep, set.language.clone()));
+                }
+            }
+        }
+        list
+    }
+
+    // 把选中的 Representation 转换成播放列表, 复用 HLS 的下载和合并流程
+    pub fn to_hls(&self, reps: &[&DashRepresentation]) -> HlsM3u8 {
+        let mut hls_m3u8 = HlsM3u8::new();
+        hls_m3u8.set_original_url(self.original_url.clone());
+        hls_m3u8.endlist = !self.dynamic;
+        let mut list = vec![];
+        for (index, rep) in reps.iter().enumerate() {
+            let map = rep.init.as_ref().map(|init| HlsMap {
+                uri: init.url.clone(),
+                byte_range: init.byte_range,
+                key: None,
+            });
+            for (position, item) in rep.segments.iter().enumerate() {
+                list.push(HlsSegment {
+                    url: item.url.clone(),
+                    sequence: list.len() as u64,
+                    byte_range: item.byte_range,
+                    // 每个 Period 的第一个分片和上一个 Period 不连续
+                    discontinuity: index > 0 && position == 0,
+                    map: map.clone(),
+                    ..HlsSegment::default()
+                });
+            }
+        }
+        // 多个 Period 时只使用第一个初始化分片, 编码参数不同的 Period 无法直接拼接
+        if let Some(init) = reps.first().and_then(|r| r.init.clone()) {
+            hls_m3u8.set_x_map_uri(init.url);
+            hls_m3u8.set_x_map_byte_range(init.byte_range);
+        } else {
+            hls_m3u8.set_extension("mp4".to_string());
+        }
+        hls_m3u8.set_list(list);
+        hls_m3u8
+    }
+}
+
+impl SegmentTemplate {
+    fn merge(&self, node: roxmltree::Node) -> SegmentTemplate {
+        let mut template = self.clone();
+        let element = match child(node, "SegmentTemplate") {
+            Some(element) => element,
+            None => return template,
+        };
+        if let Some(data) = element.attribute("media") {
+            template.media = Some(data.to_string());
+        }
+        if let Some(data) = element.attribute("initialization") {
+            template.initialization = Some(data.to_string());
+        }
+        if let Some(data) = element.attribute("startNumber").and_then(|d| d.parse().ok()) {
+            template.start_number = Some(data);
+        }
+        if let Some(data) = element.attribute("timescale").and_then(|d| d.parse().ok()) {
+            template.timescale = Some(data);
+        }
+        if let Some(data) = element.attribute("duration").and_then(|d| d.parse().ok()) {
+            template.duration = Some(data);
+        }
+        if let Some(timeline) = child(element, "SegmentTimeline") {
+            template.timeline = Some(
+                children(timeline, "S")
+                    .map(|s| TimelineItem {
+                        t: s.attribute("t").and_then(|d| d.parse().ok()),
+                        d: s.attribute("d").and_then(|d| d.parse().ok()).unwrap_or(0),
+                        r: s.attribute("r").and_then(|d| d.parse().ok()).unwrap_or(0),
+                    })
+                    .collect(),
+            );
+        }
+        template
+    }
+
+    // 展开成分片列表, 返回 (初始化分片, 分片列表)
+    fn segments(&self, rep: &DashRepresentation, base: &str, period_duration: f64) -> (Option<DashSegment>, Vec<DashSegment>) {
+        let init = self.initialization.as_ref().map(|data| DashSegment {
+            url: resolve(base, &fill_template(data, rep, 0, 0)),
+            byte_range: None,
+        });
+        let media = match &self.media {
+            Some(media) => media,
+            None => return (init, vec![]),
+        };
+        let timescale = self.timescale.unwrap_or(1).max(1);
+        let mut number = self.start_number.unwrap_or(1);
+        let mut list = vec![];
+        match &self.timeline {
+            Some(timeline) => {
+                let mut time = 0;
+                for (index, item) in timeline.iter().enumerate() {
+                    if let Some(t) = item.t {
+                        time = t;
+                    }
+                    if item.d == 0 {
+                        continue;
+                    }
+                    // r 为 -1 时重复到下一个 S 的开始时间, 或者 Period 结束
+                    let mut repeat = item.r;
+                    if repeat < 0 {
+                        let end = match timeline.get(index + 1).and_then(|n| n.t) {
+                            Some(t) => t,
+                            None => (period_duration * timescale as f64) as u64,
+                        };
+                        repeat = (end.saturating_sub(time)).div_ceil(item.d) as i64 - 1;
+                    }
+                    for _ in 0..=repeat.max(0) {
+                        list.push(DashSegment {
+                            url: resolve(base, &fill_template(media, rep, number, time)),
+                            byte_range: None,
+                        });
+                        time += item.d;
+                        number += 1;
+                    }
+                }
+            }
+            None => {
+                let duration = self.duration.unwrap_or(0);
+                if duration == 0 {
+                    return (init, list);
+                }
+                let count = (period_duration * timescale as f64 / duration as f64).ceil() as u64;
+                for index in 0..count {
+                    list.push(DashSegment {
+                        url: resolve(base, &fill_template(media, rep, number, index * duration)),
+                        byte_range: None,
+                    });
+                    number += 1;
+                }
+            }
+        }
+        (init, list)
+    }
+}
+
+fn child<'a, 'input>(node: roxmltree::Node<'a, 'input>, name: &str) -> Option<roxmltree::Node<'a, 'input>> {
+    node.children().find(|n| n.is_element() && n.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(node: roxmltree::Node<'a, 'input>, name: &'a str) -> impl Iterator<Item = roxmltree::Node<'a, 'input>> + 'a {
+    node.children().filter(move |n| n.is_element() && n.tag_name().name() == name)
+}
+
+// 当前节点的 BaseURL 相对上一级地址解析
+fn base_url(node: roxmltree::Node, parent: &str) -> String {
+    match child(node, "BaseURL").and_then(|n| n.text()) {
+        Some(data) => resolve(parent, data.trim()),
+        None => parent.to_string(),
+    }
+}
+
+fn resolve(base: &str, uri: &str) -> String {
+    if uri.is_empty() {
+        return base.to_string();
+    }
+    resolve_url(base, uri)
+}
+
+// 替换 $RepresentationID$、$Number$、$Time$、$Bandwidth$, 支持 %05d 这样的宽度
+fn fill_template(template: &str, rep: &DashRepresentation, number: u64, time: u64) -> String {
+    let re = Regex::new(r"\$(RepresentationID|Number|Time|Bandwidth)(?:%0(\d+)d)?\$").unwrap();
+    let str = re.replace_all(template, |caps: &regex::Captures| {
+        let width: usize = caps.get(2).and_then(|w| w.as_str().parse().ok()).unwrap_or(0);
+        match &caps[1] {
+            "RepresentationID" => rep.id.clone(),
+            "Number" => format!("{:0width$}", number, width = width),
+            "Time" => format!("{:0width$}", time, width = width),
+            _ => format!("{:0width$}", rep.bandwidth, width = width),
+        }
+    });
+    str.replace("$$", "$")
+}
+
+// 解析 ISO 8601 时长, 如 PT1H2M3.5S, 返回秒
+fn parse_duration(str: &str) -> f64 {
+    let re = Regex::new(r"^P(?:(\d+(?:\.\d+)?)D)?(?:T(?:(\d+(?:\.\d+)?)H)?(?:(\d+(?:\.\d+)?)M)?(?:(\d+(?:\.\d+)?)S)?)?$").unwrap();
+    let caps = match re.captures(str.trim()) {
+        Some(caps) => caps,
+        None => return 0.0,
+    };
+    let value = |index: usize| -> f64 { caps.get(index).and_then(|d| d.as_str().parse().ok()).unwrap_or(0.0) };
+    value(1) * 86400.0 + value(2) * 3600.0 + value(3) * 60.0 + value(4)
+}
+
+// 解析 "开始-结束" 形式的字节范围(包含结束位置)
+fn parse_range(str: Option<&str>) -> Option<HlsByteRange> {
+    let (start, end) = str?.trim().split_once('-')?;
+    let start: u64 = start.trim().parse().ok()?;
+    let end: u64 = end.trim().parse().ok()?;
+    if end < start {
+        return None;
+    }
+    Some(HlsByteRange { length: end - start + 1, offset: start })
+}
+
+fn parse_representation(
+    node: roxmltree::Node,
+    set_node: roxmltree::Node,
+    period_node: roxmltree::Node,
+    template: &SegmentTemplate,
+    base: &str,
+    period_duration: f64,
+) -> DashRepresentation {
+    let attribute = |name: &str| -> String {
+        node.attribute(name).or(set_node.attribute(name)).unwrap_or_default().to_string()
+    };
+    let mut rep = DashRepresentation {
+        id: attribute("id"),
+        bandwidth: attribute("bandwidth").parse().unwrap_or(0),
+        width: attribute("width").parse().unwrap_or(0),
+        height: attribute("height").parse().unwrap_or(0),
+        codecs: attribute("codecs"),
+        mime_type: attribute("mimeType"),
+        init: None,
+        segments: vec![],
+        protected: child(node, "ContentProtection").or(child(set_node, "ContentProtection")).is_some(),
+    };
+    let base = base_url(node, base);
+    let template = template.merge(node);
+    if template.media.is_some() || template.initialization.is_some() {
+        let (init, segments) = template.segments(&rep, &base, period_duration);
+        rep.init = init;
+        rep.segments = segments;
+        return rep;
+    }
+    // SegmentList 和 SegmentBase 可以在 Representation、AdaptationSet 或 Period 上
+    let find = |name: &str| child(node, name).or(child(set_node, name)).or(child(period_node, name));
+    if let Some(segment_list) = find("SegmentList") {
+        rep.init = child(segment_list, "Initialization").map(|n| DashSegment {
+            url: resolve(&base, n.attribute("sourceURL").unwrap_or_default()),
+            byte_range: parse_range(n.attribute("range")),
+        });
+        rep.segments = children(segment_list, "SegmentURL")
+            .map(|n| DashSegment {
+                url: resolve(&base, n.attribute("media").unwrap_or_default()),
+                byte_range: parse_range(n.attribute("mediaRange")),
+            })
+            .collect();
+        return rep;
+    }
+    // SegmentBase 或者只有 BaseURL: 整个文件就是一个自带初始化信息的分片
+    if find("SegmentBase").is_some() || child(node, "BaseURL").is_some() {
+        rep.segments = vec![DashSegment { url: base, byte_range: None }];
+    }
+    rep
+}
+
+pub mod dash {
+    use crate::common::download_file;
+    use crate::http::HttpClient;
+    use crate::error::Error;
+    use crate::dash::{base_url, children, parse_duration, parse_representation, DashAdaptationSet, DashMpd, DashPeriod, SegmentTemplate};
+    use std::fs::File;
+    use std::io::Read;
+
+    pub async fn parse_local(local_file: String, target_url: String) -> Result<DashMpd, Error> {
+        let mut data = File::open(local_file)?;
+        let mut str = String::default();
+        data.read_to_string(&mut str)?;
+        str_to_mpd(str, target_url)
+    }
+
+    pub fn str_to_mpd(str: String, url: String) -> Result<DashMpd, Error> {
+        let doc = roxmltree::Document::parse(&str).map_err(|e| Error::Parse(format!("MPD {}", e)))?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "MPD" {
+            return Err(Error::Parse(format!("{} 不是MPD文件", url)));
+        }
+        let mut mpd = DashMpd {
+            original_url: url.clone(),
+            dynamic: root.attribute("type") == Some("dynamic"),
+            duration: parse_duration(root.attribute("mediaPresentationDuration").unwrap_or_default()),
+            periods: vec![],
+        };
+        let mpd_base = base_url(root, &url);
+        for period_node in children(root, "Period") {
+            let period_base = base_url(period_node, &mpd_base);
+            let mut period = DashPeriod {
+                duration: parse_duration(period_node.attribute("duration").unwrap_or_default()),
+                adaptation_sets: vec![],
+            };
+            // 只有一个 Period 且没有写 duration 时, 使用整个清单的时长
+            let period_duration = if period.duration > 0.0 { period.duration } else { mpd.duration };
+            let period_template = SegmentTemplate::default().merge(period_node);
+            for set_node in children(period_node, "AdaptationSet") {
+                let set_base = base_url(set_node, &period_base);
+                let set_template = period_template.merge(set_node);
+                let mime_type = set_node.attribute("mimeType").unwrap_or_default();
+                let mut content_type = set_node.attribute("contentType").unwrap_or_default().to_string();
+                let mut set = DashAdaptationSet {
+                    content_type: String::default(),
+                    language: set_node.attribute("lang").unwrap_or_default().to_string(),
+                    representations: vec![],
+                };
+                for node in children(set_node, "Representation") {
+                    set.representations.push(parse_representation(node, set_node, period_node, &set_template, &set_base, period_duration));
+                }
+                if content_type.is_empty() {
+                    let mime = set.representations.first().map(|r| r.mime_type.clone()).unwrap_or(mime_type.to_string());
+                    content_type = mime.split('/').next().unwrap_or_default().to_string();
+                    if content_type == "application" {
+                        content_type = "text".to_string();
+                    }
+                }
+                set.content_type = content_type;
+                period.adaptation_sets.push(set);
+            }
+            mpd.periods.push(period);
+        }
+        Ok(mpd)
+    }
+
+    pub async fn parse_url(client: &HttpClient, url: String, mpd_file_name: String) -> Result<DashMpd, Error> {
+        let final_url = download_file(client, url.clone(), mpd_file_name.clone()).await?;
+        parse_local(mpd_file_name, final_url).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dash::dash::str_to_mpd;
+    use crate::dash::parse_duration;
+    use crate::m3u8::{HlsByteRange, VariantSelect};
+
+    const TEMPLATE: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<MPD xmlns="urn:mpeg:dash:schema:mpd:2011" type="static" mediaPresentationDuration="PT0M10S">
+  <BaseURL>media/</BaseURL>
+  <Period id="0">
+    <AdaptationSet mimeType="video/mp4" codecs="avc1.64001f">
+      <SegmentTemplate media="$RepresentationID$/seg-$Number%03d$.m4s" initialization="$RepresentationID$/init.mp4" startNumber="1" timescale="1000" duration="4000"/>
+      <Representation id="v360" bandwidth="800000" width="640" height="360"/>
+      <Representation id="v720" bandwidth="2500000" width="1280" height="720"/>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" lang="en" mimeType="audio/mp4">
+      <Representation id="a_en" bandwidth="128000">
+        <SegmentTemplate media="audio/en/$Time$.m4s" initialization="audio/en/init.mp4" timescale="48000">
+          <SegmentTimeline>
+            <S t="0" d="96000" r="3"/>
+            <S d="48000"/>
+          </SegmentTimeline>
+        </SegmentTemplate>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet contentType="audio" lang="ja" mimeType="audio/mp4">
+      <SegmentTemplate media="audio/ja/$Number$.m4s" initialization="audio/ja/init.mp4" duration="5" startNumber="0"/>
+      <Representation id="a_ja" bandwidth="96000"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    const SEGMENT_LIST: &str = r#"<MPD type="static" mediaPresentationDuration="PT6S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <Representation id="1" bandwidth="1000" height="480">
+        <BaseURL>https://cdn.example.com/video.mp4</BaseURL>
+        <SegmentList>
+          <Initialization range="0-799"/>
+          <SegmentURL mediaRange="800-1999"/>
+          <SegmentURL mediaRange="2000-2999"/>
+        </SegmentList>
+      </Representation>
+    </AdaptationSet>
+    <AdaptationSet mimeType="audio/mp4">
+      <Representation id="2" bandwidth="64000">
+        <BaseURL>audio.mp4</BaseURL>
+        <SegmentBase indexRange="700-900"><Initialization range="0-699"/></SegmentBase>
+      </Representation>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("PT1H2M3.5S"), 3723.5);
+        assert_eq!(parse_duration("P1DT1S"), 86401.0);
+        assert_eq!(parse_duration("PT0S"), 0.0);
+        assert_eq!(parse_duration("abc"), 0.0);
+    }
+
+    #[test]
+    fn test_parse_segment_template() {
+        let mpd = str_to_mpd(TEMPLATE.to_string(), "https://example.com/dash/manifest.mpd".to_string()).unwrap();
+        assert!(!mpd.dynamic);
+        assert_eq!(mpd.duration, 10.0);
+        assert_eq!(mpd.periods.len(), 1);
+
+        let video = mpd.select_video(&VariantSelect::Highest);
+        assert_eq!(video.len(), 1);
+        assert_eq!(video[0].id, "v720");
+        assert_eq!(video[0].height, 720);
+        assert_eq!(video[0].codecs, "avc1.64001f");
+        assert_eq!(video[0].init.as_ref().unwrap().url, "https://example.com/dash/media/v720/init.mp4");
+        let urls: Vec<&str> = video[0].segments.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(
+            urls,
+            vec![
+                "https://example.com/dash/media/v720/seg-001.m4s",
+                "https://example.com/dash/media/v720/seg-002.m4s",
+                "https://example.com/dash/media/v720/seg-003.m4s",
+            ]
+        );
+        assert_eq!(mpd.select_video(&VariantSelect::Height(360))[0].id, "v360");
+
+        // SegmentTimeline: 4 个 2 秒 + 1 个 1 秒
+        let audio = mpd.select_audio("en");
+        assert_eq!(audio[0].1, "en");
+        let times: Vec<&str> = audio[0].0.segments.iter().map(|s| s.url.rsplit('/').next().unwrap()).collect();
+        assert_eq!(times, vec!["0.m4s", "96000.m4s", "192000.m4s", "288000.m4s", "384000.m4s"]);
+
+        let audio = mpd.select_audio("ja");
+        assert_eq!(audio[0].0.id, "a_ja");
+        assert_eq!(audio[0].0.segments.len(), 2);
+        assert_eq!(audio[0].0.segments[0].url, "https://example.com/dash/media/audio/ja/0.m4s");
+
+        let hls = mpd.to_hls(&video);
+        assert_eq!(hls.list.len(), 3);
+        assert_eq!(hls.x_map_uri, "https://example.com/dash/media/v720/init.mp4");
+        assert!(hls.endlist);
+    }
+
+    #[test]
+    fn test_parse_segment_list_and_base() {
+        let mpd = str_to_mpd(SEGMENT_LIST.to_string(), "https://example.com/dash/manifest.mpd".to_string()).unwrap();
+        let video = mpd.select_video(&VariantSelect::Lowest);
+        let init = video[0].init.clone().unwrap();
+        assert_eq!(init.url, "https://cdn.example.com/video.mp4");
+        assert_eq!(init.byte_range, Some(HlsByteRange { length: 800, offset: 0 }));
+        assert_eq!(video[0].segments.len(), 2);
+        assert_eq!(video[0].segments[1].byte_range, Some(HlsByteRange { length: 1000, offset: 2000 }));
+
+        // 没有语言匹配时选择第一个音频
+        let audio = mpd.select_audio("fr");
+        assert_eq!(audio[0].0.segments.len(), 1);
+        assert_eq!(audio[0].0.segments[0].url, "https://example.com/dash/audio.mp4");
+        assert!(audio[0].0.init.is_none());
+        let hls = mpd.to_hls(&[audio[0].0]);
+        assert!(hls.x_map_uri.is_empty());
+        assert_eq!(hls.extension, "mp4");
+    }
+
+    #[test]
+    fn test_content_protection() {
+        // 加密的 AdaptationSet 被跳过, 有不加密的可以选择时正常下载
+        let mpd = r#"<MPD type="static" mediaPresentationDuration="PT4S">
+  <Period>
+    <AdaptationSet mimeType="video/mp4">
+      <ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc"/>
+      <ContentProtection schemeIdUri="urn:uuid:edef8ba9-79d6-4ace-a3c8-27dcd51d21ed"/>
+      <SegmentTemplate media="drm/$Number$.m4s" initialization="drm/init.mp4" duration="4"/>
+      <Representation id="drm1080" bandwidth="5000000" height="1080"/>
+    </AdaptationSet>
+    <AdaptationSet mimeType="video/mp4">
+      <SegmentTemplate media="clear/$Number$.m4s" initialization="clear/init.mp4" duration="4"/>
+      <Representation id="clear480" bandwidth="800000" height="480"/>
+    </AdaptationSet>
+  </Period>
+</MPD>"#;
+        let mpd = str_to_mpd(mpd.to_string(), "https://example.com/dash/manifest.mpd".to_string()).unwrap();
+        assert!(mpd.periods[0].adaptation_sets[0].representations[0].protected);
+        assert!(!mpd.is_protected());
+        assert_eq!(mpd.select_video(&VariantSelect::Highest)[0].id, "clear480");
+
+        // 只有加密的 Representation 时无法下载
+        let mpd = TEMPLATE.replace(r#"<Representation id="a_en" bandwidth="128000">"#, r#"<Representation id="a_en" bandwidth="128000"><ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc"/>"#);
+        let mpd = str_to_mpd(mpd, "https://example.com/dash/manifest.mpd".to_string()).unwrap();
+        assert!(!mpd.is_protected());
+        assert_eq!(mpd.select_audio("en")[0].0.id, "a_ja");
+        let mpd = TEMPLATE.replace(r#"codecs="avc1.64001f">"#, r#"codecs="avc1.64001f"><ContentProtection schemeIdUri="urn:mpeg:dash:mp4protection:2011" value="cenc"/>"#);
+        let mpd = str_to_mpd(mpd, "https://example.com/dash/manifest.mpd".to_string()).unwrap();
+        assert!(mpd.is_protected());
+        assert!(mpd.select_video(&VariantSelect::Highest).is_empty());
+    }
+}
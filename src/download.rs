@@ -1,13 +1,30 @@
-use crate::common::download_file;
-use serde::{Deserialize, Serialize};
+use crate::cmd::cmd::{clear_temp_files, download};
+use crate::common::{download_file_retry, now, resolve_url, Retry};
+use crate::http::{Cookie, HttpClient};
+use crate::logging::redact_url;
+use crate::manifest::{Manifest, ManifestStore};
+use crate::verify::VerifyMode;
+use crate::progress::Progress;
+use crate::m3u8::{distinct_maps, main_runs, segment_runs, HlsByteRange, HlsM3u8, HlsSegment, TimePoint, VariantSelect};
+use std::collections::HashMap;
 use std::fs;
-use std::io::{Error, Read};
-use tokio::runtime::Runtime;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, info, warn, Instrument};
+use url::Url;
 
 struct VideoTs {
     index: i32,
     url: String,
     extension:String,
+    prefix: String, // 文件路径前缀, 包含下载目录, 用于区分视频、音频和字幕的分片
+    byte_range: Option<(u64, u64)>, // (开始位置, 长度)
 }
 
 impl VideoTs {
@@ -16,190 +33,1201 @@ impl VideoTs {
             index: 0,
             url: "".to_string(),
             extension: "".to_string(),
+            prefix: "".to_string(),
+            byte_range: None,
         }
     }
 
-    pub fn set(&mut self, index: i32, url: String, extension:String) {
+    pub fn set(&mut self, index: i32, url: String, extension:String, prefix: String) {
         self.index = index;
         self.url = url.clone();
-        self.extension = extension.clone()
+        self.extension = extension.clone();
+        self.prefix = prefix
+    }
+
+    pub fn file_name(&self) -> String {
+        format!("{}{}.{}", self.prefix, self.index, self.extension)
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-pub struct BaseInfo {
-    pub url: String,
-    pub m3u8_name: String,
+// 下载任务, 库的调用方通过它下载视频:
+// Downloader::new(url).concurrency(10).output("out.mp4").run().await
+#[derive(Debug, Clone)]
+pub struct Downloader {
+    pub(crate) url: String,
+    pub(crate) output: String,          // 输出文件, 相对路径时放在下载目录中
+    pub(crate) folder: Option<PathBuf>, // 下载目录, 默认为 <download_dir>/<url path 的 md5>
+    pub(crate) download_dir: PathBuf,
+    pub(crate) concurrent: i32,
+    pub(crate) variant: VariantSelect,
+    pub(crate) audio_lang: String,
+    pub(crate) subs: Vec<String>,
+    pub(crate) live: bool,
+    pub(crate) max_duration: u64,
+    pub(crate) use_ffmpeg: bool,
+    pub(crate) clean: bool,
+    pub(crate) progress: Arc<Progress>,
+    pub(crate) retry: Retry,
+    pub(crate) allow_gaps: bool,
+    pub(crate) headers: Vec<(String, String)>, // 所有请求都带上的请求头
+    pub(crate) cookies: Vec<Cookie>,
+    pub(crate) cookie_file: Option<PathBuf>,
+    pub(crate) proxy: Option<String>,
+    pub(crate) limit_rate: u64,
+    pub(crate) connection_rate: u64,
+    pub(crate) resume: bool,
+    pub(crate) verify: VerifyMode,
+    pub(crate) skip_discontinuities: bool,
+    pub(crate) from: Option<TimePoint>,
+    pub(crate) to: Option<TimePoint>,
+    pub(crate) inherit_query: bool,
 }
 
-impl BaseInfo {
-    pub fn new() -> BaseInfo {
-        BaseInfo {
-            url: "".to_string(),
-            m3u8_name: "".to_string(),
+impl Downloader {
+    pub fn new(url: impl Into<String>) -> Downloader {
+        Downloader {
+            url: url.into(),
+            output: String::default(),
+            folder: None,
+            download_dir: PathBuf::from("download"),
+            concurrent: 10,
+            variant: VariantSelect::Highest,
+            audio_lang: String::default(),
+            subs: vec![],
+            live: false,
+            max_duration: 0,
+            use_ffmpeg: false,
+            clean: true,
+            progress: Arc::new(Progress::default()),
+            retry: Retry::default(),
+            allow_gaps: false,
+            headers: vec![],
+            cookies: vec![],
+            cookie_file: None,
+            proxy: None,
+            limit_rate: 0,
+            connection_rate: 0,
+            resume: false,
+            verify: VerifyMode::Quick,
+            skip_discontinuities: false,
+            from: None,
+            to: None,
+            inherit_query: false,
+        }
+    }
+
+    // 从下载目录中的 manifest.json 继续下载, 不需要 url, 变体、音轨、字幕和输出文件名都和上次一样
+    pub fn resume(folder: impl Into<PathBuf>) -> Downloader {
+        let mut downloader = Downloader::new("").folder(folder);
+        downloader.resume = true;
+        downloader
+    }
+
+    // 继续下载时可以换一个地址, 比如带签名的地址过期了
+    pub fn url(mut self, url: impl Into<String>) -> Self {
+        self.url = url.into();
+        self
+    }
+
+    pub fn concurrency(mut self, concurrent: i32) -> Self {
+        self.concurrent = concurrent;
+        self
+    }
+
+    pub fn output(mut self, output: impl Into<String>) -> Self {
+        self.output = output.into();
+        self
+    }
+
+    pub fn folder(mut self, folder: impl Into<PathBuf>) -> Self {
+        self.folder = Some(folder.into());
+        self
+    }
+
+    // 没有指定下载目录时, 在这个目录下按 url 创建下载目录
+    pub fn download_dir(mut self, download_dir: impl Into<PathBuf>) -> Self {
+        self.download_dir = download_dir.into();
+        self
+    }
+
+    pub fn variant(mut self, variant: VariantSelect) -> Self {
+        self.variant = variant;
+        self
+    }
+
+    pub fn audio_lang(mut self, audio_lang: impl Into<String>) -> Self {
+        self.audio_lang = audio_lang.into();
+        self
+    }
+
+    pub fn subtitles(mut self, subs: Vec<String>) -> Self {
+        self.subs = subs;
+        self
+    }
+
+    pub fn live(mut self, live: bool) -> Self {
+        self.live = live;
+        self
+    }
+
+    pub fn max_duration(mut self, seconds: u64) -> Self {
+        self.max_duration = seconds;
+        self
+    }
+
+    // 直接用 ffmpeg 下载, 不解析播放列表
+    pub fn use_ffmpeg(mut self, use_ffmpeg: bool) -> Self {
+        self.use_ffmpeg = use_ffmpeg;
+        self
+    }
+
+    // 使用外部的进度, 用于在其他线程查看进度或取消下载
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    // 下载成功后是否清理分片等临时文件, 默认清理
+    pub fn clean(mut self, clean: bool) -> Self {
+        self.clean = clean;
+        self
+    }
+
+    // 分片下载失败后的重试次数, 默认 3 次
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry.retries = retries;
+        self
+    }
+
+    // 第一次重试前的等待时间, 之后每次翻倍, 默认 1 秒
+    pub fn retry_delay(mut self, delay: Duration) -> Self {
+        self.retry.delay = delay;
+        self
+    }
+
+    // 重试后仍有分片失败时是否跳过这些分片继续合并, 默认返回 Error::Incomplete
+    pub fn allow_gaps(mut self, allow_gaps: bool) -> Self {
+        self.allow_gaps = allow_gaps;
+        self
+    }
+
+    // 播放列表、key、初始化分片和所有分片的请求都带上这个请求头, 使用 ffmpeg 下载时通过 -headers 传递
+    pub fn header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn user_agent(self, user_agent: impl Into<String>) -> Self {
+        self.header("User-Agent", user_agent)
+    }
+
+    pub fn referer(self, referer: impl Into<String>) -> Self {
+        self.header("Referer", referer)
+    }
+
+    // "a=1; b=2" 格式, 发送给所有地址
+    pub fn cookie(mut self, cookie: &str) -> Self {
+        self.cookies.extend(Cookie::parse_pairs(cookie));
+        self
+    }
+
+    // Netscape 格式的 cookie 文件, 按域名、路径匹配后发送
+    pub fn cookie_file(mut self, file: impl Into<PathBuf>) -> Self {
+        self.cookie_file = Some(file.into());
+        self
+    }
+
+    // 所有请求使用的代理, 如 http://127.0.0.1:8080、socks5h://127.0.0.1:1080,
+    // 没有指定时使用 HTTP_PROXY、HTTPS_PROXY、NO_PROXY 环境变量
+    pub fn proxy(mut self, proxy: impl Into<String>) -> Self {
+        self.proxy = Some(proxy.into());
+        self
+    }
+
+    // 所有下载共用的限速, 单位：字节/秒, 0 表示不限制
+    pub fn limit_rate(mut self, rate: u64) -> Self {
+        self.limit_rate = rate;
+        self
+    }
+
+    // 每个连接的限速, 单位：字节/秒, 0 表示不限制
+    pub fn limit_rate_per_connection(mut self, rate: u64) -> Self {
+        self.connection_rate = rate;
+        self
+    }
+
+    // 合并前检查分片的方式, 默认只检查文件结构, 有问题的分片重新下载
+    pub fn verify(mut self, verify: VerifyMode) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    // 跳过 #EXT-X-DISCONTINUITY 分隔的广告等片段, 只保留正片
+    pub fn skip_discontinuities(mut self, skip: bool) -> Self {
+        self.skip_discontinuities = skip;
+        self
+    }
+
+    // 只下载 from 到 to 之间的内容, 合并后精确截取, 不指定时从头开始或者到结尾
+    pub fn time_range(mut self, from: Option<TimePoint>, to: Option<TimePoint>) -> Self {
+        self.from = from;
+        self.to = to;
+        self
+    }
+
+    // 播放列表中同一个域名下没有查询参数的地址带上播放列表地址的查询参数(鉴权 token),
+    // 默认按 RFC 3986 解析, 只在 CDN 只给播放列表签名时使用
+    pub fn inherit_query(mut self, inherit: bool) -> Self {
+        self.inherit_query = inherit;
+        self
+    }
+
+    // 按请求头、cookie、代理和限速创建这次下载共用的 http 客户端
+    pub fn http_client(&self) -> Result<HttpClient, crate::error::Error> {
+        let mut cookies = self.cookies.clone();
+        if let Some(file) = &self.cookie_file {
+            cookies.extend(Cookie::read_netscape(file)?);
+        }
+        Ok(HttpClient::new(self.concurrent.max(1) as usize, self.headers.clone(), cookies, self.proxy.clone())?
+            .limit_rate(self.limit_rate, self.connection_rate)
+            .progress(self.progress.clone()))
+    }
+
+    // 下载目录, 没有指定时按 url 的 path 计算 md5 作为目录名
+    pub fn get_folder(&self) -> PathBuf {
+        if let Some(folder) = &self.folder {
+            return folder.clone();
         }
+        let folder_name = match Url::parse(&self.url) {
+            Ok(data) => format!("{:x}", md5::compute(data.path())),
+            Err(_) => format!("{}", now()),
+        };
+        self.download_dir.join(folder_name)
     }
-    pub fn set_host(&mut self, url: String) {
-        self.url = url
+
+    // 继续下载时使用上次的参数, 指定了的 url 和输出文件名优先
+    fn restore(&mut self, manifest: &Manifest) -> Result<(), crate::error::Error> {
+        if self.url.is_empty() {
+            self.url = manifest.url.clone();
+        }
+        if self.output.is_empty() {
+            self.output = manifest.output.clone();
+        }
+        if !manifest.variant.is_empty() {
+            self.variant = manifest.variant.parse().map_err(crate::error::Error::Parse)?;
+        }
+        self.audio_lang = manifest.audio_lang.clone();
+        self.subs = manifest.subs.clone();
+        self.skip_discontinuities = manifest.skip_discontinuities;
+        self.inherit_query = manifest.inherit_query;
+        // 分片按截取后的列表编号, 继续下载时需要使用相同的时间范围
+        self.from = parse_time_point(&manifest.from)?;
+        self.to = parse_time_point(&manifest.to)?;
+        Ok(())
     }
 
-    pub fn set_m3u8_name(&mut self, m3u8_name: String) {
-        self.m3u8_name = m3u8_name
+    // 只下载并解析播放列表, 返回摘要, 不下载分片。主播放列表同时返回按 variant 选择的媒体播放列表
+    pub async fn info(&self) -> Result<String, crate::error::Error> {
+        let mut options = self.clone();
+        if options.resume || options.url.is_empty() {
+            let folder = options.get_folder();
+            let saved = Manifest::load(&folder)?
+                .ok_or_else(|| crate::error::Error::Parse(format!("{} 中没有下载记录, 需要指定 url", folder.display())))?;
+            options.restore(&saved)?;
+        }
+        let client = options.http_client()?;
+        download::playlist_info(&options, &client).await
     }
 
-    pub fn generate(self, folder: String) -> Result<(), Error> {
-        let data = serde_json::to_vec(&self)?;
-        Ok(std::fs::write(folder, &data)?)
+    // 下载并合并, 返回输出文件的路径
+    pub async fn run(&self) -> Result<PathBuf, crate::error::Error> {
+        let mut options = self.clone();
+        let folder = options.get_folder();
+        debug!(folder = %folder.display(), "下载目录");
+        // url 为空时从下载目录中的 manifest.json 继续下载
+        let saved = Manifest::load(&folder)?;
+        if options.resume || options.url.is_empty() {
+            let saved = saved
+                .as_ref()
+                .ok_or_else(|| crate::error::Error::Parse(format!("{} 中没有下载记录, 需要指定 url", folder.display())))?;
+            options.restore(saved)?;
+        }
+        if options.output.is_empty() {
+            options.output = format!("{}.mp4", now());
+        }
+        let client = options.http_client()?;
+        download::create_folder(&folder)?;
+        // 统一使用绝对路径, ffmpeg 的文件列表按列表所在目录解析相对路径
+        let folder = fs::canonicalize(&folder)?;
+        let mut manifest = saved.unwrap_or_else(|| Manifest {
+            m3u8_name: format!("{}.m3u8", now()),
+            ..Manifest::default()
+        });
+        manifest.url = options.url.clone();
+        manifest.output = options.output.clone();
+        manifest.variant = options.variant.to_string();
+        manifest.audio_lang = options.audio_lang.clone();
+        manifest.subs = options.subs.clone();
+        manifest.skip_discontinuities = options.skip_discontinuities;
+        manifest.inherit_query = options.inherit_query;
+        manifest.from = options.from.as_ref().map(|t| t.to_string()).unwrap_or_default();
+        manifest.to = options.to.as_ref().map(|t| t.to_string()).unwrap_or_default();
+        let manifest = Arc::new(ManifestStore::new(&folder, manifest));
+        manifest.save()?;
+        let output = folder.join(&options.output);
+        debug!(output = %output.display(), "输出文件");
+        if options.use_ffmpeg {
+            if options.from.is_some() || options.to.is_some() {
+                return Err(crate::error::Error::Parse("使用 ffmpeg 下载时不支持按时间范围下载".to_string()));
+            }
+            let proxy = client.ffmpeg_proxy(&options.url)?;
+            download(options.url.clone(), output.display().to_string(), client.ffmpeg_headers(&options.url), proxy, &options.progress)?;
+        } else {
+            download::fast_download(&options, &client, &folder, &manifest).await?;
+        }
+        info!(output = %output.display(), "生成mp4文件成功");
+        if options.clean {
+            match clear_temp_files(&folder) {
+                Ok(true) => debug!("清理临时文件成功"),
+                Ok(false) => warn!(folder = %folder.display(), "清理临时文件失败"),
+                Err(e) => warn!(folder = %folder.display(), error = %e, "清理临时文件失败"),
+            }
+        }
+        Ok(output)
+    }
+}
+
+// manifest 中保存的时间, 为空时表示没有指定
+fn parse_time_point(str: &str) -> Result<Option<TimePoint>, crate::error::Error> {
+    if str.is_empty() {
+        return Ok(None);
     }
+    str.parse().map(Some).map_err(crate::error::Error::Parse)
 }
 
-fn read_base_info(file_name: &str) -> Result<BaseInfo, std::io::Error> {
-    let path = std::path::Path::new(file_name);
-    let mut file = std::fs::File::open(path)?;
-    let mut contents = String::new();
-    file.read_to_string(&mut contents)?;
+// 重试后仍然下载失败的分片
+#[derive(Debug, Clone)]
+struct FailedSegment {
+    index: i32,
+    url: String,
+    error: String,
+}
+
+// 下载队列, 每个分片一个异步任务, 通过信号量限制同时下载的数量, 可以一边解析一边下载
+struct DownloadPool {
+    client: HttpClient,
+    semaphore: Arc<Semaphore>,
+    tasks: JoinSet<Result<(), FailedSegment>>,
+    count: usize,
+    progress: Arc<Progress>,
+    retry: Retry,
+    manifest: Option<Arc<ManifestStore>>,
+    cancel: CancellationToken,
+}
+
+impl DownloadPool {
+    pub fn new(client: HttpClient, concurrent: i32, progress: Arc<Progress>, retry: Retry) -> DownloadPool {
+        DownloadPool {
+            client,
+            semaphore: Arc::new(Semaphore::new(concurrent.max(1) as usize)),
+            tasks: JoinSet::new(),
+            count: 0,
+            progress,
+            retry,
+            manifest: None,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    // token 取消后等待中的分片不再下载, 正在下载的分片立即停止, 都不算失败
+    pub fn cancel_token(mut self, cancel: CancellationToken) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    // 分片的下载状态记录到 manifest.json 中
+    pub fn manifest(mut self, manifest: Arc<ManifestStore>) -> Self {
+        self.manifest = Some(manifest);
+        self
+    }
+
+    pub fn push(&mut self, video: VideoTs) {
+        let client = self.client.clone();
+        let semaphore = self.semaphore.clone();
+        let progress = self.progress.clone();
+        let retry = self.retry;
+        let manifest = self.manifest.clone();
+        let cancel = self.cancel.clone();
+        if let Some(manifest) = &manifest {
+            manifest.register(&video.file_name(), &video.url, video.byte_range);
+        }
+        // 分片的日志都带上序号和地址, 在服务模式下还会带上任务 id
+        let span = tracing::debug_span!("segment", index = video.index, url = %redact_url(&video.url));
+        self.tasks.spawn(async move {
+            let _permit = tokio::select! {
+                permit = semaphore.acquire_owned() => permit,
+                _ = cancel.cancelled() => return Ok(()),
+            };
+            // 取消后跳过的分片不算失败, 下载结束后返回 Error::Cancelled
+            if progress.is_cancelled() || cancel.is_cancelled() {
+                return Ok(());
+            }
+            let (index, url) = (video.index, video.url.clone());
+            // 中断的分片只留下 .part 文件, 合并时不会用到
+            let result = tokio::select! {
+                res = download_ts_file(&client, video, retry, manifest.as_deref()) => res,
+                _ = cancel.cancelled() => return Ok(()),
+            };
+            let result = result.map(|_| ()).map_err(|e| FailedSegment {
+                index,
+                url,
+                error: e.to_string(),
+            });
+            progress.done.fetch_add(1, Ordering::Relaxed);
+            result
+        }.instrument(span));
+        self.count += 1;
+        self.progress.total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // 等待所有分片下载完成, 返回分片数量和下载失败的分片
+    pub async fn finish(mut self) -> (usize, Vec<FailedSegment>) {
+        let mut failed = vec![];
+        while let Some(res) = self.tasks.join_next().await {
+            match res {
+                Ok(Err(item)) => failed.push(item),
+                Ok(Ok(())) => {}
+                Err(e) => error!(error = %e, "下载任务异常退出"),
+            }
+        }
+        failed.sort_by_key(|item| item.index);
+        if let Some(manifest) = &self.manifest {
+            if let Err(e) = manifest.save() {
+                warn!(error = %e, "保存 manifest.json 失败");
+            }
+        }
+        (self.count, failed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TrackKind {
+    Video,
+    Audio,
+    Subtitle,
+}
+
+// 需要下载的一路媒体流: 视频, 或者独立的音频、字幕
+struct MediaTrack {
+    kind: TrackKind,
+    url: String,
+    prefix: String, // 分片文件名前缀
+    m3u8_file_name: String,
+    language: String,
+    playlist: HlsM3u8,
+    keys: HashMap<String, Vec<u8>>,
+    total: usize, // 已下载的分片数量
+    failed: Vec<FailedSegment>,
+    offset: f64, // 按时间范围下载时, 第一个分片在原播放列表中的位置, 单位：秒
+}
+
+impl MediaTrack {
+    pub fn new(kind: TrackKind, url: String, prefix: String, m3u8_file_name: String, language: String, playlist: HlsM3u8) -> MediaTrack {
+        let mut track = MediaTrack {
+            kind,
+            url,
+            prefix,
+            m3u8_file_name,
+            language,
+            playlist,
+            keys: HashMap::new(),
+            total: 0,
+            failed: vec![],
+            offset: 0.0,
+        };
+        track.prepare();
+        track
+    }
+
+    // 根据播放列表确定分片的扩展名
+    pub fn prepare(&mut self) {
+        if self.kind == TrackKind::Subtitle {
+            self.playlist.set_extension("vtt".to_string());
+        } else if !self.playlist.x_map_uri.is_empty() {
+            self.playlist.set_extension("m4s".to_string());
+        }
+    }
+
+    // 合并后的文件路径, 只有一路视频时直接输出到目标文件
+    pub fn target(&self, file_name: &str, output: &str, single: bool) -> String {
+        match self.kind {
+            TrackKind::Video if single => output.to_string(),
+            TrackKind::Video => format!("{}video_{}", self.prefix, file_name),
+            TrackKind::Audio if self.playlist.extension == "ts" => format!("{}audio.ts", self.prefix),
+            TrackKind::Audio => format!("{}audio.mp4", self.prefix),
+            TrackKind::Subtitle => format!("{}subtitle.vtt", self.prefix),
+        }
+    }
+
+    // --skip-discontinuities 时点播在下载前去掉广告等片段, 被跳过的分片不会下载
+    pub fn skip_discontinuities(&mut self) {
+        let list = &self.playlist.list;
+        let runs = segment_runs(list);
+        let kept = main_runs(list, &runs);
+        if kept.len() == runs.len() {
+            return;
+        }
+        let skipped: Vec<HlsSegment> = runs.iter().filter(|run| !kept.contains(run)).flat_map(|run| list[run.clone()].to_vec()).collect();
+        let seconds: f64 = skipped.iter().map(|s| s.duration).sum();
+        info!(kind = ?self.kind, segments = skipped.len(), "跳过{}段不连续的片段, 共{:.1}秒", runs.len() - kept.len(), seconds);
+        let list = kept.into_iter().flat_map(|run| list[run].to_vec()).collect();
+        self.playlist.set_list(list);
+    }
+
+    // 按 #EXT-X-DISCONTINUITY 分段合并。点播已经在下载前跳过了不连续的片段, 这里只筛选直播录制的分片,
+    // 对筛选后的列表再筛选一次会把正片中间广告之后的部分也去掉
+    pub fn combine_runs(&self, options: &Downloader) -> Vec<Range<usize>> {
+        let list = &self.playlist.list;
+        let runs = segment_runs(list);
+        if options.skip_discontinuities && options.live {
+            return main_runs(list, &runs);
+        }
+        runs
+    }
+
+    pub fn video_ts(&self, index: i32, segment: &HlsSegment) -> VideoTs {
+        let mut ts = VideoTs::new();
+        ts.set(index, segment.url.clone(), self.playlist.extension.clone(), self.prefix.clone());
+        ts.byte_range = segment.byte_range.map(|r| (r.offset, r.length));
+        ts
+    }
+
+    // list 中用到的初始化分片, 序号依次为 -1、-2..., 分片没有记录 #EXT-X-MAP 时使用播放列表的 x_map_uri
+    pub fn map_videos(&self, list: &[HlsSegment]) -> Vec<VideoTs> {
+        let mut maps: Vec<(String, Option<HlsByteRange>)> = distinct_maps(list).into_iter().map(|m| (m.uri.clone(), m.byte_range)).collect();
+        if maps.is_empty() && !self.playlist.x_map_uri.is_empty() {
+            maps.push((self.playlist.x_map_uri.clone(), self.playlist.x_map_byte_range));
+        }
+        let mut list = vec![];
+        for (index, (uri, byte_range)) in maps.into_iter().enumerate() {
+            // 播放列表中已经解析成绝对地址, 本地播放列表时按播放列表的路径解析
+            let x_url = resolve_url(&self.url, &uri);
+            let mut video = VideoTs::new();
+            video.set(-(index as i32) - 1, x_url, self.playlist.extension.clone(), self.prefix.clone());
+            video.byte_range = byte_range.map(|r| (r.offset, r.length));
+            list.push(video);
+        }
+        list
+    }
+}
 
-    let base_info: BaseInfo = serde_json::from_str(&contents)?;
-    Ok(base_info)
+// 直播重新加载后新增的分片, last_base 为上一次播放列表的媒体序号, last 为已经录制的最后一个分片的序号。
+// 媒体序号变小并且有 #EXT-X-DISCONTINUITY 时是编码器重启后重新编号, 从不连续的分片开始都是新的分片
+fn live_new_segments(playlist: &HlsM3u8, last_base: Option<u64>, last: Option<u64>) -> &[HlsSegment] {
+    let list = &playlist.list;
+    if let Some(base) = last_base.filter(|base| playlist.sequence < *base) {
+        if let Some(start) = list.iter().position(|s| s.discontinuity) {
+            warn!("媒体序号从{}变为{}, 按重新编号处理", base, playlist.sequence);
+            return &list[start..];
+        }
+    }
+    let start = list.iter().position(|s| last.is_none_or(|last| s.sequence > last)).unwrap_or(list.len());
+    &list[start..]
 }
 
 pub mod download {
-    use crate::combine::parse::handle_combine_ts;
-    use crate::common::{is_url, now, replace_last_segment};
-    use crate::download::{download_ts_file, download_ts_file_async, read_base_info, BaseInfo, VideoTs};
+    use crate::combine::parse::{combine_webvtt, handle_combine_ts};
+    use crate::common::{format_time, get_content_type, is_url, now};
+    use std::sync::Arc;
+    use crate::dash::dash;
+    use crate::download::{download_ts_file, live_new_segments, DownloadPool, Downloader, FailedSegment, MediaTrack, TrackKind, VideoTs};
+    use crate::verify::{check_segment, VerifyMode};
+    use crate::manifest::ManifestStore;
+    use crate::common::{download_file_retry, Retry};
+    use crate::http::{format_bytes, HttpClient};
+    use crate::logging::{redact_key_uri, redact_url};
+    use crate::progress::Progress;
     use crate::m3u8::m3u8::{parse_local, parse_url};
-    use std::fmt::{format, Error};
+    use crate::m3u8::{distinct_maps, time_offset, time_range_segments, HlsM3u8, HlsSegment, TimePoint, VariantSelect};
+    use crate::error::Error;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+    use url::Url;
     use std::{fs, io};
-    use std::sync::{mpsc, Arc, Mutex};
-    use std::thread;
-    use crate::cmd::cmd::check_video_validity;
-
-    pub async fn fast_download(
-        pass_url: String,
-        _file_name: String,
-        folder: String,
-        concurrent: i32,
-    ) -> Result<bool, Error> {
-        let mut hls_m3u;
-        let mut url = pass_url;
-        let base_info = "base_info.json";
-        let mut m3u8_file_name = format!("{}.m3u8", now());
-        let mut base_info_obj = BaseInfo::new();
-        let read_base_info = read_base_info(&base_info.to_string());
-        match read_base_info {
-            Ok(base_info_data) => {
-                m3u8_file_name = base_info_data.m3u8_name;
-                url = base_info_data.url;
+    use tracing::{debug, error, info, warn};
+    use tokio_util::sync::CancellationToken;
+    use crate::cmd::cmd::{check_video_validity, cut, mux};
+
+    // 下载到 dir 目录, 所有中间文件都在这个目录中, 不会改变当前工作目录
+    // 分片的下载状态和 key 记录在 manifest 中, 已经下载并且校验通过的分片不再下载
+    pub async fn fast_download(options: &Downloader, client: &HttpClient, dir: &Path, manifest: &Arc<ManifestStore>) -> Result<bool, Error> {
+        let url = options.url.clone();
+        let dir = format!("{}/", dir.display());
+        let m3u8_file_name = manifest.manifest().m3u8_name;
+        // 直播每次录制的分片都是新的, 加上前缀避免和之前录制的分片混在一起
+        let mut prefix = dir.clone();
+        if options.live {
+            prefix = format!("{}live{}_", dir, now());
+        }
+        let mut tracks = if is_dash(client, &url).await {
+            if options.live {
+                return Err(Error::Parse("DASH 暂不支持直播录制".to_string()));
             }
-            Err(_) => {
-                base_info_obj.set_host(url.clone());
-                base_info_obj.set_m3u8_name(m3u8_file_name.clone());
-                let _ = base_info_obj.generate(base_info.to_string());
+            let mpd_file_name = format!("{}{}", dir, m3u8_file_name.replace(".m3u8", ".mpd"));
+            dash_tracks(client, url, mpd_file_name, prefix, &options.variant, &options.audio_lang).await?
+        } else {
+            hls_tracks(client, url, m3u8_file_name, prefix, options).await?
+        };
+        if tracks.iter().any(|track| track.playlist.is_protected()) {
+            return Err(Error::Decrypt("暂不支持 DRM 加密(KEYFORMAT 不是 identity)的 HLS".to_string()));
+        }
+        if options.skip_discontinuities && !options.live {
+            for track in tracks.iter_mut() {
+                track.skip_discontinuities();
             }
         }
-        if is_url(url.clone()) {
-            hls_m3u = parse_url(url.clone(), folder.clone(), m3u8_file_name.clone()).await;
+        // 按时间范围下载时只下载覆盖这个范围的分片, 合并后再精确截取
+        let range = match (&options.from, &options.to) {
+            (None, None) => None,
+            _ if options.live => return Err(Error::Parse("直播录制不支持按时间范围下载".to_string())),
+            (from, to) => Some(select_time_range(&mut tracks, from.as_ref(), to.as_ref())?),
+        };
+        let start = Instant::now();
+        options.progress.start_stage("download", Duration::ZERO);
+        if options.live {
+            record_live(client, &mut tracks, options, &dir, manifest).await?;
         } else {
-            hls_m3u = parse_local(url.clone(), String::default(), folder.clone()).await;
+            for track in tracks.iter_mut() {
+                download_track(client, track, options, &dir, manifest).await?;
+            }
         }
-        let mut extension= "ts".to_string();
-        if !hls_m3u.x_map_uri.is_empty() {
-            extension = "m4s".to_string();
-            println!("-------x-map-uri----{}", hls_m3u.x_map_uri.clone());
-            let mut video = VideoTs::new();
-            let mut x_url = hls_m3u.x_map_uri.clone();
-            if !is_url(x_url.clone()) {
-                x_url = replace_last_segment(url.clone().as_str(), x_url.clone().as_str())
+        let seconds = start.elapsed().as_secs_f64().max(0.001);
+        info!(
+            "下载了{}, 用时{:.1}秒, 平均速度{}/s",
+            format_bytes(client.received()),
+            seconds,
+            format_bytes((client.received() as f64 / seconds) as u64)
+        );
+        if options.progress.is_cancelled() {
+            return Err(Error::Cancelled);
+        }
+        for track in tracks.iter_mut() {
+            verify_track(client, track, options, manifest).await?;
+        }
+        report_failed(&tracks, options)?;
+
+        // 输出文件为相对路径时放在下载目录中
+        let output = Path::new(&dir).join(&options.output);
+        let file_name = output.display().to_string();
+        let name = output.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let single = tracks.len() == 1;
+        let mut video_file = String::default();
+        let mut audio_files = vec![];
+        let mut subtitle_files = vec![];
+        for track in &tracks {
+            let target = track.target(&name, &file_name, single);
+            // 视频失败时整个下载失败, 音频和字幕失败时跳过
+            let mut res = combine_track(track, target.clone(), options).await;
+            if let (Ok(_), Some((from, to))) = (&res, range) {
+                res = trim_track(track, &target, from, to, &options.progress);
             }
-            println!("-----x-url---{}", x_url.clone());
-            video.url = x_url;
-            video.index = -1;
-            video.extension = extension.clone();
-            download_ts_file_async(video).await;
-        }
-        hls_m3u.set_extension(extension.clone());
-        let mut ts_list = vec![];
-        let mut ts_index = 0;
-        for x in &hls_m3u.list {
-            let mut ts = VideoTs::new();
-            ts.set(ts_index, x.clone(), extension.clone());
-            ts_list.push(ts);
-            ts_index += 1;
-        }
-        let total = ts_list.len();
-        // 分批下载文件
-        let (tx, rx) = mpsc::channel();
-        let (data_tx, data_rx) = mpsc::channel();
-        let new_data_rx = Arc::new(Mutex::new(data_rx));
-
-        for _i in 0..concurrent {
-            let tx_clone = tx.clone();
-            let data_rx_clone = Arc::clone(&new_data_rx);
-
-            thread::spawn(move || loop {
-                let item = {
-                    let rx_lock = data_rx_clone.lock().unwrap();
-                    match rx_lock.recv() {
-                        Ok(item) => item,
-                        Err(_) => break,
-                    }
-                };
-                let result = download_ts_file(item);
-                tx_clone.send(result).unwrap();
+            match (&track.kind, res) {
+                (TrackKind::Video, res) => {
+                    res?;
+                    video_file = target;
+                }
+                (_, Err(e)) => warn!(url = %redact_url(&track.url), error = %e, "合并失败, 跳过"),
+                (TrackKind::Audio, Ok(_)) => audio_files.push((target, track.language.clone())),
+                (TrackKind::Subtitle, Ok(_)) => subtitle_files.push((target, track.language.clone())),
+            }
+        }
+        if !single {
+            mux(video_file.clone(), audio_files, subtitle_files, file_name.clone(), &options.progress)?;
+            let _ = fs::remove_file(video_file);
+        }
+        debug!(file = %file_name, "检查输出文件");
+        check_video_validity(file_name.as_str())
+    }
+
+    // 播放列表的摘要, 播放列表下载到临时目录中
+    pub async fn playlist_info(options: &Downloader, client: &HttpClient) -> Result<String, Error> {
+        if is_dash(client, &options.url).await {
+            return Err(Error::Parse("DASH 清单暂不支持 --info".to_string()));
+        }
+        let dir = tempfile::tempdir()?;
+        let file_name = dir.path().join("info.m3u8").display().to_string();
+        let hls_m3u = parse_playlist(client, options.url.clone(), file_name.clone(), options.inherit_query).await?;
+        let mut info = hls_m3u.to_string().trim_end().to_string();
+        if hls_m3u.is_master() {
+            let variant = hls_m3u
+                .select_variant(&options.variant)
+                .ok_or_else(|| Error::Parse(format!("没有找到符合条件的variant: {:?}", options.variant)))?;
+            let media = parse_playlist(client, variant.uri.clone(), file_name, options.inherit_query).await?;
+            info = format!("{}\n\n选择的变体 {}:\n{}", info, variant.uri, media);
+        }
+        Ok(info)
+    }
+
+    // 去掉正片之外由 #EXT-X-DISCONTINUITY 分隔的片段, 这些分片不会下载
+    // 把 from、to 换算成在播放列表中的位置, 每一路只保留覆盖这个范围的分片, 返回 (from, to)。
+    // 音频、字幕的分片边界和视频不一定相同, 各自按位置选择
+    fn select_time_range(tracks: &mut [MediaTrack], from: Option<&TimePoint>, to: Option<&TimePoint>) -> Result<(f64, f64), Error> {
+        let list = &tracks[0].playlist.list;
+        if list.iter().any(|s| s.duration <= 0.0) {
+            return Err(Error::Parse("播放列表缺少 #EXTINF 时长, 无法按时间范围下载".to_string()));
+        }
+        let total = tracks[0].playlist.total_duration();
+        let from = match from {
+            Some(point) => time_offset(list, point).map_err(Error::Parse)?,
+            None => 0.0,
+        };
+        let to = match to {
+            Some(point) => time_offset(list, point).map_err(Error::Parse)?.min(total),
+            None => total,
+        };
+        if from >= to {
+            return Err(Error::Parse(format!(
+                "开始时间 {} 需要早于结束时间 {}, 播放列表总时长 {}",
+                format_time(from),
+                format_time(to),
+                format_time(total)
+            )));
+        }
+        info!("下载 {} 到 {} 的内容", format_time(from), format_time(to));
+        for track in tracks.iter_mut() {
+            // 没有时长的音频、字幕全部下载, 截取时从头计算位置
+            if track.playlist.list.iter().any(|s| s.duration <= 0.0) {
+                warn!(kind = ?track.kind, url = %redact_url(&track.url), "缺少 #EXTINF 时长, 下载全部分片");
+                continue;
+            }
+            let (segments, offset) = time_range_segments(&track.playlist.list, from, to);
+            debug!(kind = ?track.kind, start = segments.start, end = segments.end, offset, "按时间范围选择分片");
+            let list = track.playlist.list[segments].to_vec();
+            track.playlist.set_list(list);
+            track.offset = offset;
+        }
+        Ok((from, to))
+    }
+
+    // 合并后的文件从选择的第一个分片开始, 截取到精确的时间范围。
+    // WebVTT 字幕的时间一般从节目开始计算, 按原播放列表中的位置截取
+    fn trim_track(track: &MediaTrack, target: &str, from: f64, to: f64, progress: &Progress) -> Result<bool, Error> {
+        let start = match track.kind {
+            TrackKind::Subtitle => from,
+            _ => from - track.offset,
+        };
+        let path = Path::new(target);
+        let name = path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default();
+        let untrimmed = path.with_file_name(format!("untrimmed_{}", name)).display().to_string();
+        fs::rename(target, &untrimmed)?;
+        debug!(file = %target, start, duration = to - from, "截取");
+        let res = cut(untrimmed.clone(), start.max(0.0), to - from, target.to_string(), progress);
+        let _ = fs::remove_file(&untrimmed);
+        res
+    }
+
+    // 合并前检查已下载的分片, 截断或者损坏的分片删除后重新下载一次, 仍然有问题时记为下载失败
+    async fn verify_track(client: &HttpClient, track: &mut MediaTrack, options: &Downloader, manifest: &Arc<ManifestStore>) -> Result<(), Error> {
+        if options.verify == VerifyMode::Off || track.kind == TrackKind::Subtitle {
+            return Ok(());
+        }
+        options.progress.start_stage("verify", Duration::ZERO);
+        let bad = check_track(track, options.verify, None).await?;
+        if bad.is_empty() {
+            return Ok(());
+        }
+        let mut pool = DownloadPool::new(client.clone(), options.concurrent, options.progress.clone(), options.retry).manifest(manifest.clone());
+        let mut refetched = vec![];
+        for (video, reason) in bad {
+            warn!(index = video.index, url = %redact_url(&video.url), reason = %reason, "分片不完整, 重新下载");
+            let file = video.file_name();
+            let _ = fs::remove_file(&file);
+            manifest.finish(&file, false);
+            refetched.push(video.index);
+            pool.push(video);
+        }
+        let (_, failed) = pool.finish().await;
+        track.failed.extend(failed);
+        // 重新下载后还有问题的分片删除, 允许缺失分片时合并会跳过它们
+        for (video, reason) in check_track(track, options.verify, Some(&refetched)).await? {
+            let file = video.file_name();
+            let _ = fs::remove_file(&file);
+            manifest.finish(&file, false);
+            track.failed.push(FailedSegment {
+                index: video.index,
+                url: video.url,
+                error: format!("分片不完整, {}", reason),
             });
         }
-        for value in ts_list {
-            data_tx.send(value).unwrap();
+        track.failed.sort_by_key(|item| item.index);
+        manifest.save()
+    }
+
+    // 检查一路媒体流的初始化分片和所有分片, 返回有问题的分片和原因。
+    // 跳过已经记为下载失败的分片, only 不为空时只检查其中的分片
+    async fn check_track(track: &MediaTrack, mode: VerifyMode, only: Option<&[i32]>) -> Result<Vec<(VideoTs, String)>, Error> {
+        let failed: Vec<i32> = track.failed.iter().map(|item| item.index).collect();
+        // 加密的初始化分片和加密的分片一样只检查长度
+        let maps = distinct_maps(&track.playlist.list);
+        let mut items: Vec<(VideoTs, Option<HlsSegment>)> = vec![];
+        for (index, video) in track.map_videos(&track.playlist.list).into_iter().enumerate() {
+            let key = maps.get(index).and_then(|map| map.key.clone());
+            items.push((video, key.map(|key| HlsSegment { key: Some(key), ..HlsSegment::default() })));
+        }
+        for (index, segment) in track.playlist.list.iter().enumerate() {
+            items.push((track.video_ts(index as i32, segment), Some(segment.clone())));
         }
-        drop(tx); // 发送完成后关闭队列
-        let mut i = 0;
-        loop {
-            if i == total {
-                break;
+        items.retain(|(video, _)| !failed.contains(&video.index) && only.is_none_or(|only| only.contains(&video.index)));
+        let extension = track.playlist.extension.clone();
+        // full 模式下每个分片执行一次 ffprobe, 放到阻塞线程中执行
+        let bad = tokio::task::spawn_blocking(move || {
+            items
+                .into_iter()
+                .filter_map(|(video, segment)| {
+                    check_segment(&video.file_name(), &extension, segment.as_ref(), mode).err().map(|reason| (video, reason))
+                })
+                .collect()
+        })
+        .await
+        .map_err(|e| Error::Parse(format!("检查分片失败, {}", e)))?;
+        Ok(bad)
+    }
+
+    // 输出重试后仍然失败的分片, 不允许缺失分片时返回 Error::Incomplete
+    fn report_failed(tracks: &[MediaTrack], options: &Downloader) -> Result<(), Error> {
+        let failed: usize = tracks.iter().map(|t| t.failed.len()).sum();
+        if failed == 0 {
+            return Ok(());
+        }
+        error!("以下{}个分片重试{}次后仍然下载失败:", failed, options.retry.retries);
+        for track in tracks {
+            for item in &track.failed {
+                error!(kind = ?track.kind, index = item.index, url = %redact_url(&item.url), error = %item.error, "分片下载失败");
+            }
+        }
+        if !options.allow_gaps {
+            let total = tracks.iter().map(|t| t.total).sum();
+            return Err(Error::Incomplete { failed, total });
+        }
+        warn!("跳过缺失的分片继续合并");
+        Ok(())
+    }
+
+    // 解析 HLS 播放列表, 主播放列表按条件选择变体以及独立的音频和字幕
+    async fn hls_tracks(client: &HttpClient, mut url: String, mut m3u8_file_name: String, prefix: String, options: &Downloader) -> Result<Vec<MediaTrack>, Error> {
+        let (variant, audio_lang, subs, inherit) = (&options.variant, options.audio_lang.as_str(), options.subs.as_slice(), options.inherit_query);
+        let mut hls_m3u = parse_playlist(client, url.clone(), format!("{}{}", prefix, m3u8_file_name), inherit).await?;
+        let mut renditions = vec![];
+        if hls_m3u.is_master() {
+            for (index, item) in hls_m3u.variants.iter().enumerate() {
+                debug!(
+                    index,
+                    bandwidth = item.bandwidth,
+                    resolution = ?item.resolution,
+                    codecs = %item.codecs,
+                    fps = item.frame_rate,
+                    "variant"
+                );
+            }
+            match hls_m3u.select_variant(variant) {
+                Some(data) => {
+                    info!(url = %redact_url(&data.uri), bandwidth = data.bandwidth, "选择的variant");
+                    url = data.uri.clone();
+                    if let Some(audio) = hls_m3u.select_audio(data, audio_lang) {
+                        info!(url = %redact_url(&audio.uri), "选择的音频: {}({})", audio.name, audio.language);
+                        renditions.push((TrackKind::Audio, audio.clone()));
+                    }
+                    for item in hls_m3u.select_subtitles(data, subs) {
+                        info!(url = %redact_url(&item.uri), "选择的字幕: {}({})", item.name, item.language);
+                        renditions.push((TrackKind::Subtitle, item.clone()));
+                    }
+                }
+                None => return Err(Error::Parse(format!("没有找到符合条件的variant: {:?}", variant))),
+            }
+            m3u8_file_name = format!("media_{}", m3u8_file_name);
+            hls_m3u = parse_playlist(client, url.clone(), format!("{}{}", prefix, m3u8_file_name), inherit).await?;
+        }
+        let video_m3u8 = format!("{}{}", prefix, m3u8_file_name);
+        let mut tracks = vec![MediaTrack::new(TrackKind::Video, url.clone(), prefix.clone(), video_m3u8, String::default(), hls_m3u)];
+        // 独立的音频和字幕下载后再合成到一个文件里
+        for (index, (kind, item)) in renditions.into_iter().enumerate() {
+            let track_prefix = match kind {
+                TrackKind::Subtitle => format!("{}sub{}_", prefix, index),
+                _ => format!("{}audio{}_", prefix, index),
+            };
+            let track_m3u8 = format!("{}{}", track_prefix, m3u8_file_name);
+            let playlist = parse_playlist(client, item.uri.clone(), track_m3u8.clone(), inherit).await?;
+            tracks.push(MediaTrack::new(kind, item.uri.clone(), track_prefix, track_m3u8, item.language.clone(), playlist));
+        }
+        Ok(tracks)
+    }
+
+    // 解析 DASH 清单, 选择视频和音频 Representation, 转换成播放列表后按 fMP4 分片下载
+    async fn dash_tracks(client: &HttpClient, url: String, mpd_file_name: String, prefix: String, variant: &VariantSelect, audio_lang: &str) -> Result<Vec<MediaTrack>, Error> {
+        let mpd = if is_url(url.clone()) {
+            dash::parse_url(client, url.clone(), mpd_file_name.clone()).await?
+        } else {
+            dash::parse_local(url.clone(), String::default()).await?
+        };
+        if mpd.is_protected() {
+            return Err(Error::Decrypt("暂不支持 DRM 加密(ContentProtection)的 DASH".to_string()));
+        }
+        for period in &mpd.periods {
+            for set in &period.adaptation_sets {
+                for item in &set.representations {
+                    debug!(
+                        id = %item.id,
+                        content_type = %set.content_type,
+                        bandwidth = item.bandwidth,
+                        resolution = %format!("{}x{}", item.width, item.height),
+                        codecs = %item.codecs,
+                        lang = %set.language,
+                        "representation"
+                    );
+                }
+            }
+        }
+        let video = mpd.select_video(variant);
+        if video.is_empty() {
+            return Err(Error::Parse(format!("没有找到符合条件的representation: {:?}", variant)));
+        }
+        info!(id = %video[0].id, "选择的representation");
+        let mut tracks = vec![MediaTrack::new(TrackKind::Video, url.clone(), prefix.clone(), mpd_file_name.clone(), String::default(), mpd.to_hls(&video))];
+        let audio = mpd.select_audio(audio_lang);
+        if let Some((rep, language)) = audio.first() {
+            info!(id = %rep.id, "选择的音频: {}", language);
+            let reps: Vec<_> = audio.iter().map(|(r, _)| *r).collect();
+            tracks.push(MediaTrack::new(TrackKind::Audio, url.clone(), format!("{}audio0_", prefix), mpd_file_name, language.clone(), mpd.to_hls(&reps)));
+        }
+        Ok(tracks)
+    }
+
+    // 根据扩展名或者 Content-Type 判断是否为 DASH 清单
+    async fn is_dash(client: &HttpClient, url: &str) -> bool {
+        let path = match Url::parse(url) {
+            Ok(data) => data.path().to_lowercase(),
+            Err(_) => url.to_lowercase(),
+        };
+        if path.ends_with(".mpd") {
+            return true;
+        }
+        if path.ends_with(".m3u8") || !is_url(url.to_string()) {
+            return false;
+        }
+        match get_content_type(client, url.to_string()).await {
+            Some(data) => data.contains("application/dash+xml"),
+            None => false,
+        }
+    }
+
+    // 下载一路媒体流的 key 和所有分片
+    async fn download_track(client: &HttpClient, track: &mut MediaTrack, options: &Downloader, dir: &str, manifest: &Arc<ManifestStore>) -> Result<(), Error> {
+        if track.playlist.is_encrypted() {
+            track.keys = download_keys(client, &track.playlist, dir, options.retry, manifest).await?;
+        }
+        for video in track.map_videos(&track.playlist.list) {
+            debug!(url = %redact_url(&video.url), "初始化分片");
+            manifest.register(&video.file_name(), &video.url, video.byte_range);
+            download_ts_file(client, video, options.retry, Some(manifest)).await?;
+        }
+        let mut pool = DownloadPool::new(client.clone(), options.concurrent, options.progress.clone(), options.retry).manifest(manifest.clone());
+        for (index, segment) in track.playlist.list.iter().enumerate() {
+            pool.push(track.video_ts(index as i32, segment));
+        }
+        (track.total, track.failed) = pool.finish().await;
+        debug!(url = %redact_url(&track.url), total = track.total, failed = track.failed.len(), "分片下载结束");
+        Ok(())
+    }
+
+    // 直播录制: 按 target duration 重新加载播放列表, 根据媒体序号去重, 新的分片加入下载队列,
+    // 遇到 #EXT-X-ENDLIST、超过 max_duration 秒或者 Ctrl-C 时停止
+    async fn record_live(client: &HttpClient, tracks: &mut [MediaTrack], options: &Downloader, dir: &str, manifest: &Arc<ManifestStore>) -> Result<(), Error> {
+        let start = Instant::now();
+        let max_duration = options.max_duration;
+        // 按下 Ctrl-C 后取消 token, 正在进行的分片下载、播放列表和 key 的请求都会立即停止
+        let cancel = CancellationToken::new();
+        // 监听后 Ctrl-C 不再直接结束进程, 停止录制以后(包括合并时)再按 Ctrl-C 直接退出
+        tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                while tokio::signal::ctrl_c().await.is_ok() {
+                    if cancel.is_cancelled() {
+                        warn!("再次收到Ctrl-C, 退出");
+                        std::process::exit(130);
+                    }
+                    info!("收到Ctrl-C, 停止录制, 再次按下Ctrl-C直接退出");
+                    cancel.cancel();
+                }
             }
-            let result = rx.recv();
-            match result {
-                Ok(_) => {
-                    i += 1;
+        });
+        let mut pools: Vec<DownloadPool> = tracks
+            .iter()
+            .map(|_| {
+                DownloadPool::new(client.clone(), options.concurrent, options.progress.clone(), options.retry)
+                    .manifest(manifest.clone())
+                    .cancel_token(cancel.clone())
+            })
+            .collect();
+        let mut last_sequences: Vec<Option<u64>> = vec![None; tracks.len()];
+        let mut last_bases: Vec<Option<u64>> = vec![None; tracks.len()];
+        let mut recorded: Vec<Vec<HlsSegment>> = vec![vec![]; tracks.len()];
+        let mut first = true;
+        'record: loop {
+            let mut ended = true;
+            let mut has_new = false;
+            let mut target_duration = 0;
+            for (index, track) in tracks.iter_mut().enumerate() {
+                if !first {
+                    // 重新加载失败时继续使用上一次的播放列表, 下次再试
+                    let reload = tokio::select! {
+                        res = parse_playlist(client, track.url.clone(), track.m3u8_file_name.clone(), options.inherit_query) => res,
+                        _ = cancel.cancelled() => break 'record,
+                    };
+                    match reload {
+                        Ok(playlist) if playlist.is_protected() => {
+                            error!(url = %redact_url(&track.url), "播放列表改为 DRM 加密, 停止录制");
+                            break 'record;
+                        }
+                        Ok(playlist) => {
+                            track.playlist = playlist;
+                            track.prepare();
+                        }
+                        Err(e) => warn!(url = %redact_url(&track.url), error = %e, "重新加载播放列表失败"),
+                    }
+                }
+                if track.playlist.is_encrypted() {
+                    let keys = tokio::select! {
+                        res = download_keys(client, &track.playlist, dir, options.retry, manifest) => res,
+                        _ = cancel.cancelled() => break 'record,
+                    };
+                    match keys {
+                        Ok(keys) => track.keys.extend(keys),
+                        Err(e) => warn!(error = %e, "key 下载出错"),
+                    }
                 }
-                Err(_e) => {}
+                for segment in live_new_segments(&track.playlist, last_bases[index], last_sequences[index]) {
+                    pools[index].push(track.video_ts(recorded[index].len() as i32, segment));
+                    last_sequences[index] = Some(segment.sequence);
+                    recorded[index].push(segment.clone());
+                    has_new = true;
+                }
+                last_bases[index] = Some(track.playlist.sequence);
+                // 插入广告等不连续的片段可能带来新的初始化分片, 已经下载过的会跳过
+                for video in track.map_videos(&recorded[index]) {
+                    manifest.register(&video.file_name(), &video.url, video.byte_range);
+                    tokio::select! {
+                        res = download_ts_file(client, video, options.retry, Some(manifest)) => res?,
+                        _ = cancel.cancelled() => break 'record,
+                    };
+                }
+                ended = ended && track.playlist.endlist;
+                target_duration = target_duration.max(track.playlist.target_duration);
+            }
+            first = false;
+            info!("直播录制中, 已录制{}个分片, 用时{}秒", recorded[0].len(), start.elapsed().as_secs());
+            if ended {
+                info!("播放列表已结束(EXT-X-ENDLIST), 停止录制");
+                break;
+            }
+            if max_duration > 0 && start.elapsed().as_secs() >= max_duration {
+                info!("已达到最大录制时长{}秒, 停止录制", max_duration);
+                break;
+            }
+            if options.progress.is_cancelled() || cancel.is_cancelled() {
+                break;
+            }
+            // 播放列表没有变化时, 按 target duration 的一半重新加载
+            let mut wait = Duration::from_secs(target_duration.max(1));
+            if !has_new {
+                wait /= 2;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(wait) => {}
+                _ = cancel.cancelled() => break,
+            }
+        }
+        info!("等待已录制的分片下载完成");
+        for (index, pool) in pools.into_iter().enumerate() {
+            let track = &mut tracks[index];
+            let (mut total, mut failed) = pool.finish().await;
+            let mut list = recorded[index].clone();
+            if cancel.is_cancelled() {
+                // Ctrl-C 中断的分片不再补下, 只保留它之前的分片, 文件名按序号对应所以不能跳过中间的分片
+                let done = list
+                    .iter()
+                    .enumerate()
+                    .position(|(i, segment)| !failed.iter().any(|f| f.index == i as i32) && !Path::new(&track.video_ts(i as i32, segment).file_name()).exists())
+                    .unwrap_or(list.len());
+                list.truncate(done);
+                failed.retain(|f| (f.index as usize) < done);
+                total = done;
             }
+            (track.total, track.failed) = (total, failed);
+            track.playlist.set_list(list);
         }
-        println!("----download files finished");
-        let mut start = 0;
-        if !hls_m3u.x_map_uri.is_empty() {
-            start = -1;
-        }
-        let res = handle_combine_ts(
-            String::from(format!("(.*).{}", hls_m3u.extension)),
-            start,
-            (total - 1) as i32,
-            _file_name.clone(),
-            hls_m3u.method,
-            folder.clone(),
-            hls_m3u.iv,
-            hls_m3u.sequence,
-            hls_m3u.x_map_uri.clone(),
-            hls_m3u.extension.clone(),
-        )
-        .await?;
-        return if res {
-            let f_name = format!("{}", _file_name.clone());
-            println!("---f_name {}", f_name.clone());
-            check_video_validity(f_name.as_str())
+        cancel.cancel();
+        Ok(())
+    }
+
+    // 把下载好的分片合并成 target 文件
+    async fn combine_track(track: &MediaTrack, target: String, options: &Downloader) -> Result<bool, Error> {
+        if track.total == 0 {
+            return Err(Error::Parse(format!("{} 中没有分片", track.url)));
+        }
+        let reg_name = format!("{}(.*).{}", track.prefix, track.playlist.extension);
+        if track.kind == TrackKind::Subtitle {
+            return combine_webvtt(reg_name, 0, (track.total - 1) as i32, target);
+        }
+        let runs = track.combine_runs(options);
+        handle_combine_ts(reg_name, target, track.playlist.list.clone(), track.keys.clone(), track.playlist.x_map_uri.clone(), runs, &options.progress).await
+    }
+
+    // 下载播放列表中用到的所有 key, 相同地址只下载一次, 以地址的 md5 命名保存在 dir 目录,
+    // manifest 中已经有的 key 不再下载
+    async fn download_keys(client: &HttpClient, hls_m3u: &HlsM3u8, dir: &str, retry: Retry, manifest: &ManifestStore) -> Result<HashMap<String, Vec<u8>>, Error> {
+        let mut keys = HashMap::new();
+        for uri in hls_m3u.key_uris() {
+            let key_file = format!("{}key_{:x}.bin", dir, md5::compute(uri.as_bytes()));
+            if let Some(data) = manifest.key(&uri) {
+                keys.insert(uri, data);
+                continue;
+            }
+            if fs::metadata(key_file.clone()).is_err() {
+                download_file_retry(client, uri.clone(), key_file.clone(), None, retry).await?;
+                debug!(key = %redact_key_uri(&uri), "key 下载成功");
+            }
+            let data = fs::read(key_file.clone())?;
+            if data.len() != 16 {
+                warn!(key = %redact_key_uri(&uri), "key 长度为{}, 不是16字节", data.len());
+            }
+            manifest.set_key(&uri, &data);
+            keys.insert(uri, data);
+        }
+        Ok(keys)
+    }
+
+    // inherit_query 时相对地址带上播放列表地址的查询参数, 见 Downloader::inherit_query
+    async fn parse_playlist(client: &HttpClient, url: String, m3u8_file_name: String, inherit_query: bool) -> Result<HlsM3u8, Error> {
+        let mut hls_m3u = if is_url(url.clone()) {
+            parse_url(client, url.clone(), m3u8_file_name.clone()).await?
         } else {
-            Ok(false)
+            parse_local(url.clone(), String::default()).await?
+        };
+        if inherit_query {
+            hls_m3u.inherit_query();
         }
+        Ok(hls_m3u)
     }
 
-    pub fn create_folder(folder: String) -> io::Result<()> {
+    pub fn create_folder(folder: &Path) -> io::Result<()> {
         // 检查文件夹是否存在
-        if !fs::metadata(folder.clone()).is_ok() {
+        if fs::metadata(folder).is_err() {
             // 文件夹不存在，创建文件夹
-            match fs::create_dir(folder.clone()) {
+            match fs::create_dir_all(folder) {
                 Ok(_) => Ok(()),
                 Err(e) => {
-                    println!("创建文件夹时出错：{}", e);
+                    error!(folder = %folder.display(), error = %e, "创建文件夹时出错");
                     Err(e)
                 }
             }
@@ -207,53 +1235,160 @@ pub mod download {
             Ok(())
         }
     }
+}
 
-    pub fn get_file_name(file_name: String) -> String {
-        let mut target = file_name;
-        if target.is_empty() {
-            target = format!("{}.mp4", now());
-        }
-        target
+// 下载一个分片, 已经下载完成的分片直接跳过
+async fn download_ts_file(client: &HttpClient, video_ts: VideoTs, retry: Retry, manifest: Option<&ManifestStore>) -> Result<bool, crate::error::Error> {
+    let download_file_name = video_ts.file_name();
+    // 有 manifest 时按记录的大小和 md5 校验, 不完整或者损坏的分片重新下载
+    let done = match manifest {
+        Some(manifest) => manifest.verify(&download_file_name),
+        None => fs::metadata(download_file_name.clone()).is_ok(),
+    };
+    if done {
+        debug!("分片已存在, 跳过");
+        return Ok(true);
     }
+    debug!("开始下载分片");
+    let res = download_file_retry(client, video_ts.url.clone(), download_file_name.clone(), video_ts.byte_range, retry).await;
+    if let Some(manifest) = manifest {
+        manifest.finish(&download_file_name, res.is_ok());
+    }
+    res
 }
 
-fn download_ts_file(video_ts: VideoTs) -> bool {
-    println!("---pass {}", video_ts.url.clone());
-    let download_file_name = format!("./{}.{}", video_ts.index, video_ts.extension.clone());
-    match fs::metadata(download_file_name.clone()) {
-        Ok(_) => {
-            println!("file {} exists", video_ts.url.clone());
-            true
-        }
-        Err(_) => {
-            let rt = Runtime::new().unwrap();
-            rt.block_on(async {
-                println!("download ts file {}", video_ts.url.clone());
-                let res = download_file(video_ts.url.clone(), download_file_name, ).await;
-                return match res {
-                    Ok(data) => data,
-                    _ => false,
-                };
-            })
+#[cfg(test)]
+mod tests {
+    use crate::common::Retry;
+    use crate::http::HttpClient;
+    use crate::download::{live_new_segments, DownloadPool, Downloader, MediaTrack, TrackKind, VideoTs};
+    use crate::m3u8::m3u8::str_to_urls;
+    use crate::progress::Progress;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn test_backoff() {
+        let retry = Retry { retries: 3, delay: Duration::from_millis(100) };
+        for attempt in 0..3 {
+            let base = Duration::from_millis(100 * (1 << attempt));
+            let wait = retry.backoff(attempt);
+            assert!(wait >= base / 2 && wait <= base, "{:?} {:?}", wait, base);
         }
     }
-}
 
-async fn download_ts_file_async(video_ts: VideoTs) ->  bool {
-    println!("---pass {}", video_ts.url.clone());
-    let download_file_name = format!("./{}.{}", video_ts.index, video_ts.extension.clone());
-    match fs::metadata(download_file_name.clone()) {
-        Ok(_) => {
-            println!("file {} exists", video_ts.url.clone());
-            true
-        }
-        Err(_) => {
-            println!("download ts file {}", video_ts.url.clone());
-            let res = download_file(video_ts.url.clone(), download_file_name, ).await;
-            return match res {
-                Ok(data) => data,
-                _ => false,
-            };
+    #[tokio::test]
+    async fn test_pool_reports_failed_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let retry = Retry { retries: 1, delay: Duration::from_millis(1) };
+        let mut pool = DownloadPool::new(HttpClient::default(), 2, Arc::new(Progress::default()), retry);
+        for index in 0..2 {
+            let mut ts = VideoTs::new();
+            // 没有服务监听的端口, 连接失败
+            ts.set(index, format!("http://127.0.0.1:1/{}.ts", index), "ts".to_string(), format!("{}/", dir.path().display()));
+            pool.push(ts);
+        }
+        let (total, failed) = pool.finish().await;
+        assert_eq!(total, 2);
+        assert_eq!(failed.iter().map(|f| f.index).collect::<Vec<_>>(), vec![0, 1]);
+        assert!(failed[0].url.ends_with("/0.ts"));
+    }
+
+    #[tokio::test]
+    async fn test_pool_stops_on_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        // 只监听不响应, 分片一直下载不完
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let cancel = CancellationToken::new();
+        let retry = Retry { retries: 0, delay: Duration::from_millis(1) };
+        let mut pool = DownloadPool::new(HttpClient::default(), 1, Arc::new(Progress::default()), retry).cancel_token(cancel.clone());
+        let mut files = vec![];
+        for index in 0..2 {
+            let mut ts = VideoTs::new();
+            ts.set(index, format!("http://{}/{}.ts", addr, index), "ts".to_string(), format!("{}/", dir.path().display()));
+            files.push(ts.file_name());
+            pool.push(ts);
         }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cancel.cancel();
+        let (total, failed) = tokio::time::timeout(Duration::from_secs(5), pool.finish()).await.unwrap();
+        assert_eq!(total, 2);
+        assert!(failed.is_empty());
+        assert!(files.iter().all(|file| !std::path::Path::new(file).exists()));
     }
-}
\ No newline at end of file
+
+    #[tokio::test]
+    async fn test_skip_discontinuities_mid_roll() {
+        // 片头广告、正片、插在中间的广告、正片
+        let playlist = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXTINF:5,
+https://ads.example.com/a/0.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:10,
+main/0.ts
+#EXTINF:10,
+main/1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:15,
+https://ads.example.com/b/0.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:10,
+main/2.ts
+";
+        let url = "https://example.com/v/index.m3u8".to_string();
+        let options = Downloader::new(url.clone()).skip_discontinuities(true);
+        let urls = |track: &MediaTrack, options: &Downloader| -> Vec<String> {
+            track.combine_runs(options).into_iter().flat_map(|run| track.playlist.list[run].iter().map(|s| s.url.clone()).collect::<Vec<_>>()).collect()
+        };
+        let main = vec!["https://example.com/v/main/0.ts", "https://example.com/v/main/1.ts", "https://example.com/v/main/2.ts"];
+
+        // 点播在下载前跳过一次, 合并时不再筛选
+        let mut track = MediaTrack::new(TrackKind::Video, url.clone(), String::default(), String::default(), String::default(), str_to_urls(playlist.to_string(), url.clone()).await.unwrap());
+        track.skip_discontinuities();
+        assert_eq!(track.playlist.list.len(), 3);
+        assert_eq!(urls(&track, &options), main);
+
+        // 直播录制时在合并时跳过
+        let track = MediaTrack::new(TrackKind::Video, url.clone(), String::default(), String::default(), String::default(), str_to_urls(playlist.to_string(), url.clone()).await.unwrap());
+        assert_eq!(urls(&track, &options.live(true)), main);
+    }
+
+    #[tokio::test]
+    async fn test_live_new_segments() {
+        let url = "https://example.com/live/index.m3u8".to_string();
+        let playlist = "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:100
+#EXTINF:6,
+100.ts
+#EXTINF:6,
+101.ts
+#EXTINF:6,
+102.ts
+";
+        let hls = str_to_urls(playlist.to_string(), url.clone()).await.unwrap();
+        let sequences = |list: &[crate::m3u8::HlsSegment]| list.iter().map(|s| s.sequence).collect::<Vec<_>>();
+        assert_eq!(sequences(live_new_segments(&hls, None, None)), [100, 101, 102]);
+        assert_eq!(sequences(live_new_segments(&hls, Some(99), Some(101))), [102]);
+        assert!(live_new_segments(&hls, Some(100), Some(102)).is_empty());
+
+        // 编码器重启后从 0 开始编号, 不连续之前的分片已经录制过
+        let playlist = "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:0
+#EXTINF:6,
+102.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:6,
+restart0.ts
+#EXTINF:6,
+restart1.ts
+";
+        let hls = str_to_urls(playlist.to_string(), url.clone()).await.unwrap();
+        let urls: Vec<&str> = live_new_segments(&hls, Some(100), Some(102)).iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls, ["https://example.com/live/restart0.ts", "https://example.com/live/restart1.ts"]);
+        // 下一次重新加载时序号没有再变小, 按序号去重
+        assert!(live_new_segments(&hls, Some(0), Some(2)).is_empty());
+    }
+}
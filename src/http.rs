@@ -0,0 +1,455 @@
+use crate::common::now;
+use crate::error::Error;
+use crate::logging::{redact_header, redact_url};
+use crate::progress::Progress;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, COOKIE};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::debug;
+use url::Url;
+
+// 一条 cookie, domain 为空时发送给所有地址
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cookie {
+    pub domain: String,
+    pub include_subdomains: bool,
+    pub path: String,
+    pub secure: bool,
+    pub expires: u64, // 过期时间戳, 0 表示会话 cookie
+    pub name: String,
+    pub value: String,
+}
+
+impl Cookie {
+    // 解析 "a=1; b=2" 这样的 cookie, 发送给所有地址
+    pub fn parse_pairs(str: &str) -> Vec<Cookie> {
+        str.split(';')
+            .filter_map(|item| item.trim().split_once('='))
+            .map(|(name, value)| Cookie {
+                domain: String::default(),
+                include_subdomains: true,
+                path: "/".to_string(),
+                secure: false,
+                expires: 0,
+                name: name.trim().to_string(),
+                value: value.trim().to_string(),
+            })
+            .collect()
+    }
+
+    // 解析 Netscape 格式的 cookie 文件(curl、浏览器插件导出的 cookies.txt)
+    pub fn parse_netscape(content: &str) -> Vec<Cookie> {
+        let mut cookies = vec![];
+        for line in content.lines() {
+            // #HttpOnly_ 开头的是 HttpOnly cookie, 其他 # 开头的是注释
+            let line = line.strip_prefix("#HttpOnly_").unwrap_or(line);
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 7 {
+                continue;
+            }
+            cookies.push(Cookie {
+                domain: fields[0].to_lowercase(),
+                include_subdomains: fields[1].eq_ignore_ascii_case("TRUE"),
+                path: fields[2].to_string(),
+                secure: fields[3].eq_ignore_ascii_case("TRUE"),
+                expires: fields[4].parse().unwrap_or(0),
+                name: fields[5].to_string(),
+                value: fields[6].trim_end_matches('\r').to_string(),
+            });
+        }
+        cookies
+    }
+
+    pub fn read_netscape(file: &Path) -> Result<Vec<Cookie>, Error> {
+        Ok(Cookie::parse_netscape(&fs::read_to_string(file)?))
+    }
+
+    pub fn matches(&self, url: &Url) -> bool {
+        if self.expires > 0 && self.expires < now() {
+            return false;
+        }
+        if self.secure && url.scheme() != "https" {
+            return false;
+        }
+        if !url.path().starts_with(&self.path) {
+            return false;
+        }
+        if self.domain.is_empty() {
+            return true;
+        }
+        let host = url.host_str().unwrap_or_default().to_lowercase();
+        let domain = self.domain.trim_start_matches('.');
+        host == domain || ((self.include_subdomains || self.domain.starts_with('.')) && host.ends_with(&format!(".{}", domain)))
+    }
+}
+
+// 下载时共用的 http 客户端, 复用连接, https 时通过 ALPN 协商 HTTP/2,
+// 每个请求都带上设置的请求头, 以及和地址匹配的 cookie
+// 没有指定代理时使用 HTTP_PROXY、HTTPS_PROXY、NO_PROXY 环境变量,
+// 代理支持 http://、https://、socks5://, socks5h:// 由代理服务器解析域名
+#[derive(Debug, Clone)]
+pub struct HttpClient {
+    client: reqwest::Client,
+    headers: Vec<(String, String)>,
+    cookies: Arc<Vec<Cookie>>,
+    proxy: Option<String>,
+    limiter: Option<Arc<RateLimiter>>, // 所有连接共用的限速
+    connection_rate: u64,              // 每个连接的限速, 0 表示不限制
+    progress: Arc<Progress>,           // 记录已下载的字节数
+}
+
+impl HttpClient {
+    pub fn new(max_idle_per_host: usize, headers: Vec<(String, String)>, cookies: Vec<Cookie>, proxy: Option<String>) -> Result<HttpClient, Error> {
+        let mut default_headers = HeaderMap::new();
+        for (name, value) in &headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| Error::Parse(format!("请求头 {} 无效, {}", name, e)))?;
+            let value = HeaderValue::from_str(value).map_err(|e| Error::Parse(format!("请求头 {} 的值无效, {}", name, e)))?;
+            default_headers.append(name, value);
+        }
+        let mut builder = reqwest::Client::builder();
+        if let Some(proxy) = &proxy {
+            check_proxy(proxy)?;
+            builder = builder.proxy(reqwest::Proxy::all(proxy).map_err(|e| Error::Parse(format!("代理 {} 无效, {}", proxy, e)))?);
+        }
+        let client = builder
+            .default_headers(default_headers)
+            .pool_max_idle_per_host(max_idle_per_host)
+            .tcp_keepalive(Duration::from_secs(60))
+            .connect_timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| Error::Parse(format!("创建 http client 失败, {}", e)))?;
+        for (name, value) in &headers {
+            debug!(header = %redact_header(name, value), "请求头");
+        }
+        if let Some(proxy) = &proxy {
+            debug!(proxy = %redact_url(proxy), "使用代理");
+        }
+        Ok(HttpClient {
+            client,
+            headers,
+            cookies: Arc::new(cookies),
+            proxy,
+            limiter: None,
+            connection_rate: 0,
+            progress: Arc::new(Progress::default()),
+        })
+    }
+
+    // 限速, 单位：字节/秒, 0 表示不限制
+    pub fn limit_rate(mut self, rate: u64, connection_rate: u64) -> Self {
+        self.limiter = (rate > 0).then(|| Arc::new(RateLimiter::new(rate)));
+        self.connection_rate = connection_rate;
+        self
+    }
+
+    // 每次请求创建一个连接的限速
+    pub fn connection_limiter(&self) -> Option<RateLimiter> {
+        (self.connection_rate > 0).then(|| RateLimiter::new(self.connection_rate))
+    }
+
+    // 记录收到的字节数, 超过限速时等待
+    pub async fn consume(&self, bytes: u64, connection: Option<&RateLimiter>) {
+        self.progress.add_bytes(bytes);
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire(bytes).await;
+        }
+        if let Some(limiter) = connection {
+            limiter.acquire(bytes).await;
+        }
+    }
+
+    pub fn progress(mut self, progress: Arc<Progress>) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    pub fn received(&self) -> u64 {
+        self.progress.bytes()
+    }
+
+    pub fn get(&self, url: &str) -> reqwest::RequestBuilder {
+        self.with_cookie(self.client.get(url), url)
+    }
+
+    pub fn head(&self, url: &str) -> reqwest::RequestBuilder {
+        self.with_cookie(self.client.head(url), url)
+    }
+
+    fn with_cookie(&self, request: reqwest::RequestBuilder, url: &str) -> reqwest::RequestBuilder {
+        match self.cookie_header(url) {
+            Some(cookie) => request.header(COOKIE, cookie),
+            None => request,
+        }
+    }
+
+    // 和地址匹配的 cookie, 拼成 Cookie 请求头
+    pub fn cookie_header(&self, url: &str) -> Option<String> {
+        let url = Url::parse(url).ok()?;
+        let pairs: Vec<String> = self
+            .cookies
+            .iter()
+            .filter(|c| c.matches(&url))
+            .map(|c| format!("{}={}", c.name, c.value))
+            .collect();
+        if pairs.is_empty() {
+            None
+        } else {
+            Some(pairs.join("; "))
+        }
+    }
+
+    // ffmpeg -headers 参数, 每个请求头以 \r\n 结尾
+    pub fn ffmpeg_headers(&self, url: &str) -> String {
+        let mut lines: Vec<String> = self.headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+        if let Some(cookie) = self.cookie_header(url) {
+            lines.push(format!("Cookie: {}\r\n", cookie));
+        }
+        lines.concat()
+    }
+
+    // ffmpeg -http_proxy 参数, ffmpeg 只支持 http 代理, 也不会读取大写的环境变量, 这里按地址解析好传进去
+    pub fn ffmpeg_proxy(&self, url: &str) -> Result<Option<String>, Error> {
+        let proxy = match &self.proxy {
+            Some(proxy) => Some(proxy.clone()),
+            None => proxy_from_env(url, |name| std::env::var(name).ok()),
+        };
+        match proxy {
+            Some(proxy) if !proxy.starts_with("http://") => Err(Error::Parse(format!("ffmpeg 只支持 http 代理, 不支持 {}", proxy))),
+            proxy => Ok(proxy),
+        }
+    }
+}
+
+// 令牌桶限速, 每秒补充 rate 个令牌, 最多攒 1 秒的令牌;
+// 令牌不够时先记账, 等待补齐欠下的令牌, 多个下载同时取令牌时按先后排队
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    state: Mutex<(f64, Instant)>, // (剩余令牌, 上次补充的时间)
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64) -> RateLimiter {
+        RateLimiter {
+            rate: rate.max(1),
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let rate = self.rate as f64;
+            state.0 = (state.0 + now.duration_since(state.1).as_secs_f64() * rate).min(rate);
+            state.1 = now;
+            state.0 -= bytes as f64;
+            if state.0 < 0.0 {
+                Duration::from_secs_f64(-state.0 / rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+// 解析 "500K"、"5M"、"1.5MB" 这样的速度, 单位按 1024 换算, 返回字节/秒
+pub fn parse_rate(str: &str) -> Result<u64, Error> {
+    let value = str.trim().to_uppercase();
+    let value = value.trim_end_matches("/S").trim_end_matches('B');
+    let (number, unit) = match value.char_indices().last() {
+        Some((index, c)) if c.is_ascii_alphabetic() => (&value[..index], c),
+        _ => (value, ' '),
+    };
+    let scale = match unit {
+        ' ' => 1,
+        'K' => 1024,
+        'M' => 1024 * 1024,
+        'G' => 1024 * 1024 * 1024,
+        _ => return Err(Error::Parse(format!("速度 {} 无效, 单位支持 K、M、G", str))),
+    };
+    let number: f64 = number.trim().parse().map_err(|_| Error::Parse(format!("速度 {} 无效", str)))?;
+    Ok((number * scale as f64) as u64)
+}
+
+// 按 B、KB、MB、GB 显示大小
+pub fn format_bytes(bytes: u64) -> String {
+    let units = ["B", "KB", "MB", "GB"];
+    let mut value = bytes as f64;
+    let mut index = 0;
+    while value >= 1024.0 && index < units.len() - 1 {
+        value /= 1024.0;
+        index += 1;
+    }
+    format!("{:.2}{}", value, units[index])
+}
+
+fn check_proxy(proxy: &str) -> Result<(), Error> {
+    let scheme = Url::parse(proxy).map(|u| u.scheme().to_string()).unwrap_or_default();
+    if ["http", "https", "socks5", "socks5h"].contains(&scheme.as_str()) {
+        Ok(())
+    } else {
+        Err(Error::Parse(format!("代理 {} 无效, 支持 http://、https://、socks5://、socks5h://", proxy)))
+    }
+}
+
+// 按 HTTPS_PROXY/HTTP_PROXY/ALL_PROXY(大小写都可以)得到 url 使用的代理, 在 NO_PROXY 中的地址不使用代理
+fn proxy_from_env(url: &str, env: impl Fn(&str) -> Option<String>) -> Option<String> {
+    let get = |name: &str| env(name).or_else(|| env(&name.to_lowercase())).filter(|v| !v.is_empty());
+    let url = Url::parse(url).ok()?;
+    let host = url.host_str()?.to_lowercase();
+    let no_proxy = get("NO_PROXY").unwrap_or_default();
+    for item in no_proxy.split(',').map(|i| i.trim().to_lowercase()) {
+        let domain = item.trim_start_matches('.');
+        if item == "*" || (!domain.is_empty() && (host == domain || host.ends_with(&format!(".{}", domain)))) {
+            return None;
+        }
+    }
+    let name = if url.scheme() == "https" { "HTTPS_PROXY" } else { "HTTP_PROXY" };
+    get(name).or_else(|| get("ALL_PROXY"))
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient::new(10, vec![], vec![], None).expect("create http client error")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::download_file;
+    use crate::http::{format_bytes, parse_rate, proxy_from_env, Cookie, HttpClient, RateLimiter};
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Instant;
+    use url::Url;
+
+    // 测试用的代理, 不转发请求, 直接返回请求的地址, 用来确认请求经过了代理
+    fn serve_proxy(handshake: fn(&mut TcpStream) -> String) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let target = handshake(&mut stream);
+            let mut buf = [0; 4096];
+            let n = stream.read(&mut buf).unwrap();
+            let line = String::from_utf8_lossy(&buf[..n]).lines().next().unwrap_or_default().to_string();
+            let body = format!("{} {}", target, line);
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body);
+        });
+        addr.to_string()
+    }
+
+    // socks5 握手, 返回客户端请求连接的地址
+    fn socks5_handshake(stream: &mut TcpStream) -> String {
+        let mut buf = [0; 512];
+        let _ = stream.read(&mut buf).unwrap();
+        stream.write_all(&[5, 0]).unwrap();
+        let n = stream.read(&mut buf).unwrap();
+        // ATYP 为 3 时是域名, 由代理解析
+        let target = match buf[3] {
+            3 => format!("domain:{}", String::from_utf8_lossy(&buf[5..5 + buf[4] as usize])),
+            _ => format!("ip:{:?}", &buf[4..n - 2]),
+        };
+        stream.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).unwrap();
+        target
+    }
+
+    #[tokio::test]
+    async fn test_http_proxy() {
+        let proxy = serve_proxy(|_| "http".to_string());
+        let client = HttpClient::new(1, vec![], vec![], Some(format!("http://{}", proxy))).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.ts").display().to_string();
+        download_file(&client, "http://video.invalid/a.ts".to_string(), file.clone()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "http GET http://video.invalid/a.ts HTTP/1.1");
+    }
+
+    #[tokio::test]
+    async fn test_socks5h_proxy() {
+        let proxy = serve_proxy(socks5_handshake);
+        let client = HttpClient::new(1, vec![], vec![], Some(format!("socks5h://{}", proxy))).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("a.ts").display().to_string();
+        download_file(&client, "http://video.invalid/a.ts".to_string(), file.clone()).await.unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "domain:video.invalid GET /a.ts HTTP/1.1");
+    }
+
+    #[test]
+    fn test_parse_rate() {
+        assert_eq!(parse_rate("5M").unwrap(), 5 * 1024 * 1024);
+        assert_eq!(parse_rate("500k").unwrap(), 500 * 1024);
+        assert_eq!(parse_rate("1.5MB").unwrap(), 1536 * 1024);
+        assert_eq!(parse_rate("2048").unwrap(), 2048);
+        assert!(parse_rate("5X").is_err());
+        assert!(parse_rate("fast").is_err());
+        assert_eq!(format_bytes(1536 * 1024), "1.50MB");
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter() {
+        let limiter = RateLimiter::new(10_000);
+        let start = Instant::now();
+        // 一开始有 1 秒的令牌, 之后按速度等待
+        limiter.acquire(10_000).await;
+        assert!(start.elapsed().as_millis() < 100);
+        limiter.acquire(5_000).await;
+        let elapsed = start.elapsed().as_millis();
+        assert!((450..1000).contains(&elapsed), "{}", elapsed);
+    }
+
+    #[test]
+    fn test_proxy_from_env() {
+        let env = |name: &str| match name {
+            "https_proxy" => Some("http://proxy:3128".to_string()),
+            "ALL_PROXY" => Some("socks5h://proxy:1080".to_string()),
+            "NO_PROXY" => Some("localhost, .internal.com".to_string()),
+            _ => None,
+        };
+        assert_eq!(proxy_from_env("https://example.com/a.m3u8", env).unwrap(), "http://proxy:3128");
+        assert_eq!(proxy_from_env("http://example.com/a.m3u8", env).unwrap(), "socks5h://proxy:1080");
+        assert!(proxy_from_env("https://cdn.internal.com/a.ts", env).is_none());
+        assert!(proxy_from_env("http://localhost/a.ts", env).is_none());
+        let client = HttpClient::new(1, vec![], vec![], Some("socks5h://127.0.0.1:1080".to_string())).unwrap();
+        assert!(client.ffmpeg_proxy("https://example.com/a.m3u8").is_err());
+        assert!(HttpClient::new(1, vec![], vec![], Some("ftp://proxy".to_string())).is_err());
+    }
+
+    #[test]
+    fn test_netscape_cookies() {
+        let content = "# Netscape HTTP Cookie File\n\
+            .example.com\tTRUE\t/\tFALSE\t0\tsession\tabc\n\
+            #HttpOnly_cdn.example.com\tFALSE\t/video\tTRUE\t0\ttoken\txyz\n\
+            old.example.com\tFALSE\t/\tFALSE\t1\texpired\t1\n";
+        let cookies = Cookie::parse_netscape(content);
+        assert_eq!(cookies.len(), 3);
+        let url = Url::parse("https://cdn.example.com/video/1.ts").unwrap();
+        assert!(cookies[0].matches(&url));
+        assert!(cookies[1].matches(&url));
+        assert!(!cookies[1].matches(&Url::parse("http://cdn.example.com/video/1.ts").unwrap()));
+        assert!(!cookies[1].matches(&Url::parse("https://a.cdn.example.com/video/1.ts").unwrap()));
+        assert!(!cookies[2].matches(&Url::parse("https://old.example.com/").unwrap()));
+        assert!(!cookies[0].matches(&Url::parse("https://example.org/").unwrap()));
+    }
+
+    #[test]
+    fn test_headers() {
+        let headers = vec![("User-Agent".to_string(), "test".to_string()), ("Referer".to_string(), "https://example.com/".to_string())];
+        let client = HttpClient::new(1, headers, Cookie::parse_pairs("a=1; b=2"), None).unwrap();
+        assert_eq!(client.cookie_header("https://example.com/1.ts").unwrap(), "a=1; b=2");
+        assert_eq!(
+            client.ffmpeg_headers("https://example.com/1.ts"),
+            "User-Agent: test\r\nReferer: https://example.com/\r\nCookie: a=1; b=2\r\n"
+        );
+        assert!(HttpClient::new(1, vec![("bad header".to_string(), "1".to_string())], vec![], None).is_err());
+    }
+}
@@ -0,0 +1,42 @@
+//! 下载 HLS/DASH 视频、合并和截取视频, 命令行工具也是基于这些接口实现的
+//!
+//! ```no_run
+//! # async fn demo() -> Result<(), media_tool_rs::Error> {
+//! use media_tool_rs::{Combiner, Cutter, Downloader};
+//!
+//! let file = Downloader::new("https://zmis.me/index.m3u8").concurrency(8).output("out.mp4").run().await?;
+//! Cutter::new(file.display().to_string()).start(5).duration(10).output("cut.mp4").run()?;
+//! Combiner::new("video(.*).mp4", 1, 3).output("all.mp4").run()?;
+//! # Ok(())
+//! # }
+//! ```
+
+extern crate core;
+
+mod cmd;
+mod combine;
+mod common;
+pub mod dash;
+mod download;
+pub mod error;
+mod http;
+pub mod logging;
+pub mod m3u8;
+mod manifest;
+pub mod progress;
+mod repeat;
+mod sample_aes;
+pub mod server;
+mod verify;
+
+pub use crate::cmd::Cutter;
+pub use crate::common::now;
+pub use crate::combine::Combiner;
+pub use crate::dash::{DashMpd, DashRepresentation};
+pub use crate::download::Downloader;
+pub use crate::error::Error;
+pub use crate::http::{parse_rate, Cookie, HttpClient};
+pub use crate::m3u8::{HlsByteRange, HlsKey, HlsM3u8, HlsM3u8Method, HlsMap, HlsRendition, HlsSegment, HlsVariant, PlaylistType, TimePoint, VariantSelect};
+pub use crate::progress::{Progress, ProgressMode, Reporter};
+pub use crate::server::Server;
+pub use crate::verify::VerifyMode;
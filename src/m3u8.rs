@@ -1,217 +1,1477 @@
-use crate::common::{download_file, get_url_host, is_url, replace_last_segment};
+use crate::common::{format_time, inherit_query, resolve_url};
+use chrono::{DateTime, FixedOffset};
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
 
 pub struct HlsM3u8 {
-    pub key: String,
-    pub list: Vec<String>,
-    pub method: Option<HlsM3u8Method>,
-    pub iv: String,
+    pub list: Vec<HlsSegment>,
     original_url: String,
-    folder: String, // 文件夹
-    pub sequence: i32, //序号
+    pub sequence: u64, //序号
+    pub target_duration: u64, // #EXT-X-TARGETDURATION, 秒
+    pub discontinuity_sequence: u64, // #EXT-X-DISCONTINUITY-SEQUENCE
+    pub version: u64, // #EXT-X-VERSION, 没有时为 1
+    pub playlist_type: Option<PlaylistType>, // #EXT-X-PLAYLIST-TYPE
+    pub endlist: bool, // 是否有 #EXT-X-ENDLIST, 直播流没有
     pub x_map_uri:String,
+    pub x_map_byte_range: Option<HlsByteRange>, // #EXT-X-MAP 的 BYTERANGE
     pub extension:String,//视频扩展字段
+    pub variants: Vec<HlsVariant>, // 主播放列表中的码率变体
+    pub renditions: Vec<HlsRendition>, // 主播放列表中的 #EXT-X-MEDIA
+}
+
+// 主播放列表(master playlist)中的一个码率变体, 对应 #EXT-X-STREAM-INF
+#[derive(Debug, Clone)]
+pub struct HlsVariant {
+    pub uri: String,
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>, // 宽, 高
+    pub codecs: String,
+    pub frame_rate: f32,
+    pub audio: String,     // 音频分组 GROUP-ID
+    pub subtitles: String, // 字幕分组 GROUP-ID
+}
+
+// #EXT-X-MEDIA 的 TYPE
+#[derive(Debug, Clone, PartialEq)]
+pub enum RenditionType {
+    Audio,
+    Video,
+    Subtitles,
+    ClosedCaptions,
+}
+
+// 主播放列表中的音频/字幕等独立轨道, 对应 #EXT-X-MEDIA
+#[derive(Debug, Clone)]
+pub struct HlsRendition {
+    pub media_type: RenditionType,
+    pub group_id: String,
+    pub language: String,
+    pub name: String,
+    pub default: bool,
+    pub autoselect: bool,
+    pub uri: String, // 为空表示该轨道已经包含在变体的流里
+}
+
+impl HlsRendition {
+    // 语言匹配, 忽略大小写, "en" 可以匹配 "en-US"
+    pub fn match_language(&self, language: &str) -> bool {
+        let own = self.language.to_lowercase();
+        let target = language.trim().to_lowercase();
+        !target.is_empty() && (own == target || own.starts_with(&format!("{}-", target)))
+    }
+}
+
+impl HlsVariant {
+    pub fn height(&self) -> u32 {
+        match self.resolution {
+            Some((_, height)) => height,
+            None => 0,
+        }
+    }
+}
+
+// 码率变体的选择方式: highest|lowest|<height>p|<index>
+#[derive(Debug, Clone, PartialEq)]
+pub enum VariantSelect {
+    Highest,
+    Lowest,
+    Height(u32),
+    Index(usize),
+}
+
+impl FromStr for VariantSelect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let value = s.trim().to_lowercase();
+        if value == "highest" {
+            return Ok(VariantSelect::Highest);
+        }
+        if value == "lowest" {
+            return Ok(VariantSelect::Lowest);
+        }
+        if let Some(height) = value.strip_suffix('p') {
+            return match height.parse::<u32>() {
+                Ok(data) => Ok(VariantSelect::Height(data)),
+                Err(_) => Err(format!("无效的分辨率: {}", s)),
+            };
+        }
+        match value.parse::<usize>() {
+            Ok(data) => Ok(VariantSelect::Index(data)),
+            Err(_) => Err(format!("无效的variant: {}, 可选 highest|lowest|<height>p|<index>", s)),
+        }
+    }
+}
+
+// 和 from_str 的格式一致, 保存在 manifest.json 中
+impl fmt::Display for VariantSelect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VariantSelect::Highest => write!(f, "highest"),
+            VariantSelect::Lowest => write!(f, "lowest"),
+            VariantSelect::Height(height) => write!(f, "{}p", height),
+            VariantSelect::Index(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+// #EXT-X-PLAYLIST-TYPE, 没有时可能是直播
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PlaylistType {
+    Vod,   // 播放列表不会再变化
+    Event, // 只会在末尾追加分片
+}
+
+impl FromStr for PlaylistType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "VOD" => Ok(PlaylistType::Vod),
+            "EVENT" => Ok(PlaylistType::Event),
+            _ => Err(format!("未知的播放列表类型: {}", s.trim())),
+        }
+    }
 }
 
 // SAMPLE-AES || AES-128
+#[derive(Debug, Clone, PartialEq)]
 pub enum HlsM3u8Method {
     SampleAes,
     Aes128,
 }
 
+// 和 METHOD 属性的写法一致
+impl fmt::Display for HlsM3u8Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HlsM3u8Method::SampleAes => write!(f, "SAMPLE-AES"),
+            HlsM3u8Method::Aes128 => write!(f, "AES-128"),
+        }
+    }
+}
+
+// #EXT-X-KEY, 对之后的分片生效, 直到下一个 #EXT-X-KEY
+#[derive(Debug, Clone, PartialEq)]
+pub struct HlsKey {
+    pub method: HlsM3u8Method,
+    pub uri: String,
+    pub iv: String, // 0x 开头的16进制, 可以为空
+}
+
+impl HlsKey {
+    // IV 为 0x 开头的16进制, 没有指定时使用分片的媒体序号(128位大端)
+    pub fn iv_for(&self, sequence: u64) -> [u8; 16] {
+        let mut data = [0u8; 16];
+        let hex = self.iv.trim().trim_start_matches("0x").trim_start_matches("0X");
+        if hex.is_empty() || hex.len() > 32 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            data[8..].copy_from_slice(&sequence.to_be_bytes());
+            return data;
+        }
+        let padded = format!("{:0>32}", hex);
+        for (i, item) in data.iter_mut().enumerate() {
+            *item = u8::from_str_radix(&padded[i * 2..i * 2 + 2], 16).unwrap_or(0);
+        }
+        data
+    }
+}
+
+// #EXT-X-BYTERANGE, 分片只是资源中的一段
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HlsByteRange {
+    pub length: u64,
+    pub offset: u64,
+}
+
+impl HlsByteRange {
+    // 解析 <length>[@<offset>], 没有 offset 时返回 None。长度为 0 时没有可以请求的字节, 按错误处理
+    pub fn parse(str: &str) -> Result<(u64, Option<u64>), String> {
+        let str = str.trim().trim_matches('"');
+        let invalid = || format!("无效的范围: {}", str);
+        let (length, offset) = match str.split_once('@') {
+            Some((length, offset)) => (length.trim(), Some(offset.trim().parse().map_err(|_| invalid())?)),
+            None => (str, None),
+        };
+        match length.parse::<u64>().map_err(|_| invalid())? {
+            0 => Err(format!("范围的长度不能为 0: {}", str)),
+            length => Ok((length, offset)),
+        }
+    }
+
+    // 最后一个字节的位置, 用于 HTTP Range 请求
+    pub fn end(&self) -> u64 {
+        self.offset + self.length - 1
+    }
+}
+
+// 属性列表中的值, 按 RFC 8216 4.2 区分类型
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    Integer(u64),         // decimal-integer
+    Hex(String),          // hexadecimal-sequence, 保留 0x 前缀
+    Float(f64),           // decimal-floating-point, 可以是负数
+    Resolution(u32, u32), // decimal-resolution, 宽x高
+    Quoted(String),       // quoted-string, 不含引号
+    Enumerated(String),   // enumerated-string, 如 AES-128、YES
+}
+
+impl AttributeValue {
+    // 没有引号的值按格式判断类型
+    fn from_text(text: &str) -> AttributeValue {
+        let digits = |s: &str| !s.is_empty() && s.chars().all(|c| c.is_ascii_digit());
+        if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            if !hex.is_empty() && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                return AttributeValue::Hex(text.to_string());
+            }
+        }
+        if let Some((width, height)) = text.split_once('x') {
+            if let (true, true, Ok(w), Ok(h)) = (digits(width), digits(height), width.parse(), height.parse()) {
+                return AttributeValue::Resolution(w, h);
+            }
+        }
+        if digits(text) {
+            if let Ok(data) = text.parse() {
+                return AttributeValue::Integer(data);
+            }
+        }
+        let unsigned = text.strip_prefix('-').unwrap_or(text);
+        if let Some((int, frac)) = unsigned.split_once('.') {
+            if digits(int) && (frac.is_empty() || digits(frac)) {
+                if let Ok(data) = text.parse() {
+                    return AttributeValue::Float(data);
+                }
+            }
+        }
+        AttributeValue::Enumerated(text.to_string())
+    }
+}
+
+// 标签冒号后面的属性列表, 如 METHOD=AES-128,URI="key.bin",IV=0x1234。
+// 属性的顺序不固定, 带引号的值里可以有逗号和等号
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AttributeList {
+    items: Vec<(String, AttributeValue)>,
+}
+
+impl AttributeList {
+    // 格式错误时返回原因, 逗号前后的空格和末尾多余的逗号可以兼容
+    pub fn parse(str: &str) -> Result<AttributeList, String> {
+        let mut list = AttributeList::default();
+        let mut rest = str.trim();
+        while !rest.is_empty() {
+            let (name, value) = rest.split_once('=').ok_or_else(|| format!("属性 {} 缺少值", rest))?;
+            let name = name.trim();
+            if name.is_empty() || !name.chars().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '-') {
+                return Err(format!("无效的属性名: {}", name));
+            }
+            let value = value.trim_start();
+            let (item, next) = match value.strip_prefix('"') {
+                Some(quoted) => {
+                    let end = quoted.find('"').ok_or_else(|| format!("{} 的引号没有结束", name))?;
+                    let after = quoted[end + 1..].trim_start();
+                    let next = match after.strip_prefix(',') {
+                        Some(next) => next,
+                        None if after.is_empty() => after,
+                        None => return Err(format!("{} 的值后面缺少逗号", name)),
+                    };
+                    (AttributeValue::Quoted(quoted[..end].to_string()), next)
+                }
+                None => {
+                    let (text, next) = value.split_once(',').unwrap_or((value, ""));
+                    let text = text.trim();
+                    if text.is_empty() {
+                        return Err(format!("{} 的值为空", name));
+                    }
+                    if text.contains('"') {
+                        return Err(format!("{} 的值 {} 中的引号不完整", name, text));
+                    }
+                    (AttributeValue::from_text(text), next)
+                }
+            };
+            if list.get(name).is_some() {
+                return Err(format!("属性 {} 重复", name));
+            }
+            list.items.push((name.to_string(), item));
+            rest = next.trim_start();
+        }
+        Ok(list)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&AttributeValue> {
+        self.items.iter().find(|(key, _)| key == name).map(|(_, value)| value)
+    }
+
+    // 字符串和枚举值, 兼容没有加引号的字符串, 如 CODECS=avc1.4d401f
+    pub fn string(&self, name: &str) -> Result<Option<&str>, String> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(AttributeValue::Quoted(data)) | Some(AttributeValue::Enumerated(data)) => Ok(Some(data)),
+            Some(value) => Err(invalid(name, value, "字符串")),
+        }
+    }
+
+    pub fn integer(&self, name: &str) -> Result<Option<u64>, String> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(AttributeValue::Integer(data)) => Ok(Some(*data)),
+            Some(value) => Err(invalid(name, value, "整数")),
+        }
+    }
+
+    // 整数也可以作为小数, 如 FRAME-RATE=30
+    pub fn float(&self, name: &str) -> Result<Option<f64>, String> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(AttributeValue::Float(data)) => Ok(Some(*data)),
+            Some(AttributeValue::Integer(data)) => Ok(Some(*data as f64)),
+            Some(value) => Err(invalid(name, value, "小数")),
+        }
+    }
+
+    pub fn hex(&self, name: &str) -> Result<Option<&str>, String> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(AttributeValue::Hex(data)) => Ok(Some(data)),
+            Some(value) => Err(invalid(name, value, "16进制数")),
+        }
+    }
+
+    pub fn resolution(&self, name: &str) -> Result<Option<(u32, u32)>, String> {
+        match self.get(name) {
+            None => Ok(None),
+            Some(AttributeValue::Resolution(width, height)) => Ok(Some((*width, *height))),
+            Some(value) => Err(invalid(name, value, "分辨率")),
+        }
+    }
+}
+
+fn invalid(name: &str, value: &AttributeValue, expected: &str) -> String {
+    format!("{} 应该是{}, 实际为 {:?}", name, expected, value)
+}
+
+// #EXT-X-MAP, fMP4 的初始化分片, 对之后的分片生效, 直到下一个 #EXT-X-MAP
+#[derive(Debug, Clone, PartialEq)]
+pub struct HlsMap {
+    pub uri: String,
+    pub byte_range: Option<HlsByteRange>,
+    pub key: Option<HlsKey>, // #EXT-X-MAP 前面生效的 AES-128 key, 这时初始化分片也是加密的
+}
+
+// 媒体播放列表中的一个分片
+#[derive(Debug, Clone, Default)]
+pub struct HlsSegment {
+    pub url: String,
+    pub sequence: u64,       // 媒体序号, #EXT-X-MEDIA-SEQUENCE + 分片位置
+    pub key: Option<HlsKey>, // 没有加密时为空
+    pub byte_range: Option<HlsByteRange>,
+    pub duration: f64, // #EXTINF 的时长, 单位：秒, 不知道时为 0
+    pub title: String, // #EXTINF 逗号后面的标题
+    pub discontinuity: bool, // 前面有 #EXT-X-DISCONTINUITY, 编码参数或者时间戳可能和上一个分片不连续
+    // 分片开始的时间, 没有 #EXT-X-PROGRAM-DATE-TIME 的分片按上一个分片的时间加上时长推算
+    pub program_date_time: Option<DateTime<FixedOffset>>,
+    pub map: Option<HlsMap>, // 生效的 #EXT-X-MAP
+    pub protected: bool,     // 只有 DRM 的 key(KEYFORMAT 不是 identity), 无法解密
+}
+
+// 按 #EXT-X-DISCONTINUITY 和 #EXT-X-MAP 的变化把分片分成连续的几段, 每段的时间戳和编码参数是连续的
+pub fn segment_runs(list: &[HlsSegment]) -> Vec<Range<usize>> {
+    let mut runs = vec![];
+    let mut start = 0;
+    for index in 1..list.len() {
+        if list[index].discontinuity || list[index].map != list[index - 1].map {
+            runs.push(start..index);
+            start = index;
+        }
+    }
+    if start < list.len() {
+        runs.push(start..list.len());
+    }
+    runs
+}
+
+// --skip-discontinuities 时保留的片段。插入的广告一般和正片来自不同的目录或者域名,
+// 按第一个分片的目录分组, 只保留总时长最长的一组; 所有片段来自同一目录时(节目拼接、编码器重启等)全部保留
+pub fn main_runs(list: &[HlsSegment], runs: &[Range<usize>]) -> Vec<Range<usize>> {
+    // 不知道时长的分片(如 DASH)按 1 秒计算
+    let weight = |run: &Range<usize>| -> f64 { list[run.clone()].iter().map(|s| if s.duration > 0.0 { s.duration } else { 1.0 }).sum() };
+    let source = |run: &Range<usize>| {
+        let url = list[run.start].url.split(['?', '#']).next().unwrap_or_default();
+        url.rsplit_once('/').map(|(dir, _)| dir).unwrap_or_default().to_string()
+    };
+    let mut groups: Vec<(String, f64)> = vec![];
+    for run in runs {
+        let key = source(run);
+        match groups.iter_mut().find(|(name, _)| *name == key) {
+            Some(group) => group.1 += weight(run),
+            None => groups.push((key, weight(run))),
+        }
+    }
+    if groups.len() <= 1 {
+        return runs.to_vec();
+    }
+    let main = groups.iter().fold(&groups[0], |best, item| if item.1 > best.1 { item } else { best });
+    runs.iter().filter(|run| source(run) == main.0).cloned().collect()
+}
+
+// 用到的所有 #EXT-X-MAP, 去重, 按第一次出现的顺序
+pub fn distinct_maps(list: &[HlsSegment]) -> Vec<&HlsMap> {
+    let mut maps: Vec<&HlsMap> = vec![];
+    for map in list.iter().filter_map(|s| s.map.as_ref()) {
+        if !maps.contains(&map) {
+            maps.push(map);
+        }
+    }
+    maps
+}
+
+// #EXT-X-PROGRAM-DATE-TIME 的时间, ISO 8601 格式, 兼容 +0800 这样不带冒号的时区
+pub fn parse_date_time(str: &str) -> Result<DateTime<FixedOffset>, String> {
+    let str = str.trim();
+    DateTime::parse_from_rfc3339(str)
+        .or_else(|_| DateTime::parse_from_str(str, "%Y-%m-%dT%H:%M:%S%.f%z"))
+        .map_err(|_| format!("无效的时间: {}", str))
+}
+
+// --from/--to 的时间: 在播放列表中的位置(01:10:00、70:00、4200.5), 或者 #EXT-X-PROGRAM-DATE-TIME 的时间
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimePoint {
+    Offset(f64), // 从第一个分片开始的秒数
+    DateTime(DateTime<FixedOffset>),
+}
+
+impl FromStr for TimePoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        // 最多三段: 时:分:秒, 秒可以带小数
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() <= 3 && parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit() || c == '.')) {
+            let mut seconds = 0.0;
+            for (index, part) in parts.iter().enumerate() {
+                let value: f64 = part.parse().map_err(|_| format!("无效的时间: {}", s))?;
+                if (index + 1 < parts.len() && part.contains('.')) || (index > 0 && value >= 60.0) {
+                    return Err(format!("无效的时间: {}", s));
+                }
+                seconds = seconds * 60.0 + value;
+            }
+            return Ok(TimePoint::Offset(seconds));
+        }
+        parse_date_time(s)
+            .map(TimePoint::DateTime)
+            .map_err(|_| format!("无效的时间: {}, 格式为 01:10:00、4200.5 或者 2024-05-01T12:00:00Z", s))
+    }
+}
+
+impl fmt::Display for TimePoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TimePoint::Offset(seconds) => write!(f, "{}", format_time(*seconds)),
+            TimePoint::DateTime(time) => write!(f, "{}", time.to_rfc3339()),
+        }
+    }
+}
+
+// 时间在播放列表中的位置, 单位：秒。按 #EXT-X-PROGRAM-DATE-TIME 指定时需要分片带有这个标签,
+// 落在两个分片之间的空隙时取后一个分片的开始位置, 晚于最后一个分片时取播放列表的结尾
+pub fn time_offset(list: &[HlsSegment], point: &TimePoint) -> Result<f64, String> {
+    let time = match point {
+        TimePoint::Offset(seconds) => return Ok(*seconds),
+        TimePoint::DateTime(time) => time,
+    };
+    let mut offset = 0.0;
+    let mut first = None;
+    for segment in list {
+        if let Some(start) = segment.program_date_time {
+            first.get_or_insert(start);
+            let elapsed = (*time - start).num_milliseconds() as f64 / 1000.0;
+            if elapsed < segment.duration {
+                return match first {
+                    Some(first) if first == start && elapsed < 0.0 => Err(format!("{} 早于播放列表的开始时间 {}", time.to_rfc3339(), first.to_rfc3339())),
+                    _ => Ok(offset + elapsed.max(0.0)),
+                };
+            }
+        }
+        offset += segment.duration;
+    }
+    match first {
+        Some(_) => Ok(offset),
+        None => Err("播放列表没有 #EXT-X-PROGRAM-DATE-TIME, 只能按位置指定时间".to_string()),
+    }
+}
+
+// 覆盖 [from, to) 的分片, 返回分片的范围和第一个分片开始的位置
+pub fn time_range_segments(list: &[HlsSegment], from: f64, to: f64) -> (Range<usize>, f64) {
+    let mut offset = 0.0;
+    let mut start = None;
+    let mut end = 0;
+    for (index, segment) in list.iter().enumerate() {
+        if offset >= to {
+            break;
+        }
+        if start.is_none() && offset + segment.duration > from {
+            start = Some((index, offset));
+        }
+        offset += segment.duration;
+        end = index + 1;
+    }
+    match start {
+        Some((index, offset)) => (index..end, offset),
+        None => (list.len()..list.len(), offset),
+    }
+}
+
+impl Default for HlsM3u8 {
+    fn default() -> Self {
+        HlsM3u8::new()
+    }
+}
+
 impl HlsM3u8 {
     pub fn new() -> HlsM3u8 {
         HlsM3u8 {
-            key: "".to_string(),
             list: vec![],
-            method: None,
-            iv: "".to_string(),
             original_url: "".to_string(),
-            folder: "".to_string(),
             sequence: 0,
+            target_duration: 0,
+            discontinuity_sequence: 0,
+            version: 1,
+            playlist_type: None,
+            endlist: false,
             x_map_uri: "".to_string(),
+            x_map_byte_range: None,
             extension: "ts".to_string(),
+            variants: vec![],
+            renditions: vec![],
         }
     }
 
-    pub fn set_list(&mut self, list: Vec<String>) {
-        self.list = list
+    // 是否为主播放列表(包含 #EXT-X-STREAM-INF)
+    pub fn is_master(&self) -> bool {
+        !self.variants.is_empty()
     }
 
-    pub fn set_original_url(&mut self, original_url: String, folder: String) {
-        self.original_url = original_url;
-        self.folder = folder;
+    pub fn add_variant(&mut self, variant: HlsVariant) {
+        self.variants.push(variant)
     }
 
-    pub fn set_key(&mut self, key: String) {
-        self.key = key
+    pub fn add_rendition(&mut self, rendition: HlsRendition) {
+        self.renditions.push(rendition)
+    }
+
+    fn group_renditions(&self, media_type: RenditionType, group_id: &str) -> Vec<&HlsRendition> {
+        self.renditions
+            .iter()
+            .filter(|r| r.media_type == media_type && r.group_id == group_id && !r.uri.is_empty())
+            .collect()
+    }
+
+    // 选择变体对应的独立音频, 优先匹配语言, 其次 DEFAULT=YES、AUTOSELECT=YES, 最后取第一个
+    pub fn select_audio(&self, variant: &HlsVariant, language: &str) -> Option<&HlsRendition> {
+        if variant.audio.is_empty() {
+            return None;
+        }
+        let list = self.group_renditions(RenditionType::Audio, &variant.audio);
+        if let Some(data) = list.iter().find(|r| r.match_language(language)) {
+            return Some(data);
+        }
+        if let Some(data) = list.iter().find(|r| r.default) {
+            return Some(data);
+        }
+        if let Some(data) = list.iter().find(|r| r.autoselect) {
+            return Some(data);
+        }
+        list.first().copied()
+    }
+
+    // 选择变体对应的字幕, languages 为 all 时选择全部
+    pub fn select_subtitles(&self, variant: &HlsVariant, languages: &[String]) -> Vec<&HlsRendition> {
+        if variant.subtitles.is_empty() || languages.is_empty() {
+            return vec![];
+        }
+        let all = languages.iter().any(|l| l.eq_ignore_ascii_case("all"));
+        self.group_renditions(RenditionType::Subtitles, &variant.subtitles)
+            .into_iter()
+            .filter(|r| all || languages.iter().any(|l| r.match_language(l)))
+            .collect()
+    }
+
+    pub fn select_variant(&self, select: &VariantSelect) -> Option<&HlsVariant> {
+        match select {
+            VariantSelect::Highest => self.variants.iter().max_by_key(|v| v.bandwidth),
+            VariantSelect::Lowest => self.variants.iter().min_by_key(|v| v.bandwidth),
+            VariantSelect::Index(index) => self.variants.get(*index),
+            VariantSelect::Height(height) => {
+                // 优先高度最接近的, 高度相同则取码率高的
+                self.variants.iter().min_by(|a, b| {
+                    let diff_a = (a.height() as i64 - *height as i64).abs();
+                    let diff_b = (b.height() as i64 - *height as i64).abs();
+                    diff_a.cmp(&diff_b).then(b.bandwidth.cmp(&a.bandwidth))
+                })
+            }
+        }
+    }
+
+    pub fn set_list(&mut self, list: Vec<HlsSegment>) {
+        self.list = list
+    }
+
+    pub fn set_original_url(&mut self, original_url: String) {
+        self.original_url = original_url;
     }
 
     pub fn set_x_map_uri(&mut self, uri: String) {
         self.x_map_uri = uri
     }
 
+    pub fn set_x_map_byte_range(&mut self, byte_range: Option<HlsByteRange>) {
+        self.x_map_byte_range = byte_range
+    }
+
     pub fn set_extension(&mut self, extension: String) {
         self.extension = extension
     }
 
-    pub fn set_sequence(&mut self, sequence: i32) {
+    pub fn set_sequence(&mut self, sequence: u64) {
         self.sequence = sequence
     }
 
-    pub async fn set_method_and_key(
-        &mut self,
-        method: Option<HlsM3u8Method>,
-        key: String,
-        iv: String,
-    ) {
-        self.method = method;
-        self.key = key.clone();
-        self.iv = iv.clone();
-        println!("before key is: {}", self.key);
-        if !is_url(key.clone()) && !self.original_url.is_empty() {
-            if key.starts_with("/") {
-                self.set_key(format!(
-                    "{}/{}",
-                    get_url_host(&self.original_url).expect("获取host失败"),
-                    key
-                ))
-            } else {
-                self.set_key(replace_last_segment(&self.original_url, &self.key))
-            }
-            println!("transfer key,now is: {}", self.key);
-        }
-        self.convert_local_key().await;
-    }
-
-    async fn convert_local_key(&mut self) {
-        let res = download_file(self.key.clone(), format!("./{}.bin", self.folder.clone())).await;
-        return match res {
-            Ok(data) => {
-                if data {
-                    println!("key 下载成功")
-                } else{
-                   println!("key 下载出错")
-               }
-            }
-            _ => {
-                println!("key 下载出错")
+    // 所有分片 #EXTINF 时长的和, 单位：秒
+    pub fn total_duration(&self) -> f64 {
+        self.list.iter().map(|s| s.duration).sum()
+    }
+
+    // 是否有分片需要解密
+    pub fn is_encrypted(&self) -> bool {
+        self.list.iter().any(|s| s.key.is_some())
+    }
+
+    // 有分片只能用 DRM 解密, 下载后无法播放
+    pub fn is_protected(&self) -> bool {
+        self.list.iter().any(|s| s.protected)
+    }
+
+    // 所有用到的 key 地址, 去重
+    pub fn key_uris(&self) -> Vec<String> {
+        let mut list: Vec<String> = vec![];
+        for item in &self.list {
+            let map_key = item.map.as_ref().and_then(|map| map.key.as_ref());
+            for key in item.key.iter().chain(map_key) {
+                if !list.contains(&key.uri) {
+                    list.push(key.uri.clone());
+                }
+            }
+        }
+        list
+    }
+
+    // 同一个域名下没有查询参数的变体、音轨、分片、key 和初始化分片的地址带上播放列表地址的查询参数, 见 common::inherit_query
+    pub fn inherit_query(&mut self) {
+        let base = self.original_url.clone();
+        let apply = |url: &mut String| {
+            if !url.is_empty() {
+                *url = inherit_query(&base, url);
+            }
+        };
+        self.variants.iter_mut().for_each(|item| apply(&mut item.uri));
+        self.renditions.iter_mut().for_each(|item| apply(&mut item.uri));
+        apply(&mut self.x_map_uri);
+        for segment in self.list.iter_mut() {
+            apply(&mut segment.url);
+            if let Some(key) = segment.key.as_mut() {
+                apply(&mut key.uri);
+            }
+            if let Some(map) = segment.map.as_mut() {
+                apply(&mut map.uri);
+                if let Some(key) = map.key.as_mut() {
+                    apply(&mut key.uri);
+                }
+            }
+        }
+    }
+
+    // key 的地址按播放列表的地址解析
+    pub fn to_key(&self, method: Option<HlsM3u8Method>, uri: String, iv: String) -> Option<HlsKey> {
+        let key = resolve_url(&self.original_url, &uri);
+        method.map(|method| HlsKey { method, uri: key, iv })
+    }
+}
+
+// 播放列表的摘要, download --info 输出
+impl fmt::Display for HlsM3u8 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_master() {
+            writeln!(f, "主播放列表, {}个码率变体:", self.variants.len())?;
+            for (index, item) in self.variants.iter().enumerate() {
+                write!(f, "  [{}] {} bps", index, item.bandwidth)?;
+                if let Some((width, height)) = item.resolution {
+                    write!(f, ", {}x{}", width, height)?;
+                }
+                if item.frame_rate > 0.0 {
+                    write!(f, ", {}fps", item.frame_rate)?;
+                }
+                if !item.codecs.is_empty() {
+                    write!(f, ", {}", item.codecs)?;
+                }
+                writeln!(f, ", {}", item.uri)?;
+            }
+            if !self.renditions.is_empty() {
+                writeln!(f, "独立轨道:")?;
+            }
+            for item in &self.renditions {
+                let default = if item.default { ", 默认" } else { "" };
+                writeln!(f, "  {:?} {} {}({}){}", item.media_type, item.group_id, item.name, item.language, default)?;
             }
+            return Ok(());
+        }
+        let playlist_type = match (self.playlist_type, self.endlist) {
+            (Some(PlaylistType::Vod), _) => "VOD",
+            (Some(PlaylistType::Event), _) => "EVENT",
+            (None, true) => "未指定",
+            (None, false) => "直播",
         };
+        writeln!(f, "媒体播放列表, 版本 {}, 类型 {}", self.version, playlist_type)?;
+        writeln!(
+            f,
+            "目标时长 {}秒, 媒体序号 {}, 不连续序号 {}",
+            self.target_duration, self.sequence, self.discontinuity_sequence
+        )?;
+        writeln!(f, "{}个分片, 总时长 {}", self.list.len(), format_time(self.total_duration()))?;
+        let discontinuities = self.list.iter().filter(|s| s.discontinuity).count();
+        if discontinuities > 0 {
+            writeln!(f, "{}处 #EXT-X-DISCONTINUITY", discontinuities)?;
+        }
+        let mut methods = vec![];
+        for item in self.list.iter().filter_map(|s| s.key.as_ref()) {
+            if !methods.contains(&item.method.to_string()) {
+                methods.push(item.method.to_string());
+            }
+        }
+        if !methods.is_empty() {
+            writeln!(f, "加密 {}, {}个 key", methods.join("/"), self.key_uris().len())?;
+        }
+        for map in distinct_maps(&self.list) {
+            writeln!(f, "初始化分片 {}", map.uri)?;
+        }
+        let times: Vec<_> = self.list.iter().filter_map(|s| Some((s.program_date_time?, s.duration))).collect();
+        if let (Some((start, _)), Some((last, duration))) = (times.first(), times.last()) {
+            let end = *last + chrono::TimeDelta::milliseconds((duration * 1000.0).round() as i64);
+            writeln!(f, "节目时间 {} ~ {}", start.to_rfc3339(), end.to_rfc3339())?;
+        }
+        write!(f, "{}", if self.endlist { "已结束" } else { "没有 #EXT-X-ENDLIST, 可能还会更新" })
     }
 }
 
 pub mod m3u8 {
-    use crate::common::{download_file, is_url, replace_last_segment};
-    use crate::m3u8::HlsM3u8Method::{Aes128, SampleAes};
-    use crate::m3u8::{HlsM3u8, HlsM3u8Method};
-    use regex::Regex;
+    use crate::common::{download_file, is_url, resolve_url};
+    use crate::http::HttpClient;
+    use crate::logging::redact_url;
+    use crate::error::Error;
+    use crate::m3u8::{
+        parse_date_time, AttributeList, HlsByteRange, HlsKey, HlsM3u8, HlsM3u8Method, HlsMap, HlsRendition, HlsSegment, HlsVariant, RenditionType,
+    };
+    use chrono::TimeDelta;
+    use std::collections::HashMap;
     use std::fs::File;
     use std::io::Read;
+    use tracing::{debug, trace};
 
-    pub async fn parse_local(local_file: String, target_url: String, folder: String) -> HlsM3u8 {
-        let mut data = File::open(local_file).expect("file not exists");
+    pub async fn parse_local(local_file: String, target_url: String) -> Result<HlsM3u8, Error> {
+        let mut data = File::open(local_file)?;
         let mut str = String::default();
-        let _ = data.read_to_string(&mut str);
-        str_to_urls(str, target_url.clone(), folder.clone()).await
+        data.read_to_string(&mut str)?;
+        str_to_urls(str, target_url.clone()).await
     }
 
-    async fn str_to_urls(str: String, url: String, folder: String) -> HlsM3u8 {
+    pub async fn str_to_urls(str: String, url: String) -> Result<HlsM3u8, Error> {
+        // 播放列表第一行必须是 #EXTM3U, 否则多半是下载到了错误页面
+        let str = str.trim_start_matches('\u{feff}');
+        if !str.trim_start().starts_with("#EXTM3U") {
+            return Err(Error::Parse(format!("{} 不是m3u8播放列表, 缺少 #EXTM3U", url)));
+        }
         let mut hls_m3u8 = HlsM3u8::new();
-        hls_m3u8.set_original_url(url.clone(), folder.clone());
+        hls_m3u8.set_original_url(url.clone());
         let mut list = vec![];
-        let arr = str.split("\n").into_iter();
-        for i in arr {
-            if !i.is_empty() {
-                if !i.starts_with("#EXT") {
-                    if is_url(i.to_string()) {
-                        list.push(i.to_string());
-                    } else {
-                        if !url.is_empty() {
-                            let new_url = replace_last_segment(&url, i);
-                            list.push(new_url);
-                        }
+        // 当前生效的 key, 每个分片记录自己的 key, 支持 key 轮换
+        let mut current_key: Option<HlsKey> = None;
+        // 分片前面连续的 #EXT-X-KEY 是同一个 key 的不同 KEYFORMAT, 其中有 identity 时使用它, 只有 DRM 的 key 时分片无法解密
+        let (mut key_group, mut key_identity, mut key_protected) = (false, false, false);
+        // 下一个分片的 #EXT-X-BYTERANGE, 以及每个资源上一段的结束位置(没有 offset 时从这里开始)
+        let mut pending_range: Option<(u64, Option<u64>)> = None;
+        let mut range_end: HashMap<String, u64> = HashMap::new();
+        // 下一个分片的 #EXTINF 时长和标题, 以及前面是否有 #EXT-X-DISCONTINUITY
+        let mut pending_duration: f64 = 0.0;
+        let mut pending_title = String::default();
+        let mut pending_discontinuity = false;
+        // 下一个分片的开始时间, 当前生效的 #EXT-X-MAP
+        let mut next_date_time = None;
+        let mut current_map: Option<HlsMap> = None;
+        // 上一行是 #EXT-X-STREAM-INF 时, 当前行为变体的播放列表地址
+        let mut pending_variant: Option<HlsVariant> = None;
+        for (index, line) in str.split('\n').enumerate() {
+            let i = line.trim();
+            if i.is_empty() {
+                continue;
+            }
+            // 解析错误带上行号和标签
+            let tag = i.split(':').next().unwrap_or_default();
+            let error = |e: String| Error::Parse(format!("播放列表第{}行 {}: {}", index + 1, tag, e));
+            // 不是 #EXT 开头的 # 行是注释, RFC 8216 4.1 要求忽略
+            if i.starts_with('#') && !i.starts_with("#EXT") {
+                continue;
+            }
+            if !i.starts_with("#EXT") {
+                // 本地播放列表中的相对地址无法下载
+                if url.is_empty() && !is_url(i.to_string()) {
+                    continue;
+                }
+                let new_url = resolve_url(&url, i);
+                match pending_variant.take() {
+                    Some(mut variant) => {
+                        variant.uri = new_url;
+                        hls_m3u8.add_variant(variant);
                     }
-                } else {
-                    if i.starts_with("#EXT-X-KEY") {
-                        let method = get_method_from_regex(i);
-                        let uri = get_uri_from_regex(i);
-                        let iv = get_iv_from_regex(i);
-                        hls_m3u8.set_method_and_key(method, uri, iv).await;
-                    } else if i.starts_with("#EXT-X-MEDIA-SEQUENCE:") {
-                        let str = i.replace("#EXT-X-MEDIA-SEQUENCE:", "");
-                        let mut seq = 0;
-                        match str.parse::<i32>() {
-                            Ok(data) => seq = data,
-                            _ => {}
+                    None => {
+                        let sequence = hls_m3u8.sequence + list.len() as u64;
+                        let mut byte_range = None;
+                        if let Some((length, offset)) = pending_range.take() {
+                            let offset = offset.unwrap_or(*range_end.get(&new_url).unwrap_or(&0));
+                            range_end.insert(new_url.clone(), offset + length);
+                            byte_range = Some(HlsByteRange { length, offset });
                         }
-                        hls_m3u8.set_sequence(seq);
-                    } else if i.starts_with("#EXT-X-MAP:URI=") {
-                        let mut uri = i.replace("#EXT-X-MAP:URI=", "");
-                        uri = uri.replace("\"", "");
-                        hls_m3u8.set_x_map_uri(uri.clone());
+                        let duration = std::mem::take(&mut pending_duration);
+                        let program_date_time = next_date_time.take();
+                        // 下一个分片没有 #EXT-X-PROGRAM-DATE-TIME 时接着这个分片
+                        next_date_time = program_date_time.map(|time| time + TimeDelta::milliseconds((duration * 1000.0).round() as i64));
+                        list.push(HlsSegment {
+                            url: new_url,
+                            sequence,
+                            key: current_key.clone(),
+                            byte_range,
+                            duration,
+                            title: std::mem::take(&mut pending_title),
+                            discontinuity: std::mem::take(&mut pending_discontinuity),
+                            program_date_time,
+                            map: current_map.clone(),
+                            protected: key_protected,
+                        });
+                        key_group = false;
                     }
                 }
+            } else if let Some(value) = i.strip_prefix("#EXT-X-STREAM-INF:") {
+                let attributes = AttributeList::parse(value).map_err(error)?;
+                pending_variant = Some(parse_variant(&attributes).map_err(error)?);
+            } else if let Some(value) = i.strip_prefix("#EXT-X-MEDIA:") {
+                let attributes = AttributeList::parse(value).map_err(error)?;
+                let mut rendition = parse_rendition(&attributes).map_err(error)?;
+                if !rendition.uri.is_empty() {
+                    rendition.uri = resolve_url(&url, &rendition.uri);
+                }
+                hls_m3u8.add_rendition(rendition);
+            } else if let Some(value) = i.strip_prefix("#EXT-X-KEY:") {
+                let attributes = AttributeList::parse(value).map_err(error)?;
+                if !key_group {
+                    (key_group, key_identity, key_protected) = (true, false, false);
+                }
+                // KEYFORMAT 不是 identity 的是 DRM 的 key, 无法处理, 之前的 key 不再生效
+                let key_format = attributes.string("KEYFORMAT").map_err(error)?.unwrap_or("identity");
+                if key_format != "identity" {
+                    if !key_identity && attributes.string("METHOD").map_err(error)? != Some("NONE") {
+                        current_key = None;
+                        key_protected = true;
+                    }
+                    continue;
+                }
+                let method = parse_method(&attributes).map_err(error)?;
+                let uri = attributes.string("URI").map_err(error)?.unwrap_or_default();
+                if method.is_some() && uri.is_empty() {
+                    return Err(error("缺少 URI".to_string()));
+                }
+                let iv = attributes.hex("IV").map_err(error)?.unwrap_or_default();
+                if iv.len() > 34 {
+                    return Err(error(format!("IV {} 超过128位", iv)));
+                }
+                trace!(method = ?method, "EXT-X-KEY");
+                current_key = hls_m3u8.to_key(method, uri.to_string(), iv.to_string());
+                (key_identity, key_protected) = (true, false);
+            } else if let Some(value) = i.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+                hls_m3u8.set_sequence(parse_integer(value).map_err(error)?);
+            } else if let Some(value) = i.strip_prefix("#EXT-X-TARGETDURATION:") {
+                hls_m3u8.target_duration = parse_integer(value).map_err(error)?;
+            } else if let Some(value) = i.strip_prefix("#EXT-X-DISCONTINUITY-SEQUENCE:") {
+                hls_m3u8.discontinuity_sequence = parse_integer(value).map_err(error)?;
+            } else if i == "#EXT-X-DISCONTINUITY" {
+                pending_discontinuity = true;
+            } else if let Some(value) = i.strip_prefix("#EXT-X-VERSION:") {
+                hls_m3u8.version = parse_integer(value).map_err(error)?;
+            } else if let Some(value) = i.strip_prefix("#EXT-X-PLAYLIST-TYPE:") {
+                hls_m3u8.playlist_type = Some(value.parse().map_err(error)?);
+            } else if let Some(value) = i.strip_prefix("#EXT-X-PROGRAM-DATE-TIME:") {
+                next_date_time = Some(parse_date_time(value).map_err(error)?);
+            } else if i.starts_with("#EXT-X-ENDLIST") {
+                hls_m3u8.endlist = true;
+            } else if let Some(value) = i.strip_prefix("#EXTINF:") {
+                // #EXTINF:<时长>,[标题], IPTV 的列表在时长后面还有 tvg-id 等属性
+                let (value, title) = value.split_once(',').unwrap_or((value, ""));
+                pending_title = title.trim().to_string();
+                let value = value.split_whitespace().next().unwrap_or_default();
+                let duration = value.parse::<f64>().map_err(|_| error(format!("无效的时长: {}", value)))?;
+                // 直播源常用 -1 表示不知道时长
+                pending_duration = duration.max(0.0);
+            } else if let Some(value) = i.strip_prefix("#EXT-X-BYTERANGE:") {
+                pending_range = Some(HlsByteRange::parse(value).map_err(error)?);
+            } else if let Some(value) = i.strip_prefix("#EXT-X-MAP:") {
+                let attributes = AttributeList::parse(value).map_err(error)?;
+                let uri = attributes.string("URI").map_err(error)?.ok_or_else(|| error("缺少 URI".to_string()))?;
+                let byte_range = match attributes.string("BYTERANGE").map_err(error)? {
+                    Some(value) => {
+                        let (length, offset) = HlsByteRange::parse(value).map_err(error)?;
+                        Some(HlsByteRange { length, offset: offset.unwrap_or(0) })
+                    }
+                    None => None,
+                };
+                let map = HlsMap {
+                    uri: resolve_url(&url, uri),
+                    byte_range,
+                    // SAMPLE-AES 只加密音视频帧, 初始化分片不加密
+                    key: current_key.clone().filter(|key| key.method == HlsM3u8Method::Aes128),
+                };
+                hls_m3u8.set_x_map_uri(map.uri.clone());
+                hls_m3u8.set_x_map_byte_range(map.byte_range);
+                current_map = Some(map);
             }
         }
         hls_m3u8.set_list(list);
-        hls_m3u8
+        Ok(hls_m3u8)
+    }
+
+    fn parse_integer(value: &str) -> Result<u64, String> {
+        value.trim().parse::<u64>().map_err(|_| format!("{} 不是整数", value.trim()))
+    }
+
+    // METHOD=NONE 表示之后的分片不加密
+    fn parse_method(attributes: &AttributeList) -> Result<Option<HlsM3u8Method>, String> {
+        match attributes.string("METHOD")? {
+            Some("NONE") => Ok(None),
+            Some("AES-128") => Ok(Some(HlsM3u8Method::Aes128)),
+            Some("SAMPLE-AES") => Ok(Some(HlsM3u8Method::SampleAes)),
+            Some(method) => Err(format!("不支持的加密方式: {}", method)),
+            None => Err("缺少 METHOD".to_string()),
+        }
+    }
+
+    fn parse_variant(attributes: &AttributeList) -> Result<HlsVariant, String> {
+        Ok(HlsVariant {
+            uri: "".to_string(),
+            bandwidth: attributes.integer("BANDWIDTH")?.unwrap_or(0),
+            resolution: attributes.resolution("RESOLUTION")?,
+            codecs: attributes.string("CODECS")?.unwrap_or_default().to_string(),
+            frame_rate: attributes.float("FRAME-RATE")?.unwrap_or(0.0) as f32,
+            audio: attributes.string("AUDIO")?.unwrap_or_default().to_string(),
+            subtitles: attributes.string("SUBTITLES")?.unwrap_or_default().to_string(),
+        })
+    }
+
+    fn parse_rendition(attributes: &AttributeList) -> Result<HlsRendition, String> {
+        let media_type = match attributes.string("TYPE")? {
+            Some("AUDIO") => RenditionType::Audio,
+            Some("VIDEO") => RenditionType::Video,
+            Some("SUBTITLES") => RenditionType::Subtitles,
+            Some("CLOSED-CAPTIONS") => RenditionType::ClosedCaptions,
+            Some(media_type) => return Err(format!("未知的 TYPE: {}", media_type)),
+            None => return Err("缺少 TYPE".to_string()),
+        };
+        let string = |name: &str| attributes.string(name).map(|value| value.unwrap_or_default().to_string());
+        Ok(HlsRendition {
+            media_type,
+            group_id: string("GROUP-ID")?,
+            language: string("LANGUAGE")?,
+            name: string("NAME")?,
+            default: string("DEFAULT")? == "YES",
+            autoselect: string("AUTOSELECT")? == "YES",
+            uri: string("URI")?,
+        })
+    }
+
+    pub async fn parse_url(client: &HttpClient, url: String, m3u8_file_name: String) -> Result<HlsM3u8, Error> {
+        debug!(url = %redact_url(&url), file = %m3u8_file_name, "下载播放列表");
+        // 分片等相对地址按重定向后的地址解析
+        let final_url = download_file(client, url.clone(), m3u8_file_name.clone()).await?;
+        parse_local(m3u8_file_name, final_url).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::m3u8::m3u8::str_to_urls;
+    use crate::m3u8::{distinct_maps, main_runs, parse_date_time, segment_runs, time_offset, time_range_segments, AttributeList, AttributeValue, HlsByteRange, HlsKey, HlsM3u8Method, PlaylistType, RenditionType, TimePoint, VariantSelect};
+    use std::fs;
+
+    const MASTER: &str = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=1280000,AVERAGE-BANDWIDTH=1000000,RESOLUTION=640x360,CODECS=\"avc1.4d401e,mp4a.40.2\"
+low/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=2560000,RESOLUTION=1280x720,FRAME-RATE=29.970,CODECS=\"avc1.4d401f,mp4a.40.2\"
+mid/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=7680000,RESOLUTION=1920x1080,CODECS=\"avc1.640028,mp4a.40.2\"
+https://cdn.example.com/high/index.m3u8
+";
+
+    #[tokio::test]
+    async fn test_parse_master_playlist() {
+        let hls = str_to_urls(MASTER.to_string(), "https://example.com/live/master.m3u8".to_string()).await.unwrap();
+        assert!(hls.is_master());
+        assert!(hls.list.is_empty());
+        assert_eq!(hls.variants.len(), 3);
+        let low = &hls.variants[0];
+        assert_eq!(low.uri, "https://example.com/live/low/index.m3u8");
+        assert_eq!(low.bandwidth, 1280000);
+        assert_eq!(low.resolution, Some((640, 360)));
+        assert_eq!(low.codecs, "avc1.4d401e,mp4a.40.2");
+        assert_eq!(hls.variants[1].frame_rate, 29.97);
+    }
+
+    #[tokio::test]
+    async fn test_select_variant() {
+        let hls = str_to_urls(MASTER.to_string(), "https://example.com/live/master.m3u8".to_string()).await.unwrap();
+        let uri = |select: &str| hls.select_variant(&select.parse::<VariantSelect>().unwrap()).map(|v| v.uri.clone());
+        assert_eq!(uri("highest").unwrap(), "https://cdn.example.com/high/index.m3u8");
+        assert_eq!(uri("lowest").unwrap(), "https://example.com/live/low/index.m3u8");
+        assert_eq!(uri("720p").unwrap(), "https://example.com/live/mid/index.m3u8");
+        assert_eq!(uri("1").unwrap(), "https://example.com/live/mid/index.m3u8");
+        assert!(uri("5").is_none());
+        assert!("best".parse::<VariantSelect>().is_err());
+    }
+
+    const MASTER_WITH_MEDIA: &str = "#EXTM3U
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",LANGUAGE=\"en\",NAME=\"English\",DEFAULT=YES,AUTOSELECT=YES,URI=\"audio/en.m3u8\"
+#EXT-X-MEDIA:TYPE=AUDIO,GROUP-ID=\"aac\",LANGUAGE=\"ja\",NAME=\"Japanese\",DEFAULT=NO,URI=\"audio/ja.m3u8\"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",LANGUAGE=\"en-US\",NAME=\"English\",URI=\"subs/en.m3u8\"
+#EXT-X-MEDIA:TYPE=SUBTITLES,GROUP-ID=\"subs\",LANGUAGE=\"fr\",NAME=\"French\",URI=\"subs/fr.m3u8\"
+#EXT-X-STREAM-INF:BANDWIDTH=2560000,RESOLUTION=1280x720,AUDIO=\"aac\",SUBTITLES=\"subs\"
+video/720.m3u8
+";
+
+    #[tokio::test]
+    async fn test_parse_renditions() {
+        let hls = str_to_urls(MASTER_WITH_MEDIA.to_string(), "https://example.com/master.m3u8".to_string()).await.unwrap();
+        assert_eq!(hls.renditions.len(), 4);
+        assert_eq!(hls.renditions[0].media_type, RenditionType::Audio);
+        assert_eq!(hls.renditions[0].uri, "https://example.com/audio/en.m3u8");
+        let variant = hls.variants[0].clone();
+        assert_eq!(variant.audio, "aac");
+        assert_eq!(variant.subtitles, "subs");
+
+        assert_eq!(hls.select_audio(&variant, "").unwrap().language, "en");
+        assert_eq!(hls.select_audio(&variant, "JA").unwrap().language, "ja");
+        assert_eq!(hls.select_audio(&variant, "de").unwrap().language, "en");
+
+        let subs = hls.select_subtitles(&variant, &["en".to_string()]);
+        assert_eq!(subs.len(), 1);
+        assert_eq!(subs[0].language, "en-US");
+        assert_eq!(hls.select_subtitles(&variant, &["all".to_string()]).len(), 2);
+        assert!(hls.select_subtitles(&variant, &[]).is_empty());
     }
 
-    pub fn get_method_from_regex(str: &str) -> Option<HlsM3u8Method> {
-        let regex = Regex::new(r"(?m)METHOD=(.*),").unwrap();
+    #[tokio::test]
+    async fn test_parse_key_rotation() {
+        let playlist = "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:300
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k1\"
+#EXTINF:6,
+0.ts
+#EXTINF:6,
+1.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k2\",IV=0x000102030405060708090a0b0c0d0e0f
+#EXTINF:6,
+2.ts
+#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"skd://drm\",KEYFORMAT=\"com.apple.streamingkeydelivery\"
+#EXTINF:6,
+3.ts
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:6,
+4.ts
+";
+        let hls = str_to_urls(playlist.to_string(), "https://example.com/v/index.m3u8".to_string()).await.unwrap();
+        assert_eq!(hls.list.len(), 5);
+        assert_eq!(hls.list[0].sequence, 300);
+        assert_eq!(hls.list[4].sequence, 304);
+        assert_eq!(hls.list[4].duration, 6.0);
+        assert_eq!(hls.list[0].url, "https://example.com/v/0.ts");
+        assert_eq!(hls.list[1].key.as_ref().unwrap().uri, "https://keys.example.com/k1");
+        let key = hls.list[2].key.as_ref().unwrap();
+        assert_eq!(key.uri, "https://keys.example.com/k2");
+        assert_eq!(key.method, HlsM3u8Method::Aes128);
+        // 只有非 identity 的 KEYFORMAT 时是 DRM 加密, 不能沿用之前的 key
+        assert!(hls.list[3].key.is_none());
+        assert!(hls.list[3].protected);
+        assert!(!hls.list[2].protected && !hls.list[4].protected);
+        assert!(hls.list[4].key.is_none());
+        assert!(hls.is_encrypted());
+        assert!(hls.is_protected());
+        assert_eq!(hls.key_uris().len(), 2);
+        assert!(!hls.endlist);
+    }
 
-        let result = regex.captures_iter(str);
+    #[test]
+    fn test_key_iv() {
+        let key = HlsKey {
+            method: HlsM3u8Method::Aes128,
+            uri: "".to_string(),
+            iv: "0x000102030405060708090A0B0C0D0E0F".to_string(),
+        };
+        assert_eq!(key.iv_for(1), [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        let key = HlsKey { iv: "0x1".to_string(), ..key };
+        assert_eq!(key.iv_for(9)[15], 1);
+        // 没有 IV 时使用媒体序号, 超过255也不会溢出
+        let key = HlsKey { iv: "".to_string(), ..key };
+        let mut expect = [0u8; 16];
+        expect[14] = 0x01;
+        expect[15] = 0x2c;
+        assert_eq!(key.iv_for(300), expect);
+    }
 
-        let mut method: Option<HlsM3u8Method> = None;
+    #[tokio::test]
+    async fn test_parse_byte_range() {
+        let playlist = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-TARGETDURATION:6
+#EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"720@0\"
+#EXTINF:6,
+#EXT-X-BYTERANGE:1000@720
+main.mp4
+#EXTINF:6,
+#EXT-X-BYTERANGE:2000
+main.mp4
+#EXTINF:6,
+#EXT-X-BYTERANGE:500
+other.mp4
+#EXTINF:6,
+#EXT-X-BYTERANGE:300
+main.mp4
+#EXTINF:6,
+plain.ts
+#EXT-X-ENDLIST
+";
+        let hls = str_to_urls(playlist.to_string(), "https://example.com/v/index.m3u8".to_string()).await.unwrap();
+        assert_eq!(hls.x_map_uri, "https://example.com/v/main.mp4");
+        assert_eq!(hls.x_map_byte_range, Some(HlsByteRange { length: 720, offset: 0 }));
+        let ranges: Vec<Option<HlsByteRange>> = hls.list.iter().map(|s| s.byte_range).collect();
+        assert_eq!(ranges[0], Some(HlsByteRange { length: 1000, offset: 720 }));
+        // 没有 offset 时紧接着同一资源的上一段
+        assert_eq!(ranges[1], Some(HlsByteRange { length: 2000, offset: 1720 }));
+        assert_eq!(ranges[2], Some(HlsByteRange { length: 500, offset: 0 }));
+        assert_eq!(ranges[3], Some(HlsByteRange { length: 300, offset: 3720 }));
+        assert_eq!(ranges[4], None);
+        assert_eq!(ranges[3].unwrap().end(), 4019);
+        assert_eq!(hls.target_duration, 6);
+        assert!(hls.endlist);
 
-        println!("parse data {}", str);
-        for mat in result {
-            let data = mat.get(1).expect("error").as_str().to_string();
-            println!("{}", data);
-            if data.contains("AES-128") {
-                method = Some(Aes128)
-            } else if data.contains("SAMPLE-AES") {
-                method = Some(SampleAes)
+        // 长度为 0 时没有可以请求的字节, 带行号报错
+        assert_eq!(HlsByteRange::parse("1000@720"), Ok((1000, Some(720))));
+        assert!(HlsByteRange::parse("0@100").unwrap_err().contains("不能为 0"));
+        assert!(HlsByteRange::parse("abc").is_err());
+        for tag in ["#EXT-X-BYTERANGE:0@100", "#EXT-X-MAP:URI=\"main.mp4\",BYTERANGE=\"0@0\""] {
+            let playlist = format!("#EXTM3U\n#EXTINF:6,\n{}\nmain.mp4\n", tag);
+            match str_to_urls(playlist, "https://example.com/v/index.m3u8".to_string()).await {
+                Err(e) => assert!(e.to_string().contains("第3行"), "{}", e),
+                Ok(_) => panic!("{} 应该解析失败", tag),
             }
         }
-        method
     }
 
-    pub fn get_iv_from_regex(str: &str) -> String {
-        let regex = Regex::new(r"(?m)IV=(.*)").unwrap();
-        let result = regex.captures_iter(str);
-        for mat in result {
-            return mat.get(1).expect("error").as_str().to_string();
-        }
-        "".to_string()
+    #[test]
+    fn test_attribute_list() {
+        let list = AttributeList::parse(
+            r#"METHOD=AES-128,URI="https://k.example.com/key?a=1,b=2",IV=0x0A0b,BANDWIDTH=800000,RESOLUTION=1280x720,FRAME-RATE=23.976,TIME-OFFSET=-2.5"#,
+        )
+        .unwrap();
+        assert_eq!(list.get("METHOD"), Some(&AttributeValue::Enumerated("AES-128".to_string())));
+        assert_eq!(list.string("URI").unwrap(), Some("https://k.example.com/key?a=1,b=2"));
+        assert_eq!(list.hex("IV").unwrap(), Some("0x0A0b"));
+        assert_eq!(list.integer("BANDWIDTH").unwrap(), Some(800000));
+        assert_eq!(list.resolution("RESOLUTION").unwrap(), Some((1280, 720)));
+        assert_eq!(list.float("FRAME-RATE").unwrap(), Some(23.976));
+        assert_eq!(list.float("TIME-OFFSET").unwrap(), Some(-2.5));
+        assert_eq!(list.float("BANDWIDTH").unwrap(), Some(800000.0));
+        assert_eq!(list.string("CODECS").unwrap(), None);
+        assert!(list.integer("URI").is_err());
+        assert!(list.hex("BANDWIDTH").is_err());
+
+        // 空格和末尾的逗号可以兼容
+        let list = AttributeList::parse(r#" TYPE=AUDIO, NAME="a=b" ,"#).unwrap();
+        assert_eq!(list.string("NAME").unwrap(), Some("a=b"));
+        assert_eq!(AttributeList::parse("").unwrap(), AttributeList::default());
+
+        assert!(AttributeList::parse(r#"URI="key.bin"#).unwrap_err().contains("引号"));
+        assert!(AttributeList::parse(r#"URI="a"b,METHOD=NONE"#).is_err());
+        assert!(AttributeList::parse("METHOD=NONE,METHOD=AES-128").unwrap_err().contains("重复"));
+        assert!(AttributeList::parse("METHOD").is_err());
+        assert!(AttributeList::parse("METHOD=").is_err());
+        assert!(AttributeList::parse("method=NONE").is_err());
     }
 
-    pub fn get_uri_from_regex(str: &str) -> String {
-        let regex = Regex::new(r#"(?m)URI="(.*)""#).unwrap();
-        let result = regex.captures_iter(str);
-        for mat in result {
-            return mat.get(1).expect("error").as_str().to_string();
+    #[tokio::test]
+    async fn test_parse_error_line() {
+        let url = "https://example.com/v/index.m3u8".to_string();
+        let cases = [
+            ("#EXTM3U\n#EXTINF:6,\n0.ts\n#EXT-X-KEY:METHOD=AES-128,URI=\"key\n", "第4行 #EXT-X-KEY"),
+            ("#EXTM3U\n\n#EXT-X-KEY:METHOD=AES-256,URI=\"key\"\n", "第3行 #EXT-X-KEY: 不支持的加密方式"),
+            ("#EXTM3U\n#EXT-X-KEY:METHOD=AES-128\n", "第2行 #EXT-X-KEY: 缺少 URI"),
+            ("#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"k\",IV=1234\n", "IV 应该是16进制数"),
+            ("#EXTM3U\n#EXT-X-MAP:BYTERANGE=\"100@0\"\n", "第2行 #EXT-X-MAP: 缺少 URI"),
+            ("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=high\nlow.m3u8\n", "第2行 #EXT-X-STREAM-INF"),
+            ("#EXTM3U\n#EXT-X-TARGETDURATION:six\n", "第2行 #EXT-X-TARGETDURATION"),
+            ("#EXTM3U\n#EXTINF:abc,\n0.ts\n", "第2行 #EXTINF: 无效的时长"),
+            ("#EXTM3U\n#EXT-X-BYTERANGE:100@x\n0.ts\n", "第2行 #EXT-X-BYTERANGE"),
+        ];
+        for (playlist, expect) in cases {
+            match str_to_urls(playlist.to_string(), url.clone()).await {
+                Err(crate::error::Error::Parse(message)) => assert!(message.contains(expect), "{}", message),
+                _ => panic!("{} 应该解析失败", playlist),
+            }
         }
-        "".to_string()
     }
 
-    pub async fn parse_url(url: String, folder_name: String, m3u8_file_name: String) -> HlsM3u8 {
-        let hls_m3u8 = HlsM3u8::new();
-        let local_file = format!("./{}", m3u8_file_name);
-        match download_file(url.clone(), local_file.clone(), ).await {
-            Ok(data) => {
-                if data {
-                    parse_local(
-                        local_file.clone().to_string(),
-                        url.clone(),
-                        folder_name.clone(),
-                    )
-                        .await
-                } else {
-                    hls_m3u8
-                }
+    // tests/fixtures/m3u8 中收集的各种来源的播放列表都要能正确解析
+    #[tokio::test]
+    async fn test_fixtures() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/m3u8");
+        let parse = |name: &str| {
+            let data = fs::read_to_string(format!("{}/{}", dir, name)).unwrap();
+            str_to_urls(data, format!("https://example.com/fixtures/{}", name))
+        };
+        for entry in fs::read_dir(dir).unwrap() {
+            let name = entry.unwrap().file_name().to_string_lossy().to_string();
+            if let Err(e) = parse(&name).await {
+                panic!("{}: {}", name, e);
             }
-            _ => hls_m3u8,
         }
+
+        let hls = parse("apple_advanced_master.m3u8").await.unwrap();
+        assert_eq!(hls.variants.len(), 4);
+        assert_eq!(hls.renditions.len(), 4);
+        assert_eq!(hls.variants[1].resolution, Some((1920, 1080)));
+        assert_eq!(hls.variants[3].frame_rate, 30.0);
+        assert_eq!(hls.renditions[3].media_type, RenditionType::ClosedCaptions);
+        assert!(hls.renditions[3].uri.is_empty());
+
+        // URI 不是第一个属性
+        let hls = parse("fmp4_byterange.m3u8").await.unwrap();
+        assert_eq!(hls.x_map_uri, "https://example.com/fixtures/video_1080p.mp4");
+        assert_eq!(hls.x_map_byte_range, Some(HlsByteRange { length: 1324, offset: 0 }));
+        assert_eq!(hls.list[1].byte_range, Some(HlsByteRange { length: 798772, offset: 813669 }));
+        assert_eq!(hls.list[3].duration, 1.501);
+        assert_eq!(hls.sequence, 1);
+
+        // 带逗号和等号的 key 地址, IV 在 URI 前面
+        let hls = parse("aes128_key_rotation.m3u8").await.unwrap();
+        let key = hls.list[0].key.as_ref().unwrap();
+        assert_eq!(key.uri, "https://drm.example.net/key?id=a1,b2&sig=x=y");
+        assert_eq!(key.iv, "0x00000000000000000000000000000001");
+        let key = hls.list[2].key.as_ref().unwrap();
+        assert_eq!(key.uri, "https://example.com/fixtures/key2.bin");
+        assert_eq!(key.iv_for(0)[0], 0x0f);
+        assert!(hls.list[3].key.is_none());
+        assert_eq!(hls.list[0].url, "https://example.com/fixtures/seg-0.ts?token=abc");
+
+        // 只使用 identity 的 key
+        let hls = parse("sample_aes_multi_drm.m3u8").await.unwrap();
+        let key = hls.list[0].key.as_ref().unwrap();
+        assert_eq!(key.method, HlsM3u8Method::SampleAes);
+        assert_eq!(key.uri, "https://keys.example.com/clear.key");
+        // 有 identity 的 key 时可以解密
+        assert!(!hls.is_protected());
+
+        // 注释行被忽略
+        let hls = parse("commented.m3u8").await.unwrap();
+        let urls: Vec<&str> = hls.list.iter().map(|s| s.url.as_str()).collect();
+        assert_eq!(urls, ["https://example.com/fixtures/segment0.ts", "https://example.com/fixtures/segment1.ts", "https://example.com/fixtures/segment2.ts"]);
+        assert_eq!(hls.list[1].duration, 10.0);
+        assert_eq!(hls.list[2].duration, 9.5);
+
+        let hls = parse("live_program_date_time.m3u8").await.unwrap();
+        assert_eq!(hls.list.len(), 4);
+        assert_eq!(hls.list[3].sequence, 2683);
+        assert!(!hls.endlist);
+        let flags: Vec<bool> = hls.list.iter().map(|s| s.discontinuity).collect();
+        assert_eq!(flags, [false, true, false, true]);
+        assert_eq!(hls.list[1].title, "Ad");
+        assert_eq!(hls.list[3].program_date_time.unwrap().to_rfc3339(), "2024-05-01T12:00:36+00:00");
+
+        // BOM、CRLF 换行, IPTV 的 #EXTINF:-1 带属性
+        let hls = parse("iptv_crlf_bom.m3u8").await.unwrap();
+        assert_eq!(hls.list.len(), 2);
+        assert_eq!(hls.list[0].url, "http://iptv.example.com/news/index.m3u8");
+        assert_eq!(hls.list[0].duration, 0.0);
+        assert_eq!(hls.list[1].duration, 10.5);
+        assert_eq!(hls.target_duration, 11);
+    }
+
+    #[tokio::test]
+    async fn test_segment_metadata() {
+        let playlist = "#EXTM3U
+#EXT-X-VERSION:7
+#EXT-X-PLAYLIST-TYPE:EVENT
+#EXT-X-DISCONTINUITY-SEQUENCE:3
+#EXT-X-MAP:URI=\"init-a.mp4\"
+#EXT-X-PROGRAM-DATE-TIME:2024-05-01T20:00:00.500+0800
+#EXTINF:4.5,Intro
+a0.m4s
+#EXTINF:4.5,
+a1.m4s
+#EXT-X-DISCONTINUITY
+#EXT-X-MAP:URI=\"init-b.mp4\"
+#EXTINF:2,
+b0.m4s
+";
+        let hls = str_to_urls(playlist.to_string(), "https://example.com/v/index.m3u8".to_string()).await.unwrap();
+        assert_eq!(hls.version, 7);
+        assert_eq!(hls.playlist_type, Some(PlaylistType::Event));
+        assert_eq!(hls.discontinuity_sequence, 3);
+        assert_eq!(hls.total_duration(), 11.0);
+        assert_eq!(hls.list[0].title, "Intro");
+        assert_eq!(hls.list[1].title, "");
+        assert!(!hls.list[1].discontinuity);
+        assert!(hls.list[2].discontinuity);
+        assert_eq!(hls.list[0].map.as_ref().unwrap().uri, "https://example.com/v/init-a.mp4");
+        assert_eq!(hls.list[2].map.as_ref().unwrap().uri, "https://example.com/v/init-b.mp4");
+        // 没有 #EXT-X-PROGRAM-DATE-TIME 的分片按时长推算
+        let times: Vec<String> = hls.list.iter().map(|s| s.program_date_time.unwrap().to_rfc3339()).collect();
+        assert_eq!(times, ["2024-05-01T20:00:00.500+08:00", "2024-05-01T20:00:05+08:00", "2024-05-01T20:00:09.500+08:00"]);
+        assert!(parse_date_time("2024-05-01T12:00:00Z").is_ok());
+        assert!(parse_date_time("yesterday").is_err());
+
+        let summary = hls.to_string();
+        assert!(summary.contains("类型 EVENT"));
+        assert!(summary.contains("3个分片, 总时长 00:00:11.000"));
+        assert!(summary.contains("1处 #EXT-X-DISCONTINUITY"));
+
+        let hls = str_to_urls("#EXTM3U\n#EXTINF:6,\n0.ts\n".to_string(), String::default()).await.unwrap();
+        assert_eq!(hls.version, 1);
+        assert_eq!(hls.playlist_type, None);
+        assert!(hls.to_string().contains("类型 直播"));
+    }
+
+    #[tokio::test]
+    async fn test_discontinuity_runs() {
+        // 片头广告、正片、中插广告、正片
+        let playlist = "#EXTM3U
+#EXTINF:5,
+https://ads.example.com/a/0.ts
+#EXTINF:5,
+https://ads.example.com/a/1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:10,
+main/0.ts
+#EXTINF:10,
+main/1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:15,
+https://ads.example.com/b/0.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:10,
+main/2.ts
+";
+        let hls = str_to_urls(playlist.to_string(), "https://example.com/v/index.m3u8".to_string()).await.unwrap();
+        let runs = segment_runs(&hls.list);
+        assert_eq!(runs, [0..2, 2..4, 4..5, 5..6]);
+        // 广告虽然来自同一个域名, 但是目录不同, 正片的总时长最长
+        assert_eq!(main_runs(&hls.list, &runs), [2..4, 5..6]);
+
+        // 都来自同一目录时无法区分广告, 全部保留
+        let mut list = hls.list.clone();
+        for segment in list.iter_mut() {
+            segment.url = format!("https://example.com/v/{}", segment.sequence);
+        }
+        assert_eq!(main_runs(&list, &runs), runs);
+
+        // #EXT-X-MAP 变化时也分段
+        let playlist = "#EXTM3U
+#EXT-X-MAP:URI=\"a.mp4\"
+#EXTINF:4,
+a0.m4s
+#EXT-X-MAP:URI=\"b.mp4\"
+#EXTINF:4,
+b0.m4s
+#EXTINF:4,
+b1.m4s
+";
+        let hls = str_to_urls(playlist.to_string(), "https://example.com/v/index.m3u8".to_string()).await.unwrap();
+        assert_eq!(segment_runs(&hls.list), [0..1, 1..3]);
+        assert_eq!(distinct_maps(&hls.list).len(), 2);
+        assert!(segment_runs(&[]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_discontinuity_runs_same_source() {
+        // 编码器重启后时间戳不连续, 分片都在同一目录, 每一段都是正片
+        let playlist = "#EXTM3U
+#EXT-X-TARGETDURATION:10
+#EXTINF:10,
+seg0.ts
+#EXTINF:10,
+seg1.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:4,
+seg2.ts
+#EXT-X-DISCONTINUITY
+#EXTINF:10,
+seg3.ts
+#EXTINF:10,
+seg4.ts
+";
+        let hls = str_to_urls(playlist.to_string(), "https://example.com/live/index.m3u8".to_string()).await.unwrap();
+        let runs = segment_runs(&hls.list);
+        assert_eq!(runs, [0..2, 2..3, 3..5]);
+        assert_eq!(main_runs(&hls.list, &runs), runs);
+    }
+
+    #[tokio::test]
+    async fn test_time_range() {
+        assert_eq!("01:10:00".parse(), Ok(TimePoint::Offset(4200.0)));
+        assert_eq!("70:00.5".parse(), Ok(TimePoint::Offset(4200.5)));
+        assert_eq!("4200".parse(), Ok(TimePoint::Offset(4200.0)));
+        assert!("01:75:00".parse::<TimePoint>().is_err());
+        assert!("1.5:00".parse::<TimePoint>().is_err());
+        assert!("abc".parse::<TimePoint>().is_err());
+        let point: TimePoint = "2024-05-01T12:00:10+08:00".parse().unwrap();
+        assert_eq!(point.to_string().parse(), Ok(point.clone()));
+        assert_eq!(TimePoint::Offset(4200.5).to_string(), "01:10:00.500");
+
+        // 第二个分片之后中断了 10 秒
+        let playlist = "#EXTM3U
+#EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:00+08:00
+#EXTINF:6,
+0.ts
+#EXTINF:6,
+1.ts
+#EXT-X-DISCONTINUITY
+#EXT-X-PROGRAM-DATE-TIME:2024-05-01T12:00:22+08:00
+#EXTINF:6,
+2.ts
+#EXTINF:6,
+3.ts
+";
+        let hls = str_to_urls(playlist.to_string(), "https://example.com/v/index.m3u8".to_string()).await.unwrap();
+        assert_eq!(time_range_segments(&hls.list, 7.0, 13.0), (1..3, 6.0));
+        assert_eq!(time_range_segments(&hls.list, 0.0, 6.0), (0..1, 0.0));
+        assert_eq!(time_range_segments(&hls.list, 20.0, 100.0), (3..4, 18.0));
+        assert_eq!(time_range_segments(&hls.list, 30.0, 40.0).0, 4..4);
+
+        assert_eq!(time_offset(&hls.list, &TimePoint::Offset(5.0)), Ok(5.0));
+        assert_eq!(time_offset(&hls.list, &point), Ok(6.0 + 4.0));
+        // 落在中断的空隙里时从后一个分片开始, 晚于结尾时取结尾
+        assert_eq!(time_offset(&hls.list, &"2024-05-01T12:00:15+08:00".parse().unwrap()), Ok(12.0));
+        assert_eq!(time_offset(&hls.list, &"2024-05-01T12:00:25.5+08:00".parse().unwrap()), Ok(15.5));
+        assert_eq!(time_offset(&hls.list, &"2024-05-01T13:00:00+08:00".parse().unwrap()), Ok(24.0));
+        assert!(time_offset(&hls.list, &"2024-05-01T11:59:00+08:00".parse().unwrap()).is_err());
+        assert!(time_offset(&hls.list[..0], &point).unwrap_err().contains("PROGRAM-DATE-TIME"));
+    }
+
+    #[tokio::test]
+    async fn test_missing_header() {
+        let res = str_to_urls("<html>404</html>".to_string(), "https://example.com/v/index.m3u8".to_string()).await;
+        assert!(matches!(res, Err(crate::error::Error::Parse(_))));
     }
 }
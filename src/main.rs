@@ -1,20 +1,8 @@
-extern crate core;
-
-mod cmd;
-mod combine;
-mod common;
-mod download;
-mod m3u8;
-mod repeat;
-use crate::cmd::cmd::{check_base_info_exists, clear_temp_files, cut, download};
-use crate::combine::parse::{combine_video, get_reg_file_name, get_reg_files, to_files};
-use crate::common::now;
-use crate::download::download::{create_folder, fast_download, get_file_name};
 use clap::{arg, Args as clapArgs, Parser, Subcommand};
-use std::{env};
-use std::path::{Path, PathBuf};
-use url::Url;
-use md5;
+use media_tool_rs::{logging, now, parse_rate, Combiner, Cutter, Downloader, Error, Progress, ProgressMode, Reporter, Server, TimePoint, VariantSelect, VerifyMode};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "ffmpeg-tool-rs")]
@@ -24,6 +12,22 @@ use md5;
 pub struct Args {
     #[command(subcommand)]
     command: Commands,
+
+    /// 不显示进度
+    #[arg(long = "quiet", global = true)]
+    quiet: bool,
+
+    /// 每秒输出一行 json 格式的进度, 方便脚本读取
+    #[arg(long = "json-progress", global = true)]
+    json_progress: bool,
+
+    /// 输出更详细的日志, -v 为 debug, -vv 为 trace
+    #[arg(short = 'v', long = "verbose", global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// 日志写到文件中, 不输出到终端
+    #[arg(long = "log-file", global = true)]
+    log_file: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -31,9 +35,11 @@ enum Commands {
     /// 合并视频
     Combine(CombineArgs),
     /// 下载视频
-    Download(DownloadArgs),
+    Download(Box<DownloadArgs>),
     /// 截取视频
     Cut(CutArgs),
+    /// 启动 REST 服务, 通过接口提交下载、合并、截取任务
+    Serve(ServeArgs),
 }
 
 #[derive(clapArgs)]
@@ -78,18 +84,19 @@ impl CutArgs {
         }
         target
     }
-    pub fn cut(&mut self) {
+    pub fn cut(&mut self, progress: Arc<Progress>) -> Result<bool, Error> {
         let status = self.check();
         if status == 1 {
-            return;
-        }
-        let target = self.get_target();
-        let res = cut(self.input.clone(), self.start, self.duration, target.clone()).expect("处理失败");
-        if res {
-            println!("截取视频成功")
-        } else {
-            println!("截取视频失败")
+            return Ok(false);
         }
+        Cutter::new(self.input.clone())
+            .start(self.start)
+            .duration(self.duration)
+            .output(self.get_target())
+            .progress(progress)
+            .run()?;
+        println!("截取视频成功");
+        Ok(true)
     }
 }
 
@@ -137,42 +144,20 @@ pub struct CombineArgs {
 }
 
 impl CombineArgs {
-    fn get_target_folder(&self) -> String {
-        let target;
-        if self.target_file_name.is_empty() {
-            target = format!("./{}", get_reg_file_name(self.reg_name.to_owned()));
-        } else {
-            target = format!("./{}", self.target_file_name);
-        }
-
-        target
-    }
-    pub fn combine(&self) {
-        let files = get_reg_files(
-            self.reg_name.clone(),
-            self.reg_name_start,
-            self.reg_name_end,
-        )
-            .expect("解析失败");
-        let file_name = to_files().expect("生成文件失败");
-        let target = self.get_target_folder();
-        let res = combine_video(
-            files,
-            file_name.clone(),
-            target,
-            self.same_param_index,
-            self.set_a_b,
-            self.set_v_b,
-            self.set_fps,
-            self.set_width,
-            self.set_height,
-        )
-            .expect("合并文件失败");
-        if res {
-            println!("合并文件成功")
-        } else {
-            println!("合并文件失败")
+    pub fn combine(&self, progress: Arc<Progress>) -> Result<bool, Error> {
+        let mut combiner = Combiner::new(self.reg_name.clone(), self.reg_name_start, self.reg_name_end)
+            .same_param_index(self.same_param_index)
+            .audio_bitrate(self.set_a_b)
+            .video_bitrate(self.set_v_b)
+            .fps(self.set_fps)
+            .size(self.set_width, self.set_height)
+            .progress(progress);
+        if !self.target_file_name.is_empty() {
+            combiner = combiner.output(format!("./{}", self.target_file_name));
         }
+        combiner.run()?;
+        println!("合并文件成功");
+        Ok(true)
     }
 }
 
@@ -194,6 +179,14 @@ pub struct DownloadArgs {
     #[arg(long = "folder", default_value_t = String::from(""))]
     folder: String,
 
+    /// 从下载目录中的 manifest.json 继续下载, 不需要 url, 只重新下载缺失或者损坏的分片
+    #[arg(long = "resume", default_value_t = String::from(""))]
+    resume: String,
+
+    /// 只输出播放列表的信息(分片数、总时长、加密方式等), 不下载
+    #[arg(long = "info")]
+    info: bool,
+
     /// 下载并发数
     #[arg(long = "concurrent", default_value_t = 10)]
     concurrent: i32,
@@ -201,81 +194,196 @@ pub struct DownloadArgs {
     /// 下载并发数
     #[arg(long = "download_dir", default_value_t = String::from("download"))]
     download_dir: String,
-}
 
+    /// 主播放列表时选择的码率变体: highest|lowest|<height>p|<index>
+    #[arg(long = "variant", default_value = "highest")]
+    variant: VariantSelect,
+
+    /// 独立音轨的语言, 如 en, 不指定时使用默认音轨
+    #[arg(long = "audio-lang", default_value_t = String::from(""))]
+    audio_lang: String,
+
+    /// 需要下载的字幕语言, 多个用逗号分隔, all 表示全部
+    #[arg(long = "subs", value_delimiter = ',')]
+    subs: Vec<String>,
+
+    /// 直播录制, 持续刷新播放列表直到 EXT-X-ENDLIST、达到最大时长或者 Ctrl-C
+    #[arg(long = "live")]
+    live: bool,
+
+    /// 直播录制的最大时长, 单位：秒, 0 表示不限制
+    #[arg(long = "max-duration", default_value_t = 0)]
+    max_duration: u64,
+
+    /// 分片下载失败后的重试次数
+    #[arg(long = "retries", default_value_t = 3)]
+    retries: u32,
+
+    /// 第一次重试前等待的毫秒数, 之后每次翻倍并随机抖动
+    #[arg(long = "retry-delay", default_value_t = 1000)]
+    retry_delay: u64,
+
+    /// 重试后仍有分片失败时, 跳过这些分片继续合并
+    #[arg(long = "allow-gaps")]
+    allow_gaps: bool,
+
+    /// 跳过 #EXT-X-DISCONTINUITY 分隔的片段(一般是插入的广告), 只下载和合并正片
+    #[arg(long = "skip-discontinuities")]
+    skip_discontinuities: bool,
+
+    /// 同一个域名下没有查询参数的分片、key 等地址带上播放列表地址的查询参数, 用于只给播放列表签名的 CDN
+    #[arg(long = "inherit-query")]
+    inherit_query: bool,
+
+    /// 只下载从这个时间开始的内容, 如 01:10:00、4200.5, 或者 #EXT-X-PROGRAM-DATE-TIME 的时间 2024-05-01T12:00:00Z
+    #[arg(long = "from")]
+    from: Option<TimePoint>,
+
+    /// 只下载到这个时间为止的内容, 格式和 --from 相同
+    #[arg(long = "to")]
+    to: Option<TimePoint>,
+
+    /// 合并前检查分片: off|quick(检查文件结构)|full(再用 ffprobe 比较时长和 #EXTINF), 有问题的分片重新下载
+    #[arg(long = "verify", default_value = "quick")]
+    verify: VerifyMode,
 
-fn path_to_md5(url_str: &str) -> Option<String> {
-    // 尝试解析 URL
-    let parsed_url = Url::parse(url_str).ok()?;
-    let path = parsed_url.path(); // 提取 path，比如 "/api/v1/data"
+    /// 自定义请求头, 如 "Authorization: Bearer xxx", 可以多次指定
+    #[arg(long = "header")]
+    header: Vec<String>,
 
-    // 计算 MD5
-    let digest = md5::compute(path);
-    Some(format!("{:x}", digest)) // 转成十六进制字符串返回
+    /// cookie, 如 "a=1; b=2", 可以多次指定
+    #[arg(long = "cookie")]
+    cookie: Vec<String>,
+
+    /// Netscape 格式的 cookie 文件
+    #[arg(long = "cookie-file", default_value_t = String::from(""))]
+    cookie_file: String,
+
+    /// User-Agent
+    #[arg(long = "user-agent", default_value_t = String::from(""))]
+    user_agent: String,
+
+    /// Referer
+    #[arg(long = "referer", default_value_t = String::from(""))]
+    referer: String,
+
+    /// 代理, 如 http://127.0.0.1:8080、socks5h://127.0.0.1:1080, 不指定时使用 HTTP_PROXY/HTTPS_PROXY/NO_PROXY 环境变量
+    #[arg(long = "proxy", default_value_t = String::from(""))]
+    proxy: String,
+
+    /// 所有下载共用的限速, 如 500K、5M
+    #[arg(long = "limit-rate", default_value_t = String::from(""))]
+    limit_rate: String,
+
+    /// 每个连接的限速, 如 500K
+    #[arg(long = "limit-rate-per-conn", default_value_t = String::from(""))]
+    limit_rate_per_conn: String,
 }
 
+
 impl DownloadArgs {
-    fn get_folder(&self) -> String {
-        let mut folder_name = self.folder.clone();
-        if folder_name.is_empty() {
-            let md5_str = path_to_md5(self.url.clone().as_str());
-            if md5_str.is_some() {
-                folder_name = md5_str.unwrap()
-            }else{
-                folder_name = format!("{}", now());
-            }
+    pub async fn download(&mut self, progress: Arc<Progress>) -> Result<bool, Error> {
+        // 继续下载时使用 manifest.json 中记录的变体、音轨和字幕
+        let mut downloader = if self.resume.is_empty() {
+            Downloader::new(self.url.clone())
+                .variant(self.variant.clone())
+                .audio_lang(self.audio_lang.clone())
+                .subtitles(self.subs.clone())
+                .skip_discontinuities(self.skip_discontinuities)
+                .time_range(self.from.clone(), self.to.clone())
+                .inherit_query(self.inherit_query)
+        } else {
+            Downloader::resume(&self.resume)
+        };
+        downloader = downloader
+            .progress(progress)
+            .output(self.target_file_name.clone())
+            .download_dir(self.download_dir.clone())
+            .concurrency(self.concurrent)
+            .live(self.live)
+            .max_duration(self.max_duration)
+            .retries(self.retries)
+            .retry_delay(Duration::from_millis(self.retry_delay))
+            .allow_gaps(self.allow_gaps)
+            .verify(self.verify)
+            .use_ffmpeg(self.ffmpeg_download);
+        if !self.resume.is_empty() && !self.url.is_empty() {
+            downloader = downloader.url(self.url.clone());
         }
-        format!("./{}/{}", self.download_dir, folder_name)
-    }
-    pub async fn download(&mut self, current_dir: PathBuf) {
-        let folder_name = self.get_folder();
-        println!("download folder name == {}", folder_name.clone());
-        // url 或者文件夹存在base_info.json 存在即可，否则报错
-        if self.url.is_empty() && !check_base_info_exists(folder_name.clone()) {
-            println!("url or folder is required!");
-            return;
+        if !self.folder.is_empty() && self.resume.is_empty() {
+            downloader = downloader.folder(Path::new(&self.download_dir).join(&self.folder));
         }
-        let file_name = get_file_name(self.target_file_name.to_owned());
-        println!("download file name: {}", file_name.clone());
-        let res;
-        if !self.ffmpeg_download {
-            match create_folder(folder_name.clone()) {
-                Ok(_) => {
-                    if !folder_name.is_empty() {
-                        if let Err(_) = env::set_current_dir(&Path::new(&folder_name)) {
-                            println!("进入文件夹失败");
-                            return;
-                        }
-                    }
-                    res = fast_download(
-                        self.url.clone(),
-                        file_name,
-                        self.folder.clone(),
-                        self.concurrent,
-                    )
-                        .await
-                        .expect("下载失败");
-                }
-                Err(e) => {
-                    println!("创建{}文件夹出错,{}", folder_name.clone(), e);
-                    return;
-                }
-            }
-        } else {
-            let full_file = format!("{}/{}",folder_name, file_name);
-            println!("full file name = {}", full_file.clone());
-            res = download(self.url.clone(), full_file.clone()).expect("下载失败");
+        for item in &self.header {
+            let (name, value) = item
+                .split_once(':')
+                .ok_or_else(|| Error::Parse(format!("请求头 {} 需要是 \"名称: 值\" 格式", item)))?;
+            downloader = downloader.header(name.trim(), value.trim());
+        }
+        for item in &self.cookie {
+            downloader = downloader.cookie(item);
+        }
+        if !self.cookie_file.is_empty() {
+            downloader = downloader.cookie_file(&self.cookie_file);
+        }
+        if !self.user_agent.is_empty() {
+            downloader = downloader.user_agent(self.user_agent.clone());
+        }
+        if !self.referer.is_empty() {
+            downloader = downloader.referer(self.referer.clone());
         }
-        println!("生成mp4文件成功");
-        if res {
-            env::set_current_dir(current_dir).unwrap();
-            let data = clear_temp_files(folder_name.clone());
-            if data {
-                println!("清理临时文件成功");
-            } else {
-                println!("清理临时文件失败");
-            }
+        if !self.proxy.is_empty() {
+            downloader = downloader.proxy(self.proxy.clone());
         }
+        if !self.limit_rate.is_empty() {
+            downloader = downloader.limit_rate(parse_rate(&self.limit_rate)?);
+        }
+        if !self.limit_rate_per_conn.is_empty() {
+            downloader = downloader.limit_rate_per_connection(parse_rate(&self.limit_rate_per_conn)?);
+        }
+        if self.info {
+            println!("{}", downloader.info().await?);
+            return Ok(true);
+        }
+        let file = downloader.run().await?;
+        println!("生成文件: {}", file.display());
+        Ok(true)
+    }
+}
+
+#[derive(clapArgs)]
+pub struct ServeArgs {
+    /// 监听地址
+    #[arg(long = "bind", default_value_t = String::from("127.0.0.1:8080"))]
+    bind: String,
+
+    /// 任务状态和生成文件保存的目录
+    #[arg(long = "data-dir", default_value_t = String::from("jobs"))]
+    data_dir: String,
+
+    /// 合并、截取任务可以读取的本地目录, 不指定时为 --data-dir
+    #[arg(long = "input-dir", default_value_t = String::from(""))]
+    input_dir: String,
+
+    /// 同时执行的任务数
+    #[arg(long = "workers", default_value_t = 2)]
+    workers: usize,
+
+    /// 最多等待执行的任务数
+    #[arg(long = "max-queue", default_value_t = 100)]
+    max_queue: usize,
+}
+
+impl ServeArgs {
+    pub async fn serve(&self) -> Result<bool, Error> {
+        let mut server = Server::new(self.bind.clone())
+            .data_dir(self.data_dir.clone())
+            .workers(self.workers)
+            .max_queue(self.max_queue);
+        if !self.input_dir.is_empty() {
+            server = server.input_dir(self.input_dir.clone());
+        }
+        server.run().await?;
+        Ok(true)
     }
 }
 
@@ -301,17 +409,48 @@ fn ensure_directory_exists(path: &str) {
 #[actix_web::main]
 pub async fn main() {
     init_folder();
-    let current_dir = env::current_dir().unwrap();
     let args = Args::parse();
-    match args.command {
-        Commands::Combine(mut args) => {
-            args.combine()
+    if let Err(e) = logging::init(args.verbose, args.log_file.as_deref().map(Path::new)) {
+        eprintln!("{}", e);
+        std::process::exit(e.exit_code());
+    }
+    let mode = if args.quiet {
+        ProgressMode::Quiet
+    } else if args.json_progress {
+        ProgressMode::Json
+    } else {
+        ProgressMode::Bar
+    };
+    let progress = Arc::new(Progress::default());
+    // 服务模式下每个任务有自己的进度, 通过接口查看
+    let reporter = match args.command {
+        Commands::Serve(_) => None,
+        _ => Some(Reporter::start(progress.clone(), mode)),
+    };
+    let res = match args.command {
+        Commands::Combine(args) => {
+            args.combine(progress)
         }
         Commands::Cut(mut args) => {
-            args.cut();
+            args.cut(progress)
         }
         Commands::Download(mut args) => {
-            args.download(current_dir).await;
+            args.download(progress).await
+        }
+        Commands::Serve(args) => {
+            args.serve().await
+        }
+    };
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    // 出错时按错误类型返回不同的退出码, 没有完成但也没有具体错误时返回 1
+    match res {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(e.exit_code());
         }
     }
 }
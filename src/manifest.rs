@@ -0,0 +1,271 @@
+use crate::error::Error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentStatus {
+    Pending,
+    Done,
+    Failed,
+}
+
+// 一个分片的下载状态, 下载完成后记录文件大小和 md5, 继续下载时用来校验文件是否完整
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SegmentState {
+    pub url: String,
+    #[serde(default)]
+    pub range: Option<(u64, u64)>, // (开始位置, 长度)
+    #[serde(default)]
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub md5: Option<String>,
+    pub status: SegmentStatus,
+}
+
+// 下载目录中的 manifest.json, 记录下载参数、所有分片的状态和 key,
+// 不指定 url 时可以从这里继续下载
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    pub url: String,
+    pub m3u8_name: String,
+    #[serde(default)]
+    pub output: String,
+    #[serde(default)]
+    pub variant: String,
+    #[serde(default)]
+    pub audio_lang: String,
+    #[serde(default)]
+    pub subs: Vec<String>,
+    #[serde(default)]
+    pub skip_discontinuities: bool,
+    #[serde(default)]
+    pub inherit_query: bool,
+    #[serde(default)]
+    pub from: String, // 按时间范围下载的开始时间, 为空时从头开始
+    #[serde(default)]
+    pub to: String,
+    #[serde(default)]
+    pub segments: BTreeMap<String, SegmentState>, // 分片文件名(相对下载目录) -> 状态
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>, // key 的地址 -> 十六进制的 key
+}
+
+// 之前版本只保存了 url 和播放列表文件名
+#[derive(Deserialize)]
+struct BaseInfo {
+    url: String,
+    m3u8_name: String,
+}
+
+impl Manifest {
+    // 读取 dir 中的 manifest.json, 没有时读取旧版本的 base_info.json, 都没有时返回 None
+    pub fn load(dir: &Path) -> Result<Option<Manifest>, Error> {
+        let file = dir.join(MANIFEST);
+        if file.is_file() {
+            let data = fs::read(&file)?;
+            let manifest = serde_json::from_slice(&data).map_err(|e| Error::Parse(format!("{} 格式错误, {}", file.display(), e)))?;
+            return Ok(Some(manifest));
+        }
+        let file = dir.join("base_info.json");
+        if file.is_file() {
+            let data = fs::read(&file)?;
+            let info: BaseInfo = serde_json::from_slice(&data).map_err(|e| Error::Parse(format!("{} 格式错误, {}", file.display(), e)))?;
+            return Ok(Some(Manifest {
+                url: info.url,
+                m3u8_name: info.m3u8_name,
+                ..Manifest::default()
+            }));
+        }
+        Ok(None)
+    }
+}
+
+// 下载过程中多个任务共用的 manifest, 分片状态变化时更新, 最多每秒写一次文件
+pub struct ManifestStore {
+    dir: PathBuf,
+    state: Mutex<(Manifest, Instant)>, // (manifest, 上次保存的时间)
+}
+
+impl ManifestStore {
+    pub fn new(dir: &Path, manifest: Manifest) -> ManifestStore {
+        ManifestStore {
+            dir: dir.to_path_buf(),
+            state: Mutex::new((manifest, Instant::now())),
+        }
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        self.state.lock().unwrap().0.clone()
+    }
+
+    // 写到临时文件后再重命名, 中途退出时不会留下不完整的 manifest.json
+    pub fn save(&self) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        self.write(&state.0)?;
+        state.1 = Instant::now();
+        Ok(())
+    }
+
+    fn write(&self, manifest: &Manifest) -> Result<(), Error> {
+        let data = serde_json::to_vec_pretty(manifest).map_err(|e| Error::Parse(e.to_string()))?;
+        let tmp = self.dir.join(format!("{}.tmp", MANIFEST));
+        fs::write(&tmp, data)?;
+        fs::rename(tmp, self.dir.join(MANIFEST))?;
+        Ok(())
+    }
+
+    fn update(&self, f: impl FnOnce(&mut Manifest)) {
+        let mut state = self.state.lock().unwrap();
+        f(&mut state.0);
+        if state.1.elapsed() >= Duration::from_secs(1) {
+            // 写失败时下次再写, 最后 save 时会返回错误
+            if self.write(&state.0).is_ok() {
+                state.1 = Instant::now();
+            }
+        }
+    }
+
+    fn relative(&self, file: &str) -> String {
+        let path = Path::new(file);
+        path.strip_prefix(&self.dir).unwrap_or(path).display().to_string()
+    }
+
+    // 记录需要下载的分片, 地址(不含查询参数)和范围没变时保留之前的下载状态
+    pub fn register(&self, file: &str, url: &str, range: Option<(u64, u64)>) {
+        let name = self.relative(file);
+        self.update(|manifest| {
+            let same = manifest
+                .segments
+                .get(&name)
+                .is_some_and(|item| item.range == range && without_query(&item.url) == without_query(url));
+            match manifest.segments.get_mut(&name) {
+                // 带签名的地址每次可能不同, 使用最新的地址
+                Some(item) if same => item.url = url.to_string(),
+                _ => {
+                    manifest.segments.insert(
+                        name,
+                        SegmentState {
+                            url: url.to_string(),
+                            range,
+                            size: None,
+                            md5: None,
+                            status: SegmentStatus::Pending,
+                        },
+                    );
+                }
+            }
+        });
+    }
+
+    // 分片已经下载完成, 并且文件的大小和 md5 和记录的一致
+    pub fn verify(&self, file: &str) -> bool {
+        let expected = match self.state.lock().unwrap().0.segments.get(&self.relative(file)) {
+            Some(item) if item.status == SegmentStatus::Done => (item.size, item.md5.clone()),
+            _ => return false,
+        };
+        match fs::read(file) {
+            Ok(data) => expected == (Some(data.len() as u64), Some(format!("{:x}", md5::compute(&data)))),
+            Err(_) => false,
+        }
+    }
+
+    // 下载结束后记录结果, 成功时记录文件大小和 md5
+    pub fn finish(&self, file: &str, ok: bool) {
+        let name = self.relative(file);
+        let data = if ok { fs::read(file).ok() } else { None };
+        self.update(|manifest| {
+            if let Some(item) = manifest.segments.get_mut(&name) {
+                match data {
+                    Some(data) => {
+                        item.size = Some(data.len() as u64);
+                        item.md5 = Some(format!("{:x}", md5::compute(&data)));
+                        item.status = SegmentStatus::Done;
+                    }
+                    None => {
+                        item.size = None;
+                        item.md5 = None;
+                        item.status = SegmentStatus::Failed;
+                    }
+                }
+            }
+        });
+    }
+
+    pub fn key(&self, uri: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        from_hex(state.0.keys.get(uri)?)
+    }
+
+    pub fn set_key(&self, uri: &str, data: &[u8]) {
+        self.update(|manifest| {
+            manifest.keys.insert(uri.to_string(), data.iter().map(|b| format!("{:02x}", b)).collect());
+        });
+    }
+}
+
+fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or(url)
+}
+
+fn from_hex(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len()).step_by(2).map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::manifest::{Manifest, ManifestStore, SegmentStatus};
+    use std::fs;
+
+    #[test]
+    fn test_verify_and_resume() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("0.ts").display().to_string();
+        let store = ManifestStore::new(dir.path(), Manifest::default());
+        store.register(&file, "https://example.com/0.ts?token=1", None);
+        assert!(!store.verify(&file));
+
+        fs::write(&file, b"segment").unwrap();
+        store.finish(&file, true);
+        store.set_key("https://example.com/key", &[0, 1, 0xab, 0xff]);
+        store.save().unwrap();
+        assert!(store.verify(&file));
+
+        // 重新加载后签名变了也保留下载状态
+        let store = ManifestStore::new(dir.path(), Manifest::load(dir.path()).unwrap().unwrap());
+        store.register(&file, "https://example.com/0.ts?token=2", None);
+        assert!(store.verify(&file));
+        assert_eq!(store.manifest().segments["0.ts"].url, "https://example.com/0.ts?token=2");
+        assert_eq!(store.key("https://example.com/key"), Some(vec![0, 1, 0xab, 0xff]));
+
+        // 文件内容被破坏时需要重新下载
+        fs::write(&file, b"segmenX").unwrap();
+        assert!(!store.verify(&file));
+
+        // 地址变了时重新下载
+        fs::write(&file, b"segment").unwrap();
+        store.register(&file, "https://example.com/other.ts", None);
+        assert!(!store.verify(&file));
+        assert_eq!(store.manifest().segments["0.ts"].status, SegmentStatus::Pending);
+    }
+
+    #[test]
+    fn test_load_base_info() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(Manifest::load(dir.path()).unwrap().is_none());
+        fs::write(dir.path().join("base_info.json"), r#"{"url":"https://example.com/a.m3u8","m3u8_name":"1.m3u8"}"#).unwrap();
+        let manifest = Manifest::load(dir.path()).unwrap().unwrap();
+        assert_eq!(manifest.url, "https://example.com/a.m3u8");
+        assert_eq!(manifest.m3u8_name, "1.m3u8");
+        assert!(manifest.segments.is_empty());
+    }
+}
@@ -0,0 +1,244 @@
+use crate::http::format_bytes;
+use serde::Serialize;
+use std::io::{IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// 任务进度, 可以在其他线程中读取, 也可以用来取消下载。
+// 下载时记录分片数和字节数, ffmpeg 执行时记录已处理的时长和总时长
+#[derive(Debug)]
+pub struct Progress {
+    pub(crate) total: AtomicU64,
+    pub(crate) done: AtomicU64,
+    bytes: AtomicU64,
+    media_done_ms: AtomicU64,
+    media_total_ms: AtomicU64,
+    cancelled: AtomicBool,
+    stage: Mutex<(String, Instant)>, // (当前阶段, 开始时间)
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Progress {
+            total: AtomicU64::new(0),
+            done: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            media_done_ms: AtomicU64::new(0),
+            media_total_ms: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            stage: Mutex::new((String::default(), Instant::now())),
+        }
+    }
+}
+
+// 某一时刻的进度, --json-progress 时每行输出一个
+#[derive(Debug, Clone, Serialize)]
+pub struct ProgressState {
+    pub stage: String,
+    pub done: u64,
+    pub total: u64,
+    pub bytes: u64,
+    pub speed: u64, // 字节/秒
+    pub media_done_ms: u64,
+    pub media_total_ms: u64,
+    pub percent: Option<f64>,
+    pub eta: Option<u64>, // 预计剩余秒数
+    pub elapsed: u64,
+}
+
+impl Progress {
+    // (已完成的分片数, 分片总数)
+    pub fn snapshot(&self) -> (u64, u64) {
+        (self.done.load(Ordering::Relaxed), self.total.load(Ordering::Relaxed))
+    }
+
+    // 取消后还没开始的分片不再下载, 下载结束后返回 Error::Cancelled
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    // 进入新的阶段(download、combine、cut 等), media_total 为 ffmpeg 需要处理的总时长, 不知道时为 0
+    pub fn start_stage(&self, stage: &str, media_total: Duration) {
+        *self.stage.lock().unwrap() = (stage.to_string(), Instant::now());
+        self.media_done_ms.store(0, Ordering::Relaxed);
+        self.media_total_ms.store(media_total.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    // ffmpeg 已处理的时长
+    pub fn set_media_done(&self, done: Duration) {
+        self.media_done_ms.store(done.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    pub fn state(&self) -> ProgressState {
+        let (stage, started) = self.stage.lock().unwrap().clone();
+        let elapsed = started.elapsed().as_secs_f64();
+        let (done, total) = self.snapshot();
+        let bytes = self.bytes();
+        let media_done_ms = self.media_done_ms.load(Ordering::Relaxed);
+        let media_total_ms = self.media_total_ms.load(Ordering::Relaxed);
+        // ffmpeg 执行时按处理的时长计算, 下载时按分片数计算
+        let fraction = if media_total_ms > 0 {
+            Some((media_done_ms as f64 / media_total_ms as f64).min(1.0))
+        } else if stage == "download" && total > 0 {
+            Some(done as f64 / total as f64)
+        } else {
+            None
+        };
+        let eta = fraction.filter(|f| *f > 0.0).map(|f| (elapsed * (1.0 - f) / f) as u64);
+        let speed = if stage == "download" && elapsed > 0.0 { (bytes as f64 / elapsed) as u64 } else { 0 };
+        ProgressState {
+            stage,
+            done,
+            total,
+            bytes,
+            speed,
+            media_done_ms,
+            media_total_ms,
+            percent: fraction.map(|f| (f * 1000.0).round() / 10.0),
+            eta,
+            elapsed: elapsed as u64,
+        }
+    }
+}
+
+// 00:01:23 这样的时长
+fn format_duration(seconds: u64) -> String {
+    format!("{:02}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+impl ProgressState {
+    // 终端里的进度条, 如 download [#####-----] 45/100 12.30MB 1.20MB/s ETA 00:00:12
+    pub fn bar(&self) -> String {
+        let width = 30;
+        let filled = self.percent.map(|p| (p / 100.0 * width as f64) as usize).unwrap_or(0).min(width);
+        let mut line = format!("{} [{}{}]", self.stage, "#".repeat(filled), "-".repeat(width - filled));
+        if let Some(percent) = self.percent {
+            line.push_str(&format!(" {:.1}%", percent));
+        }
+        if self.media_total_ms > 0 {
+            line.push_str(&format!(
+                " {}/{}",
+                format_duration(self.media_done_ms / 1000),
+                format_duration(self.media_total_ms / 1000)
+            ));
+        } else if self.stage == "download" {
+            line.push_str(&format!(" {}/{} {} {}/s", self.done, self.total, format_bytes(self.bytes), format_bytes(self.speed)));
+        }
+        if let Some(eta) = self.eta {
+            line.push_str(&format!(" ETA {}", format_duration(eta)));
+        }
+        line
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgressMode {
+    Bar,   // 终端中显示进度条, 不是终端时不输出
+    Json,  // 每秒输出一行 json
+    Quiet, // 不输出
+}
+
+// 在后台线程中定时输出进度, 进度条输出到 stderr, json 输出到 stdout
+pub struct Reporter {
+    stop: Arc<AtomicBool>,
+    handle: Option<thread::JoinHandle<()>>,
+}
+
+impl Reporter {
+    pub fn start(progress: Arc<Progress>, mut mode: ProgressMode) -> Reporter {
+        if mode == ProgressMode::Bar && !std::io::stderr().is_terminal() {
+            mode = ProgressMode::Quiet;
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let stop_clone = stop.clone();
+        let handle = (mode != ProgressMode::Quiet).then(|| {
+            thread::spawn(move || {
+                let interval = if mode == ProgressMode::Json { Duration::from_secs(1) } else { Duration::from_millis(200) };
+                let mut last = Instant::now() - interval;
+                loop {
+                    let stopped = stop_clone.load(Ordering::Relaxed);
+                    if stopped || last.elapsed() >= interval {
+                        last = Instant::now();
+                        let state = progress.state();
+                        if !state.stage.is_empty() {
+                            Reporter::print(&state, mode);
+                        }
+                    }
+                    if stopped {
+                        if mode == ProgressMode::Bar {
+                            eprintln!();
+                        }
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(50));
+                }
+            })
+        });
+        Reporter { stop, handle }
+    }
+
+    fn print(state: &ProgressState, mode: ProgressMode) {
+        match mode {
+            ProgressMode::Bar => {
+                let mut stderr = std::io::stderr();
+                let _ = write!(stderr, "\r\x1b[2K{}", state.bar());
+                let _ = stderr.flush();
+            }
+            ProgressMode::Json => {
+                if let Ok(line) = serde_json::to_string(state) {
+                    println!("{}", line);
+                }
+            }
+            ProgressMode::Quiet => {}
+        }
+    }
+
+    // 停止输出, 最后再输出一次进度
+    pub fn finish(mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::progress::Progress;
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    #[test]
+    fn test_state() {
+        let progress = Progress::default();
+        progress.start_stage("download", Duration::ZERO);
+        progress.total.store(4, Ordering::Relaxed);
+        progress.done.store(1, Ordering::Relaxed);
+        progress.add_bytes(2048);
+        let state = progress.state();
+        assert_eq!(state.percent, Some(25.0));
+        assert!(state.bar().starts_with("download [#######-----"));
+        assert!(state.bar().contains(" 1/4 2.00KB "));
+
+        progress.start_stage("combine", Duration::from_secs(200));
+        progress.set_media_done(Duration::from_secs(50));
+        let state = progress.state();
+        assert_eq!(state.percent, Some(25.0));
+        assert!(state.bar().contains("00:00:50/00:03:20"));
+        assert_eq!(state.speed, 0);
+    }
+}
@@ -0,0 +1,706 @@
+// SAMPLE-AES 解密, 参考 Apple 的 MPEG-2 Stream Encryption Format for HTTP Live Streaming
+// 视频只加密 H.264 的 slice NAL(类型1和5), 音频加密 AAC(ADTS) 和 AC-3/E-AC-3 帧,
+// 解密后重新打包成普通的 TS, 交给后面的合并流程处理
+use openssl::symm::{Cipher, Crypter, Mode};
+use crate::error::Error;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+const PAT_PID: u16 = 0;
+
+// AC-3 frmsizecod 对应的码率(kbps)
+const AC3_BITRATES: [usize; 19] = [
+    32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384, 448, 512, 576, 640,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SampleKind {
+    H264,
+    Aac,
+    Ac3,
+}
+
+// 加密流的 stream_type, 以及解密后应该改回的 stream_type
+fn sample_kind(stream_type: u8) -> Option<(SampleKind, u8)> {
+    match stream_type {
+        0xdb => Some((SampleKind::H264, 0x1b)),
+        0xcf => Some((SampleKind::Aac, 0x0f)),
+        0xc1 => Some((SampleKind::Ac3, 0x81)),
+        0xc2 => Some((SampleKind::Ac3, 0x87)),
+        _ => None,
+    }
+}
+
+fn packet_pid(packet: &[u8]) -> u16 {
+    (((packet[1] & 0x1f) as u16) << 8) | packet[2] as u16
+}
+
+fn packet_pusi(packet: &[u8]) -> bool {
+    packet[1] & 0x40 != 0
+}
+
+// 拆分 TS 包, 返回 (自适应字段(含长度字节), 负载)
+fn split_packet(packet: &[u8]) -> (&[u8], &[u8]) {
+    let control = (packet[3] >> 4) & 0x03;
+    let mut offset = 4;
+    let mut adaptation: &[u8] = &[];
+    if control & 0x02 != 0 {
+        let end = (5 + packet[4] as usize).min(TS_PACKET_SIZE);
+        adaptation = &packet[4..end];
+        offset = end;
+    }
+    if control & 0x01 == 0 {
+        return (adaptation, &[]);
+    }
+    (adaptation, &packet[offset..])
+}
+
+// 去掉自适应字段里的填充字节, 只保留标志位和 PCR 等可选字段
+fn trim_adaptation(adaptation: &[u8]) -> Vec<u8> {
+    if adaptation.len() < 2 {
+        return vec![];
+    }
+    let content = &adaptation[1..];
+    let flags = content[0];
+    let mut size = 1;
+    if flags & 0x10 != 0 {
+        size += 6; // PCR
+    }
+    if flags & 0x08 != 0 {
+        size += 6; // OPCR
+    }
+    if flags & 0x04 != 0 {
+        size += 1; // splice_countdown
+    }
+    if flags & 0x02 != 0 && size < content.len() {
+        size += 1 + content[size] as usize; // transport_private_data
+    }
+    if flags & 0x01 != 0 && size < content.len() {
+        size += 1 + content[size] as usize; // adaptation_field_extension
+    }
+    if flags == 0 {
+        return vec![];
+    }
+    content[..size.min(content.len())].to_vec()
+}
+
+// 把一个 PES 切成 TS 包, 第一个包带上原来的自适应字段(PCR、随机访问标志等)
+fn packetize_pes(pid: u16, pes: &[u8], first_adaptation: &[u8], cc: &mut u8) -> Vec<[u8; TS_PACKET_SIZE]> {
+    let mut packets = vec![];
+    let mut offset = 0;
+    let mut first = true;
+    while offset < pes.len() || first {
+        let mut packet = [0xffu8; TS_PACKET_SIZE];
+        packet[0] = TS_SYNC_BYTE;
+        packet[1] = ((pid >> 8) as u8 & 0x1f) | if first { 0x40 } else { 0x00 };
+        packet[2] = pid as u8;
+        let adaptation: &[u8] = if first { first_adaptation } else { &[] };
+        let remain = pes.len() - offset;
+        // 自适应字段的总长度(含长度字节), 最后一个包不够时用填充补齐
+        let mut adaptation_total = if adaptation.is_empty() { 0 } else { 1 + adaptation.len() };
+        if TS_PACKET_SIZE - 4 - adaptation_total > remain {
+            adaptation_total = TS_PACKET_SIZE - 4 - remain;
+        }
+        if adaptation_total > 0 {
+            packet[3] = 0x30 | (*cc & 0x0f);
+            packet[4] = (adaptation_total - 1) as u8;
+            if adaptation_total > 1 {
+                if adaptation.is_empty() {
+                    packet[5] = 0x00;
+                } else {
+                    packet[5..5 + adaptation.len()].copy_from_slice(adaptation);
+                }
+            }
+        } else {
+            packet[3] = 0x10 | (*cc & 0x0f);
+        }
+        let payload_len = TS_PACKET_SIZE - 4 - adaptation_total;
+        packet[4 + adaptation_total..].copy_from_slice(&pes[offset..offset + payload_len]);
+        offset += payload_len;
+        *cc = (*cc + 1) & 0x0f;
+        first = false;
+        packets.push(packet);
+    }
+    packets
+}
+
+// 返回 PSI 表在负载中的起始位置和长度(含3字节表头)
+fn psi_section(payload: &[u8]) -> Option<(usize, usize)> {
+    let start = 1 + *payload.first()? as usize;
+    if start + 3 > payload.len() {
+        return None;
+    }
+    let length = 3 + ((((payload[start + 1] & 0x0f) as usize) << 8) | payload[start + 2] as usize);
+    if start + length > payload.len() || length < 12 {
+        return None;
+    }
+    Some((start, length))
+}
+
+fn crc32_mpeg2(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for byte in data {
+        crc ^= (*byte as u32) << 24;
+        for _ in 0..8 {
+            crc = if crc & 0x80000000 != 0 { (crc << 1) ^ 0x04c11db7 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+fn parse_pat(payload: &[u8]) -> Vec<u16> {
+    let mut pids = vec![];
+    if let Some((start, length)) = psi_section(payload) {
+        let section = &payload[start..start + length];
+        let mut pos = 8;
+        while pos + 4 <= length - 4 {
+            let program = ((section[pos] as u16) << 8) | section[pos + 1] as u16;
+            let pid = (((section[pos + 2] & 0x1f) as u16) << 8) | section[pos + 3] as u16;
+            if program != 0 {
+                pids.push(pid);
+            }
+            pos += 4;
+        }
+    }
+    pids
+}
+
+// 把 PMT 中加密流的 stream_type 改回明文的类型并重新计算 CRC, 返回加密流的 PID
+fn rewrite_pmt(packet: &mut [u8; TS_PACKET_SIZE]) -> Vec<(u16, SampleKind)> {
+    let mut streams = vec![];
+    let payload_offset = TS_PACKET_SIZE - split_packet(packet).1.len();
+    let (start, length) = match psi_section(&packet[payload_offset..]) {
+        Some(data) => data,
+        None => return streams,
+    };
+    let section = &mut packet[payload_offset + start..payload_offset + start + length];
+    let program_info_length = (((section[10] & 0x0f) as usize) << 8) | section[11] as usize;
+    let mut pos = 12 + program_info_length;
+    while pos + 5 <= length - 4 {
+        let pid = (((section[pos + 1] & 0x1f) as u16) << 8) | section[pos + 2] as u16;
+        let info_length = (((section[pos + 3] & 0x0f) as usize) << 8) | section[pos + 4] as usize;
+        if let Some((kind, clear_type)) = sample_kind(section[pos]) {
+            section[pos] = clear_type;
+            streams.push((pid, kind));
+        }
+        pos += 5 + info_length;
+    }
+    if !streams.is_empty() {
+        let crc = crc32_mpeg2(&section[..length - 4]);
+        section[length - 4..].copy_from_slice(&crc.to_be_bytes());
+    }
+    streams
+}
+
+fn cbc_decrypt(key: &[u8], iv: &[u8], data: &mut [u8]) -> Result<(), Error> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut crypter = Crypter::new(Cipher::aes_128_cbc(), Mode::Decrypt, key, Some(iv))?;
+    crypter.pad(false);
+    let mut output = vec![0u8; data.len() + 16];
+    let mut count = crypter.update(data, &mut output)?;
+    count += crypter.finalize(&mut output[count..])?;
+    data.copy_from_slice(&output[..count]);
+    Ok(())
+}
+
+// 16字节加密块 + 最多144字节明文 循环, 剩余不超过16字节的部分不加密, 加密块之间是 CBC 链
+fn decrypt_pattern(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), Error> {
+    let mut blocks = vec![];
+    let mut pos = 0;
+    let mut remain = data.len();
+    while remain > 0 {
+        if remain > 16 {
+            blocks.push(pos);
+            pos += 16;
+            remain -= 16;
+        }
+        let skip = remain.min(144);
+        pos += skip;
+        remain -= skip;
+    }
+    let mut buffer: Vec<u8> = blocks.iter().flat_map(|p| data[*p..*p + 16].to_vec()).collect();
+    cbc_decrypt(key, iv, &mut buffer)?;
+    for (index, p) in blocks.iter().enumerate() {
+        data[*p..*p + 16].copy_from_slice(&buffer[index * 16..index * 16 + 16]);
+    }
+    Ok(())
+}
+
+// 去掉 NAL 中的防竞争字节 00 00 03
+fn remove_emulation_prevention(nal: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(nal.len());
+    let mut zeros = 0;
+    for byte in nal {
+        if zeros >= 2 && *byte == 0x03 {
+            zeros = 0;
+            continue;
+        }
+        zeros = if *byte == 0 { zeros + 1 } else { 0 };
+        output.push(*byte);
+    }
+    output
+}
+
+// 找出 Annex-B 码流中所有起始码的位置, 返回 (起始码开始, NAL 开始)
+fn find_start_codes(es: &[u8]) -> Vec<(usize, usize)> {
+    let mut list = vec![];
+    let mut i = 0;
+    while i + 3 <= es.len() {
+        if es[i] == 0 && es[i + 1] == 0 && es[i + 2] == 1 {
+            let mut code_start = i;
+            let min = list.last().map(|(_, nal)| *nal).unwrap_or(0);
+            if i > min && es[i - 1] == 0 {
+                code_start = i - 1;
+            }
+            list.push((code_start, i + 3));
+            i += 3;
+        } else {
+            i += 1;
+        }
+    }
+    list
+}
+
+fn decrypt_h264(es: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
+    let starts = find_start_codes(es);
+    if starts.is_empty() {
+        return Ok(es.to_vec());
+    }
+    let mut output = Vec::with_capacity(es.len());
+    output.extend_from_slice(&es[..starts[0].0]);
+    for (index, (code_start, nal_start)) in starts.iter().enumerate() {
+        let nal_end = match starts.get(index + 1) {
+            Some((next, _)) => *next,
+            None => es.len(),
+        };
+        output.extend_from_slice(&es[*code_start..*nal_start]);
+        let nal = &es[*nal_start..nal_end];
+        let nal_type = nal.first().map(|b| b & 0x1f).unwrap_or(0);
+        if nal.len() > 48 && (nal_type == 1 || nal_type == 5) {
+            // 加密后才插入的防竞争字节, 所以先去掉再解密, 前32字节不加密
+            let mut clear = remove_emulation_prevention(nal);
+            if clear.len() > 32 {
+                decrypt_pattern(&mut clear[32..], key, iv)?;
+            }
+            output.extend_from_slice(&clear);
+        } else {
+            output.extend_from_slice(nal);
+        }
+    }
+    Ok(output)
+}
+
+// 音频帧: 前16字节不加密, 之后完整的16字节块加密, 尾部不足16字节的不加密
+fn decrypt_audio_frame(data: &mut [u8], key: &[u8], iv: &[u8]) -> Result<(), Error> {
+    if data.len() <= 16 {
+        return Ok(());
+    }
+    let blocks = (data.len() - 16) / 16;
+    cbc_decrypt(key, iv, &mut data[16..16 + blocks * 16])
+}
+
+fn decrypt_aac(es: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = es.to_vec();
+    let mut pos = 0;
+    while pos + 7 <= output.len() {
+        if output[pos] != 0xff || output[pos + 1] & 0xf0 != 0xf0 {
+            break;
+        }
+        let header = if output[pos + 1] & 0x01 == 1 { 7 } else { 9 };
+        let frame_len = (((output[pos + 3] & 0x03) as usize) << 11)
+            | ((output[pos + 4] as usize) << 3)
+            | ((output[pos + 5] >> 5) as usize);
+        if frame_len < header || pos + frame_len > output.len() {
+            break;
+        }
+        decrypt_audio_frame(&mut output[pos + header..pos + frame_len], key, iv)?;
+        pos += frame_len;
+    }
+    Ok(output)
+}
+
+fn ac3_frame_len(frame: &[u8]) -> Option<usize> {
+    if frame.len() < 6 || frame[0] != 0x0b || frame[1] != 0x77 {
+        return None;
+    }
+    let bsid = frame[5] >> 3;
+    if bsid > 10 {
+        // E-AC-3
+        let size = (((frame[2] & 0x07) as usize) << 8) | frame[3] as usize;
+        return Some((size + 1) * 2);
+    }
+    let code = (frame[4] & 0x3f) as usize;
+    let bitrate = *AC3_BITRATES.get(code >> 1)?;
+    let words = match frame[4] >> 6 {
+        0 => bitrate * 2,
+        1 => bitrate * 96000 / 44100 + (code & 1),
+        2 => bitrate * 3,
+        _ => return None,
+    };
+    Some(words * 2)
+}
+
+fn decrypt_ac3(es: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut output = es.to_vec();
+    let mut pos = 0;
+    while let Some(frame_len) = ac3_frame_len(&output[pos..]) {
+        if frame_len == 0 || pos + frame_len > output.len() {
+            break;
+        }
+        decrypt_audio_frame(&mut output[pos..pos + frame_len], key, iv)?;
+        pos += frame_len;
+    }
+    Ok(output)
+}
+
+fn decrypt_pes(kind: SampleKind, pes: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
+    if pes.len() < 9 || pes[0] != 0 || pes[1] != 0 || pes[2] != 1 {
+        return Ok(pes.to_vec());
+    }
+    let header_len = 9 + pes[8] as usize;
+    if header_len > pes.len() {
+        return Ok(pes.to_vec());
+    }
+    let packet_length = ((pes[4] as usize) << 8) | pes[5] as usize;
+    let mut es_end = pes.len();
+    if packet_length != 0 {
+        es_end = (6 + packet_length).clamp(header_len, pes.len());
+    }
+    let es = &pes[header_len..es_end];
+    let clear = match kind {
+        SampleKind::H264 => decrypt_h264(es, key, iv)?,
+        SampleKind::Aac => decrypt_aac(es, key, iv)?,
+        SampleKind::Ac3 => decrypt_ac3(es, key, iv)?,
+    };
+    let mut output = pes[..header_len].to_vec();
+    output.extend_from_slice(&clear);
+    if packet_length != 0 {
+        let length = output.len() - 6;
+        let length = if length > 0xffff { 0 } else { length };
+        output[4..6].copy_from_slice(&(length as u16).to_be_bytes());
+    }
+    Ok(output)
+}
+
+// 一个加密流当前正在收集的 PES
+struct PesBuffer {
+    pid: u16,
+    kind: SampleKind,
+    cc: Option<u8>,
+    adaptation: Vec<u8>,
+    payload: Vec<u8>,
+    slots: Vec<usize>, // 原来的 TS 包在输出中的位置
+}
+
+impl PesBuffer {
+    fn new(pid: u16, kind: SampleKind) -> PesBuffer {
+        PesBuffer {
+            pid,
+            kind,
+            cc: None,
+            adaptation: vec![],
+            payload: vec![],
+            slots: vec![],
+        }
+    }
+
+    // 解密收集到的 PES, 重新打包后按原来的位置放回去, 多出来的位置留空
+    fn flush(&mut self, key: &[u8], iv: &[u8], output: &mut [Vec<[u8; TS_PACKET_SIZE]>]) -> Result<(), Error> {
+        if self.slots.is_empty() {
+            return Ok(());
+        }
+        let pes = decrypt_pes(self.kind, &self.payload, key, iv)?;
+        let mut cc = self.cc.unwrap_or(0);
+        let packets = packetize_pes(self.pid, &pes, &self.adaptation, &mut cc);
+        self.cc = Some(cc);
+        let last = self.slots.len() - 1;
+        for (index, packet) in packets.into_iter().enumerate() {
+            output[self.slots[index.min(last)]].push(packet);
+        }
+        self.payload.clear();
+        self.slots.clear();
+        Ok(())
+    }
+}
+
+pub mod sample_aes {
+    use crate::sample_aes::{
+        packet_pid, packet_pusi, parse_pat, rewrite_pmt, split_packet, trim_adaptation, PesBuffer, PAT_PID,
+        TS_PACKET_SIZE, TS_SYNC_BYTE,
+    };
+    use std::collections::HashMap;
+    use crate::error::Error;
+    use std::fs;
+
+    // 解密一段 SAMPLE-AES 加密的 TS 数据
+    pub fn decrypt_ts(data: &[u8], key: &[u8], iv: &[u8]) -> Result<Vec<u8>, Error> {
+        if key.len() != 16 || iv.len() != 16 {
+            return Err(Error::Decrypt(format!("SAMPLE-AES key和iv需要16字节, key {} iv {}", key.len(), iv.len())));
+        }
+        let mut pmt_pids: Vec<u16> = vec![];
+        let mut streams: HashMap<u16, PesBuffer> = HashMap::new();
+        let mut output: Vec<Vec<[u8; TS_PACKET_SIZE]>> = vec![];
+        let mut offset = 0;
+        while offset + TS_PACKET_SIZE <= data.len() {
+            if data[offset] != TS_SYNC_BYTE {
+                offset += 1;
+                continue;
+            }
+            let mut packet = [0u8; TS_PACKET_SIZE];
+            packet.copy_from_slice(&data[offset..offset + TS_PACKET_SIZE]);
+            offset += TS_PACKET_SIZE;
+            let pid = packet_pid(&packet);
+            if pid == PAT_PID {
+                pmt_pids = parse_pat(split_packet(&packet).1);
+            } else if pmt_pids.contains(&pid) && packet_pusi(&packet) {
+                for (stream_pid, kind) in rewrite_pmt(&mut packet) {
+                    streams.entry(stream_pid).or_insert_with(|| PesBuffer::new(stream_pid, kind));
+                }
+            } else if let Some(stream) = streams.get_mut(&pid) {
+                let (adaptation, payload) = split_packet(&packet);
+                if packet_pusi(&packet) {
+                    stream.flush(key, iv, &mut output)?;
+                    stream.adaptation = trim_adaptation(adaptation);
+                    if stream.cc.is_none() {
+                        stream.cc = Some(packet[3] & 0x0f);
+                    }
+                }
+                // PES 开始之前的数据和没有负载的包原样保留
+                if (!stream.slots.is_empty() || packet_pusi(&packet)) && !payload.is_empty() {
+                    stream.payload.extend_from_slice(payload);
+                    stream.slots.push(output.len());
+                    output.push(vec![]);
+                    continue;
+                }
+            }
+            output.push(vec![packet]);
+        }
+        for stream in streams.values_mut() {
+            stream.flush(key, iv, &mut output)?;
+        }
+        Ok(output.into_iter().flatten().flatten().collect())
+    }
+
+    pub fn decrypt_ts_file(input: &str, target: &str, key: &[u8], iv: &[u8]) -> Result<bool, Error> {
+        let data = fs::read(input)?;
+        let clear = decrypt_ts(&data, key, iv)?;
+        fs::write(target, clear)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sample_aes::sample_aes::decrypt_ts;
+    use crate::sample_aes::{
+        ac3_frame_len, crc32_mpeg2, packet_pid, packet_pusi, packetize_pes, psi_section, remove_emulation_prevention,
+        split_packet, TS_PACKET_SIZE,
+    };
+    use openssl::symm::{Cipher, Crypter, Mode};
+    use std::collections::HashMap;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+    const IV: [u8; 16] = [7u8; 16];
+    const VIDEO_PID: u16 = 0x100;
+    const AUDIO_PID: u16 = 0x101;
+
+    fn cbc_encrypt(data: &mut [u8]) {
+        let mut crypter = Crypter::new(Cipher::aes_128_cbc(), Mode::Encrypt, &KEY, Some(&IV)).unwrap();
+        crypter.pad(false);
+        let mut output = vec![0u8; data.len() + 16];
+        let count = crypter.update(data, &mut output).unwrap();
+        data.copy_from_slice(&output[..count]);
+    }
+
+    fn add_emulation_prevention(nal: &[u8]) -> Vec<u8> {
+        let mut output = vec![];
+        let mut zeros = 0;
+        for byte in nal {
+            if zeros >= 2 && *byte <= 3 {
+                output.push(0x03);
+                zeros = 0;
+            }
+            zeros = if *byte == 0 { zeros + 1 } else { 0 };
+            output.push(*byte);
+        }
+        output
+    }
+
+    fn encrypt_nal(nal: &[u8]) -> Vec<u8> {
+        let mut data = nal.to_vec();
+        let mut blocks = vec![];
+        let (mut pos, mut remain) = (32, nal.len() - 32);
+        while remain > 0 {
+            if remain > 16 {
+                blocks.push(pos);
+                pos += 16;
+                remain -= 16;
+            }
+            let skip = remain.min(144);
+            pos += skip;
+            remain -= skip;
+        }
+        let mut buffer: Vec<u8> = blocks.iter().flat_map(|p| data[*p..*p + 16].to_vec()).collect();
+        cbc_encrypt(&mut buffer);
+        for (index, p) in blocks.iter().enumerate() {
+            data[*p..*p + 16].copy_from_slice(&buffer[index * 16..index * 16 + 16]);
+        }
+        add_emulation_prevention(&data)
+    }
+
+    fn adts_frame(payload_len: usize, seed: u8) -> Vec<u8> {
+        let frame_len = payload_len + 7;
+        let mut frame = vec![
+            0xff,
+            0xf1,
+            0x50,
+            0x80 | ((frame_len >> 11) as u8 & 0x03),
+            (frame_len >> 3) as u8,
+            ((frame_len & 0x07) as u8) << 5 | 0x1f,
+            0xfc,
+        ];
+        frame.extend((0..payload_len).map(|i| (i as u8).wrapping_mul(13).wrapping_add(seed)));
+        frame
+    }
+
+    fn pes(stream_id: u8, es: &[u8], with_length: bool) -> Vec<u8> {
+        let mut data = vec![0, 0, 1, stream_id, 0, 0, 0x80, 0x80, 5, 0x21, 0, 1, 0, 1];
+        if with_length {
+            let length = (data.len() - 6 + es.len()) as u16;
+            data[4..6].copy_from_slice(&length.to_be_bytes());
+        }
+        data.extend_from_slice(es);
+        data
+    }
+
+    fn psi_packet(pid: u16, section: &[u8]) -> [u8; TS_PACKET_SIZE] {
+        let mut payload = vec![0u8];
+        payload.extend_from_slice(section);
+        let crc = crc32_mpeg2(section);
+        payload.extend_from_slice(&crc.to_be_bytes());
+        let mut cc = 0;
+        packetize_pes(pid, &payload, &[], &mut cc)[0]
+    }
+
+    fn section(table_id: u8, body: &[u8]) -> Vec<u8> {
+        let length = (body.len() + 5 + 4) as u16;
+        let mut data = vec![table_id, 0xb0 | (length >> 8) as u8, length as u8, 0, 1, 0xc1, 0, 0];
+        data.extend_from_slice(body);
+        data
+    }
+
+    // 生成加密的 TS: 一个视频 PES 和一个音频 PES
+    fn encrypted_fixture(video_es: &[u8], audio_es: &[u8]) -> Vec<u8> {
+        let mut data = vec![];
+        data.extend_from_slice(&psi_packet(0, &section(0x00, &[0, 1, 0xf0, 0x00])));
+        let pmt_body = [
+            0xe1, 0x00, 0xf0, 0x00, // PCR PID, program_info_length
+            0xdb, 0xe1, 0x00, 0xf0, 0x00, // SAMPLE-AES H.264
+            0xcf, 0xe1, 0x01, 0xf0, 0x00, // SAMPLE-AES AAC
+        ];
+        data.extend_from_slice(&psi_packet(0x1000, &section(0x02, &pmt_body)));
+
+        let mut cc = 0;
+        let pcr = [0x50, 0, 0, 0, 0, 0x7e, 0];
+        for packet in packetize_pes(VIDEO_PID, &pes(0xe0, video_es, false), &pcr, &mut cc) {
+            data.extend_from_slice(&packet);
+        }
+        let mut cc = 0;
+        for packet in packetize_pes(AUDIO_PID, &pes(0xc0, audio_es, true), &[], &mut cc) {
+            data.extend_from_slice(&packet);
+        }
+        data
+    }
+
+    // 按 PID 拼出 PES 和 PMT
+    fn demux(data: &[u8]) -> (HashMap<u16, Vec<u8>>, Vec<u8>) {
+        let mut streams: HashMap<u16, Vec<u8>> = HashMap::new();
+        let mut pmt = vec![];
+        for packet in data.chunks(TS_PACKET_SIZE) {
+            assert_eq!(packet[0], 0x47);
+            let pid = packet_pid(packet);
+            let payload = split_packet(packet).1;
+            if pid == 0x1000 && packet_pusi(packet) {
+                let (start, length) = psi_section(payload).unwrap();
+                pmt = payload[start..start + length].to_vec();
+            } else if pid != 0 {
+                streams.entry(pid).or_default().extend_from_slice(payload);
+            }
+        }
+        (streams, pmt)
+    }
+
+    #[test]
+    fn test_decrypt_sample_aes_ts() {
+        let slice: Vec<u8> = (0..600).map(|i| ((i * 7) % 251 + 1) as u8).collect();
+        let mut idr = vec![0x65];
+        idr.extend_from_slice(&slice);
+        let short = [0x41, 1, 2, 3, 4, 5]; // 长度不超过48的 slice 不加密
+        let sps = [0x67, 0x42, 0x00, 0x1e, 0x00, 0x00, 0x03, 0x01];
+
+        let mut clear_video = vec![0, 0, 0, 1, 0x09, 0xf0];
+        clear_video.extend_from_slice(&[0, 0, 0, 1]);
+        clear_video.extend_from_slice(&sps);
+        clear_video.extend_from_slice(&[0, 0, 1]);
+        clear_video.extend_from_slice(&idr);
+        clear_video.extend_from_slice(&[0, 0, 1]);
+        clear_video.extend_from_slice(&short);
+
+        let mut encrypted_video = clear_video[..clear_video.len() - idr.len() - short.len() - 3].to_vec();
+        encrypted_video.extend_from_slice(&encrypt_nal(&idr));
+        encrypted_video.extend_from_slice(&[0, 0, 1]);
+        encrypted_video.extend_from_slice(&short);
+        assert_ne!(encrypted_video, clear_video);
+
+        let mut clear_audio = vec![];
+        let mut encrypted_audio = vec![];
+        for (seed, size) in [(1u8, 200usize), (9, 60), (3, 12)] {
+            let frame = adts_frame(size, seed);
+            clear_audio.extend_from_slice(&frame);
+            let mut encrypted = frame.clone();
+            let blocks = size.saturating_sub(16) / 16;
+            if blocks > 0 {
+                cbc_encrypt(&mut encrypted[7 + 16..7 + 16 + blocks * 16]);
+            }
+            encrypted_audio.extend_from_slice(&encrypted);
+        }
+
+        let input = encrypted_fixture(&encrypted_video, &encrypted_audio);
+        let output = decrypt_ts(&input, &KEY, &IV).unwrap();
+        assert_eq!(output.len() % TS_PACKET_SIZE, 0);
+
+        let (streams, pmt) = demux(&output);
+        assert_eq!(streams[&VIDEO_PID], pes(0xe0, &clear_video, false));
+        assert_eq!(streams[&AUDIO_PID], pes(0xc0, &clear_audio, true));
+        // stream_type 改回明文类型并且 CRC 正确
+        assert_eq!(pmt[12], 0x1b);
+        assert_eq!(pmt[17], 0x0f);
+        assert_eq!(crc32_mpeg2(&pmt), 0);
+    }
+
+    #[test]
+    fn test_decrypt_rejects_invalid_key() {
+        assert!(decrypt_ts(&[], &KEY[..8], &IV).is_err());
+    }
+
+    #[test]
+    fn test_remove_emulation_prevention() {
+        assert_eq!(
+            remove_emulation_prevention(&[0x65, 0, 0, 3, 1, 0, 0, 3, 3, 0, 0, 3]),
+            vec![0x65, 0, 0, 1, 0, 0, 3, 0, 0]
+        );
+    }
+
+    #[test]
+    fn test_ac3_frame_len() {
+        // 48kHz 192kbps => 768 字节
+        assert_eq!(ac3_frame_len(&[0x0b, 0x77, 0, 0, 0x14, 0x40]), Some(768));
+        // 44.1kHz 32kbps 奇数 frmsizecod => 140 字节
+        assert_eq!(ac3_frame_len(&[0x0b, 0x77, 0, 0, 0x41, 0x40]), Some(140));
+        // E-AC-3 frmsiz=255 => 512 字节
+        assert_eq!(ac3_frame_len(&[0x0b, 0x77, 0x00, 0xff, 0x00, 0x80]), Some(512));
+        assert_eq!(ac3_frame_len(&[0x47, 0x77, 0, 0, 0, 0]), None);
+    }
+}
@@ -0,0 +1,561 @@
+use crate::common::now;
+use crate::progress::Progress;
+use crate::error::Error;
+use crate::m3u8::VariantSelect;
+use crate::{Combiner, Cutter, Downloader};
+use actix_files::NamedFile;
+use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use tokio::runtime::Runtime;
+use tracing::{error, info, info_span, warn, Instrument};
+use url::Url;
+
+// 提交的任务, 和命令行的 download/combine/cut 对应
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum JobRequest {
+    Download {
+        url: String,
+        #[serde(default)]
+        output: String,
+        #[serde(default)]
+        concurrency: Option<i32>,
+        #[serde(default)]
+        variant: Option<String>,
+        #[serde(default)]
+        audio_lang: String,
+        #[serde(default)]
+        subs: Vec<String>,
+    },
+    Combine {
+        reg_name: String,
+        start: i32,
+        end: i32,
+        #[serde(default)]
+        output: String,
+    },
+    Cut {
+        input: String,
+        #[serde(default)]
+        start: u32,
+        duration: u32,
+        #[serde(default)]
+        output: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Finished,
+    Failed,
+    Cancelled,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub request: JobRequest,
+    pub status: JobStatus,
+    pub done: u64,  // 已完成的分片数
+    pub total: u64, // 分片总数, 合并和截取任务为 0
+    pub error: Option<String>,
+    pub output: Option<String>, // 完成后的文件名, 在任务目录中
+    pub created_at: u64,
+    pub updated_at: u64,
+}
+
+// 任务队列, 每个任务保存在 <data_dir>/<id>/job.json, 重启后继续执行没有完成的任务
+struct JobQueue {
+    data_dir: PathBuf,
+    input_dir: PathBuf, // 合并、截取任务可以读取的本地目录, 规范化后的绝对路径
+    max_queue: usize,
+    jobs: Mutex<HashMap<String, Job>>,
+    pending: Mutex<VecDeque<String>>,
+    ready: Condvar,
+    running: Mutex<HashMap<String, Arc<Progress>>>,
+    counter: AtomicU64,
+}
+
+impl JobQueue {
+    fn load(data_dir: PathBuf, max_queue: usize) -> Result<JobQueue, Error> {
+        fs::create_dir_all(&data_dir)?;
+        let mut jobs = HashMap::new();
+        for entry in fs::read_dir(&data_dir)? {
+            let file = entry?.path().join("job.json");
+            if !file.is_file() {
+                continue;
+            }
+            let mut job: Job = match fs::read(&file).map(|data| serde_json::from_slice(&data)) {
+                Ok(Ok(job)) => job,
+                _ => {
+                    warn!(file = %file.display(), "读取任务失败, 跳过");
+                    continue;
+                }
+            };
+            // 上次退出时正在执行的任务重新排队, 下载任务会跳过已经下载的分片
+            if job.status == JobStatus::Running {
+                job.status = JobStatus::Queued;
+            }
+            jobs.insert(job.id.clone(), job);
+        }
+        let mut pending: Vec<&Job> = jobs.values().filter(|j| j.status == JobStatus::Queued).collect();
+        pending.sort_by_key(|j| (j.created_at, j.id.clone()));
+        let pending: VecDeque<String> = pending.into_iter().map(|j| j.id.clone()).collect();
+        info!("加载了{}个任务, {}个等待执行", jobs.len(), pending.len());
+        let queue = JobQueue {
+            input_dir: data_dir.canonicalize()?,
+            data_dir,
+            max_queue,
+            jobs: Mutex::new(jobs),
+            pending: Mutex::new(pending),
+            ready: Condvar::new(),
+            running: Mutex::new(HashMap::new()),
+            counter: AtomicU64::new(0),
+        };
+        for job in queue.jobs.lock().unwrap().values() {
+            queue.save(job)?;
+        }
+        Ok(queue)
+    }
+
+    fn job_dir(&self, id: &str) -> PathBuf {
+        self.data_dir.join(id)
+    }
+
+    fn save(&self, job: &Job) -> Result<(), Error> {
+        let dir = self.job_dir(&job.id);
+        fs::create_dir_all(&dir)?;
+        // 先写临时文件再改名, 避免退出时留下写了一半的 job.json
+        let file = dir.join("job.json");
+        let temp = dir.join("job.json.tmp");
+        fs::write(&temp, serde_json::to_vec_pretty(job)?)?;
+        fs::rename(temp, file)?;
+        Ok(())
+    }
+
+    fn submit(&self, request: JobRequest) -> Result<Job, String> {
+        let mut pending = self.pending.lock().unwrap();
+        if pending.len() >= self.max_queue {
+            return Err(format!("队列已满, 最多{}个等待中的任务", self.max_queue));
+        }
+        let seed = format!("{}-{}-{:?}", now(), self.counter.fetch_add(1, Ordering::Relaxed), thread::current().id());
+        let id = format!("{:x}", md5::compute(seed))[..12].to_string();
+        let job = Job {
+            id: id.clone(),
+            request,
+            status: JobStatus::Queued,
+            done: 0,
+            total: 0,
+            error: None,
+            output: None,
+            created_at: now(),
+            updated_at: now(),
+        };
+        self.save(&job).map_err(|e| e.to_string())?;
+        self.jobs.lock().unwrap().insert(id.clone(), job.clone());
+        pending.push_back(id);
+        self.ready.notify_one();
+        Ok(job)
+    }
+
+    // 等待下一个任务
+    fn next(&self) -> String {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            if let Some(id) = pending.pop_front() {
+                return id;
+            }
+            pending = self.ready.wait(pending).unwrap();
+        }
+    }
+
+    fn update(&self, id: &str, f: impl FnOnce(&mut Job)) -> Option<Job> {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = jobs.get_mut(id)?;
+        f(job);
+        job.updated_at = now();
+        if let Err(e) = self.save(job) {
+            error!(job = %id, error = %e, "保存任务失败");
+        }
+        Some(job.clone())
+    }
+
+    // 带上正在执行的任务的实时进度
+    fn view(&self, id: &str) -> Option<Job> {
+        let mut job = self.jobs.lock().unwrap().get(id)?.clone();
+        if let Some(progress) = self.running.lock().unwrap().get(id) {
+            (job.done, job.total) = progress.snapshot();
+        }
+        Some(job)
+    }
+
+    fn list(&self) -> Vec<Job> {
+        let ids: Vec<String> = self.jobs.lock().unwrap().keys().cloned().collect();
+        let mut list: Vec<Job> = ids.iter().filter_map(|id| self.view(id)).collect();
+        list.sort_by_key(|j| (j.created_at, j.id.clone()));
+        list
+    }
+
+    // 等待中的任务直接取消, 正在执行的任务通知下载停止, 由执行线程更新状态
+    fn cancel(&self, id: &str) -> Option<Job> {
+        let removed = {
+            let mut pending = self.pending.lock().unwrap();
+            let before = pending.len();
+            pending.retain(|item| item != id);
+            before != pending.len()
+        };
+        if removed {
+            return self.update(id, |job| job.status = JobStatus::Cancelled);
+        }
+        if let Some(progress) = self.running.lock().unwrap().get(id) {
+            progress.cancel();
+        }
+        self.view(id)
+    }
+
+    fn work(&self, rt: &Runtime) {
+        loop {
+            let id = self.next();
+            let job = match self.update(&id, |job| job.status = JobStatus::Running) {
+                Some(job) => job,
+                None => continue,
+            };
+            let progress = Arc::new(Progress::default());
+            self.running.lock().unwrap().insert(id.clone(), progress.clone());
+            // 任务执行过程中的日志都带上任务 id
+            let span = info_span!("job", job = %id);
+            info!(parent: &span, "开始执行任务");
+            let res = rt.block_on(run_job(&job, &self.job_dir(&id), &self.input_dir, progress.clone()).instrument(span.clone()));
+            self.running.lock().unwrap().remove(&id);
+            let (done, total) = progress.snapshot();
+            let job = self.update(&id, move |job| {
+                job.done = done;
+                job.total = total;
+                match res {
+                    Ok(file) => {
+                        job.status = JobStatus::Finished;
+                        job.output = file.file_name().map(|n| n.to_string_lossy().to_string());
+                    }
+                    Err(_) if progress.is_cancelled() => job.status = JobStatus::Cancelled,
+                    Err(e) => {
+                        job.status = JobStatus::Failed;
+                        job.error = Some(e.to_string());
+                    }
+                }
+            });
+            if let Some(job) = job {
+                info!(parent: &span, status = ?job.status, "任务结束");
+            }
+        }
+    }
+}
+
+// 输出文件只保留文件名, 都放在任务目录中
+fn output_name(output: &str, id: &str) -> String {
+    match Path::new(output).file_name() {
+        Some(name) => name.to_string_lossy().to_string(),
+        None => format!("{}.mp4", id),
+    }
+}
+
+// 合并、截取任务读取的本地文件只能在 input_dir 中, 相对路径按 input_dir 解析, 返回规范化后的绝对路径,
+// 符号链接指向目录外时也拒绝。http(s) 地址由 ffmpeg 直接读取, 其他 ffmpeg 协议(file:、concat: 等)按路径处理后会被拒绝。
+// range 为合并任务的序号范围, 检查 (.*) 替换后的每个文件
+fn check_input(input_dir: &Path, input: &str, range: Option<(i32, i32)>) -> Result<String, Error> {
+    if Url::parse(input).is_ok_and(|url| url.scheme() == "http" || url.scheme() == "https") {
+        return Ok(input.to_string());
+    }
+    let denied = || Error::Parse(format!("输入 {} 不在允许的目录 {} 中", input, input_dir.display()));
+    let path = input_dir.join(input);
+    let name = path.file_name().ok_or_else(denied)?.to_string_lossy().to_string();
+    let parent = path.parent().ok_or_else(denied)?.canonicalize().map_err(|_| denied())?;
+    if !parent.starts_with(input_dir) {
+        return Err(denied());
+    }
+    let files: Vec<String> = match range {
+        Some((start, end)) => (start..=end).map(|i| name.replace("(.*)", &i.to_string())).collect(),
+        None => vec![name.clone()],
+    };
+    for file in files {
+        // 不存在的文件合并时跳过, 截取时由 ffmpeg 报错
+        if let Ok(real) = parent.join(file).canonicalize() {
+            if !real.starts_with(input_dir) {
+                return Err(denied());
+            }
+        }
+    }
+    Ok(parent.join(name).display().to_string())
+}
+
+// 下载任务只接受 http(s) 地址, 本地的播放列表会让客户端读取服务器上的任意文件
+fn check_url(url: &str) -> Result<(), Error> {
+    match Url::parse(url) {
+        Ok(parsed) if parsed.scheme() == "http" || parsed.scheme() == "https" => Ok(()),
+        _ => Err(Error::Parse(format!("下载地址 {} 不是 http(s) 地址", url))),
+    }
+}
+
+// 提交时先检查输入, 执行时再检查一次, 期间文件可能被替换
+fn check_request(input_dir: &Path, request: &JobRequest) -> Result<(), Error> {
+    match request {
+        JobRequest::Download { url, .. } => check_url(url),
+        JobRequest::Combine { reg_name, start, end, .. } => check_input(input_dir, reg_name, Some((*start, *end))).map(|_| ()),
+        JobRequest::Cut { input, .. } => check_input(input_dir, input, None).map(|_| ()),
+    }
+}
+
+async fn run_job(job: &Job, dir: &Path, input_dir: &Path, progress: Arc<Progress>) -> Result<PathBuf, Error> {
+    match job.request.clone() {
+        JobRequest::Download { url, output, concurrency, variant, audio_lang, subs } => {
+            check_url(&url)?;
+            let mut downloader = Downloader::new(url)
+                .folder(dir)
+                .output(output_name(&output, &job.id))
+                .audio_lang(audio_lang)
+                .subtitles(subs)
+                .progress(progress);
+            if let Some(concurrency) = concurrency {
+                downloader = downloader.concurrency(concurrency);
+            }
+            if let Some(variant) = variant {
+                downloader = downloader.variant(variant.parse::<VariantSelect>().map_err(Error::Parse)?);
+            }
+            downloader.run().await
+        }
+        JobRequest::Combine { reg_name, start, end, output } => Combiner::new(check_input(input_dir, &reg_name, Some((start, end)))?, start, end)
+            .output(dir.join(output_name(&output, &job.id)).display().to_string())
+            .run(),
+        JobRequest::Cut { input, start, duration, output } => Cutter::new(check_input(input_dir, &input, None)?)
+            .start(start)
+            .duration(duration)
+            .output(dir.join(output_name(&output, &job.id)).display().to_string())
+            .run(),
+    }
+}
+
+fn error_response(mut builder: actix_web::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(serde_json::json!({ "error": message }))
+}
+
+#[post("/jobs")]
+async fn submit_job(queue: web::Data<JobQueue>, body: web::Json<JobRequest>) -> HttpResponse {
+    let request = body.into_inner();
+    if let Err(e) = check_request(&queue.input_dir, &request) {
+        return error_response(HttpResponse::BadRequest(), &e.to_string());
+    }
+    match queue.submit(request) {
+        Ok(job) => HttpResponse::Created().json(job),
+        Err(e) => error_response(HttpResponse::TooManyRequests(), &e),
+    }
+}
+
+#[get("/jobs")]
+async fn list_jobs(queue: web::Data<JobQueue>) -> HttpResponse {
+    HttpResponse::Ok().json(queue.list())
+}
+
+#[get("/jobs/{id}")]
+async fn get_job(queue: web::Data<JobQueue>, id: web::Path<String>) -> HttpResponse {
+    match queue.view(&id) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => error_response(HttpResponse::NotFound(), "任务不存在"),
+    }
+}
+
+#[post("/jobs/{id}/cancel")]
+async fn cancel_job(queue: web::Data<JobQueue>, id: web::Path<String>) -> HttpResponse {
+    match queue.cancel(&id) {
+        Some(job) => HttpResponse::Ok().json(job),
+        None => error_response(HttpResponse::NotFound(), "任务不存在"),
+    }
+}
+
+#[get("/jobs/{id}/file")]
+async fn job_file(req: HttpRequest, queue: web::Data<JobQueue>, id: web::Path<String>) -> HttpResponse {
+    let job = match queue.view(&id) {
+        Some(job) => job,
+        None => return error_response(HttpResponse::NotFound(), "任务不存在"),
+    };
+    let output = match (job.status, job.output) {
+        (JobStatus::Finished, Some(output)) => output,
+        _ => return error_response(HttpResponse::Conflict(), "任务还没有完成"),
+    };
+    match NamedFile::open_async(queue.job_dir(&id).join(output)).await {
+        Ok(file) => file.into_response(&req),
+        Err(e) => error_response(HttpResponse::NotFound(), &e.to_string()),
+    }
+}
+
+// REST 服务: Server::new("127.0.0.1:8080").data_dir("jobs").workers(2).run().await
+#[derive(Debug, Clone)]
+pub struct Server {
+    bind: String,
+    data_dir: PathBuf,
+    input_dir: Option<PathBuf>,
+    workers: usize,
+    max_queue: usize,
+}
+
+impl Server {
+    pub fn new(bind: impl Into<String>) -> Server {
+        Server {
+            bind: bind.into(),
+            data_dir: PathBuf::from("jobs"),
+            input_dir: None,
+            workers: 2,
+            max_queue: 100,
+        }
+    }
+
+    // 任务状态和下载的文件保存的目录
+    pub fn data_dir(mut self, data_dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = data_dir.into();
+        self
+    }
+
+    // 合并、截取任务可以读取的本地目录, 不指定时为 data_dir, 目录外的文件不能通过接口读取
+    pub fn input_dir(mut self, input_dir: impl Into<PathBuf>) -> Self {
+        self.input_dir = Some(input_dir.into());
+        self
+    }
+
+    // 同时执行的任务数
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    // 最多等待执行的任务数, 超过后提交返回 429
+    pub fn max_queue(mut self, max_queue: usize) -> Self {
+        self.max_queue = max_queue;
+        self
+    }
+
+    pub async fn run(&self) -> Result<(), Error> {
+        let mut queue = JobQueue::load(self.data_dir.clone(), self.max_queue)?;
+        if let Some(input_dir) = &self.input_dir {
+            queue.input_dir = input_dir.canonicalize()?;
+        }
+        info!(input_dir = %queue.input_dir.display(), "合并、截取任务可以读取的目录");
+        let queue = Arc::new(queue);
+        // 任务在独立的线程中执行, 合并、截取等 ffmpeg 调用不会阻塞 http 服务,
+        // 所有任务的下载共用一个多线程的 tokio runtime
+        let rt = Arc::new(Runtime::new()?);
+        for _ in 0..self.workers.max(1) {
+            let queue = queue.clone();
+            let rt = rt.clone();
+            thread::spawn(move || queue.work(&rt));
+        }
+        let data = web::Data::from(queue);
+        info!("服务启动: http://{}", self.bind);
+        HttpServer::new(move || {
+            App::new()
+                .app_data(data.clone())
+                .service(submit_job)
+                .service(list_jobs)
+                .service(get_job)
+                .service(cancel_job)
+                .service(job_file)
+        })
+        .bind(&self.bind)?
+        .run()
+        .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::{check_input, check_request, output_name, JobQueue, JobRequest, JobStatus};
+    use std::fs;
+
+    fn cut_request() -> JobRequest {
+        serde_json::from_str(r#"{"type": "cut", "input": "in.mp4", "duration": 5}"#).unwrap()
+    }
+
+    #[test]
+    fn test_bounded_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let queue = JobQueue::load(dir.path().to_path_buf(), 1).unwrap();
+        let job = queue.submit(cut_request()).unwrap();
+        assert_eq!(job.status, JobStatus::Queued);
+        assert!(queue.submit(cut_request()).is_err());
+        assert_eq!(queue.next(), job.id);
+        assert!(queue.submit(cut_request()).is_ok());
+    }
+
+    #[test]
+    fn test_resume_and_cancel() {
+        let dir = tempfile::tempdir().unwrap();
+        let (running, queued) = {
+            let queue = JobQueue::load(dir.path().to_path_buf(), 10).unwrap();
+            let running = queue.submit(cut_request()).unwrap();
+            let queued = queue.submit(cut_request()).unwrap();
+            queue.next();
+            queue.update(&running.id, |job| job.status = JobStatus::Running);
+            (running.id, queued.id)
+        };
+        // 重启后正在执行的任务重新排队
+        let queue = JobQueue::load(dir.path().to_path_buf(), 10).unwrap();
+        assert_eq!(queue.view(&running).unwrap().status, JobStatus::Queued);
+        assert_eq!(queue.list().len(), 2);
+        assert_eq!(queue.cancel(&queued).unwrap().status, JobStatus::Cancelled);
+        assert_eq!(queue.next(), running);
+        assert!(queue.pending.lock().unwrap().is_empty());
+        assert!(queue.cancel("missing").is_none());
+    }
+
+    #[test]
+    fn test_output_name() {
+        assert_eq!(output_name("../../etc/out.mp4", "abc"), "out.mp4");
+        assert_eq!(output_name("", "abc"), "abc.mp4");
+    }
+
+    #[test]
+    fn test_check_input() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let input_dir = root.path().canonicalize().unwrap();
+        fs::create_dir(input_dir.join("job1")).unwrap();
+        fs::write(input_dir.join("job1/video1.mp4"), b"").unwrap();
+        fs::write(outside.path().join("secret.mp4"), b"").unwrap();
+
+        let expected = input_dir.join("job1/video1.mp4").display().to_string();
+        assert_eq!(check_input(&input_dir, "job1/video1.mp4", None).unwrap(), expected);
+        assert_eq!(check_input(&input_dir, &expected, None).unwrap(), expected);
+        assert_eq!(
+            check_input(&input_dir, "job1/video(.*).mp4", Some((1, 3))).unwrap(),
+            input_dir.join("job1/video(.*).mp4").display().to_string()
+        );
+        assert_eq!(check_input(&input_dir, "https://example.com/a.mp4", None).unwrap(), "https://example.com/a.mp4");
+
+        let secret = outside.path().join("secret.mp4").display().to_string();
+        for input in [secret.as_str(), "../secret.mp4", "job1/../../secret.mp4", "/etc/passwd", "file:///etc/passwd", "concat:/etc/passwd|/etc/hosts"] {
+            assert!(check_input(&input_dir, input, None).is_err(), "{}", input);
+        }
+        // 目录中指向目录外的符号链接
+        std::os::unix::fs::symlink(outside.path().join("secret.mp4"), input_dir.join("job1/video2.mp4")).unwrap();
+        assert!(check_input(&input_dir, "job1/video2.mp4", None).is_err());
+        assert!(check_input(&input_dir, "job1/video(.*).mp4", Some((1, 2))).is_err());
+
+        let request: JobRequest = serde_json::from_str(&format!(r#"{{"type": "cut", "input": "{}", "duration": 5}}"#, secret)).unwrap();
+        assert!(check_request(&input_dir, &request).is_err());
+        assert!(check_request(&input_dir, &cut_request()).is_ok());
+
+        for url in ["/etc/passwd", "file:///etc/passwd", "../index.m3u8"] {
+            let request: JobRequest = serde_json::from_str(&format!(r#"{{"type": "download", "url": "{}"}}"#, url)).unwrap();
+            assert!(check_request(&input_dir, &request).is_err(), "{}", url);
+        }
+        let request: JobRequest = serde_json::from_str(r#"{"type": "download", "url": "https://example.com/index.m3u8"}"#).unwrap();
+        assert!(check_request(&input_dir, &request).is_ok());
+    }
+}
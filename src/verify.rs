@@ -0,0 +1,176 @@
+// 合并前检查下载的分片是否完整: TS 检查长度和每个包的同步字节, fMP4 检查 box 结构,
+// full 模式下再用 ffprobe 获取 TS 分片的时长和 #EXTINF 比较
+use crate::cmd::cmd::get_duration;
+use crate::m3u8::{HlsM3u8Method, HlsSegment};
+use std::fs;
+use std::str::FromStr;
+
+const TS_PACKET_SIZE: usize = 188;
+const TS_SYNC_BYTE: u8 = 0x47;
+
+// 分片的检查方式: off|quick|full
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VerifyMode {
+    Off,   // 不检查
+    Quick, // 只检查文件结构
+    Full,  // 再检查时长, 每个分片执行一次 ffprobe
+}
+
+impl FromStr for VerifyMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "off" => Ok(VerifyMode::Off),
+            "quick" => Ok(VerifyMode::Quick),
+            "full" => Ok(VerifyMode::Full),
+            _ => Err(format!("无效的检查方式: {}, 可选 off|quick|full", s)),
+        }
+    }
+}
+
+// 检查一个分片, 有问题时返回原因。segment 为空时是初始化分片
+pub fn check_segment(file: &str, extension: &str, segment: Option<&HlsSegment>, mode: VerifyMode) -> Result<(), String> {
+    if mode == VerifyMode::Off {
+        return Ok(());
+    }
+    let data = fs::read(file).map_err(|e| format!("读取失败, {}", e))?;
+    if data.is_empty() {
+        return Err("文件为空".to_string());
+    }
+    // AES-128 加密的是整个分片, 只能检查长度
+    if segment.and_then(|s| s.key.as_ref()).is_some_and(|k| k.method == HlsM3u8Method::Aes128) {
+        if !data.len().is_multiple_of(16) {
+            return Err(format!("加密分片的长度 {} 不是16的倍数, 可能被截断", data.len()));
+        }
+        return Ok(());
+    }
+    match extension {
+        "ts" => scan_ts(&data)?,
+        "m4s" | "mp4" | "m4a" | "m4v" => scan_boxes(&data)?,
+        _ => return Ok(()),
+    }
+    if mode == VerifyMode::Full && extension == "ts" {
+        if let Some(segment) = segment.filter(|s| s.duration > 0.0) {
+            // 没有安装 ffprobe 或者无法获取时长时跳过
+            if let Some(duration) = get_duration(file) {
+                check_duration(duration, segment.duration)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// 长度是 188 的整数倍, 并且每个包都以 0x47 开头
+fn scan_ts(data: &[u8]) -> Result<(), String> {
+    if !data.len().is_multiple_of(TS_PACKET_SIZE) {
+        return Err(format!("长度 {} 不是{}的倍数, 可能被截断", data.len(), TS_PACKET_SIZE));
+    }
+    match data.chunks(TS_PACKET_SIZE).position(|packet| packet[0] != TS_SYNC_BYTE) {
+        Some(index) => Err(format!("第{}个 TS 包缺少同步字节 0x47", index)),
+        None => Ok(()),
+    }
+}
+
+// 顶层的 box 首尾相接, 正好到文件结尾
+fn scan_boxes(data: &[u8]) -> Result<(), String> {
+    let mut offset = 0;
+    while offset < data.len() {
+        let rest = &data[offset..];
+        if rest.len() < 8 {
+            return Err(format!("位置 {} 的 box 头不完整", offset));
+        }
+        let name = &rest[4..8];
+        if !name.iter().all(|c| c.is_ascii_graphic() || *c == b' ') {
+            return Err(format!("位置 {} 不是有效的 MP4 box", offset));
+        }
+        let name = String::from_utf8_lossy(name);
+        let (size, header) = match u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) {
+            // size 为 0 时一直到文件结尾
+            0 => (rest.len() as u64, 8),
+            1 if rest.len() >= 16 => (u64::from_be_bytes(rest[8..16].try_into().unwrap()), 16),
+            1 => return Err(format!("box {} 的长度不完整", name)),
+            size => (size as u64, 8),
+        };
+        if size < header {
+            return Err(format!("box {} 的长度 {} 无效", name, size));
+        }
+        if size > rest.len() as u64 {
+            return Err(format!("box {} 需要 {} 字节, 只有 {} 字节, 可能被截断", name, size, rest.len()));
+        }
+        offset += size as usize;
+    }
+    Ok(())
+}
+
+// #EXTINF 一般会取整, 相差超过 1 秒并且超过 20% 时认为分片不完整
+fn check_duration(duration: f64, expected: f64) -> Result<(), String> {
+    let diff = (duration - expected).abs();
+    if diff > 1.0 && diff > expected * 0.2 {
+        return Err(format!("时长 {:.2} 秒, #EXTINF 为 {:.2} 秒", duration, expected));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::m3u8::{HlsKey, HlsM3u8Method, HlsSegment};
+    use crate::verify::{check_duration, check_segment, scan_boxes, scan_ts, VerifyMode};
+    use std::fs;
+
+    fn mp4_box(name: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut data = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        data.extend_from_slice(name);
+        data.extend_from_slice(body);
+        data
+    }
+
+    #[test]
+    fn test_scan() {
+        let mut ts = vec![0u8; 188 * 3];
+        for packet in ts.chunks_mut(188) {
+            packet[0] = 0x47;
+        }
+        assert!(scan_ts(&ts).is_ok());
+        assert!(scan_ts(&ts[..188 * 2 + 100]).is_err());
+        ts[188] = 0;
+        assert!(scan_ts(&ts).unwrap_err().contains("第1个"));
+
+        let mut mp4 = mp4_box(b"moof", &[0; 16]);
+        mp4.extend(mp4_box(b"mdat", &[1; 100]));
+        assert!(scan_boxes(&mp4).is_ok());
+        assert!(scan_boxes(&mp4[..mp4.len() - 10]).unwrap_err().contains("mdat"));
+        assert!(scan_boxes(&[0xff; 32]).is_err());
+
+        assert!(check_duration(5.96, 6.0).is_ok());
+        assert!(check_duration(9.5, 10.0).is_ok());
+        assert!(check_duration(2.0, 6.0).is_err());
+    }
+
+    #[test]
+    fn test_check_segment() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("0.ts").display().to_string();
+        fs::write(&file, [0x47u8; 100]).unwrap();
+        assert!(check_segment(&file, "ts", None, VerifyMode::Quick).is_err());
+        assert!(check_segment(&file, "ts", None, VerifyMode::Off).is_ok());
+        assert!(check_segment(&file, "vtt", None, VerifyMode::Quick).is_ok());
+
+        // AES-128 加密的分片只检查长度
+        let segment = HlsSegment {
+            key: Some(HlsKey {
+                method: HlsM3u8Method::Aes128,
+                uri: String::default(),
+                iv: String::default(),
+            }),
+            duration: 6.0,
+            ..HlsSegment::default()
+        };
+        fs::write(&file, [0u8; 64]).unwrap();
+        assert!(check_segment(&file, "ts", Some(&segment), VerifyMode::Quick).is_ok());
+        fs::write(&file, [0u8; 60]).unwrap();
+        assert!(check_segment(&file, "ts", Some(&segment), VerifyMode::Quick).is_err());
+        fs::write(&file, []).unwrap();
+        assert_eq!(check_segment(&file, "ts", None, VerifyMode::Quick), Err("文件为空".to_string()));
+    }
+}